@@ -0,0 +1,53 @@
+#!/usr/bin/env python3
+"""Closed loop load generator, each connection sends its next request once answered.
+
+usage: load.py <url> <connections> <seconds>
+"""
+import http.client
+import sys
+import threading
+import time
+import urllib.parse
+
+url = urllib.parse.urlparse(sys.argv[1])
+connections = int(sys.argv[2])
+seconds = float(sys.argv[3])
+
+latencies = []
+errors = 0
+lock = threading.Lock()
+deadline = time.monotonic() + seconds
+
+
+def run():
+    global errors
+    conn = http.client.HTTPConnection(url.hostname, url.port, timeout=30)
+    while time.monotonic() < deadline:
+        start = time.monotonic()
+        try:
+            conn.request("GET", url.path)
+            res = conn.getresponse()
+            res.read()
+            ok = res.status == 200
+        except Exception:
+            conn.close()
+            conn = http.client.HTTPConnection(url.hostname, url.port, timeout=30)
+            ok = False
+        with lock:
+            if ok:
+                latencies.append(time.monotonic() - start)
+            else:
+                errors += 1
+
+
+threads = [threading.Thread(target=run) for _ in range(connections)]
+for t in threads:
+    t.start()
+for t in threads:
+    t.join()
+
+latencies.sort()
+n = len(latencies)
+pct = lambda p: latencies[min(n - 1, int(n * p))] * 1000 if n else 0
+print(f"requests: {n}, errors: {errors}, rps: {n / seconds:.1f}, "
+      f"p50: {pct(0.5):.1f}ms, p99: {pct(0.99):.1f}ms")
@@ -0,0 +1,35 @@
+#!/usr/bin/env python3
+"""Upstream answering every GET after a fixed delay.
+
+usage: slow_upstream.py <port> <delay_ms>
+"""
+import http.server
+import socketserver
+import sys
+import time
+
+DELAY = int(sys.argv[2]) / 1000.0
+
+
+class Handler(http.server.BaseHTTPRequestHandler):
+    protocol_version = "HTTP/1.1"
+
+    def do_GET(self):
+        time.sleep(DELAY)
+        body = b"ok\n"
+        self.send_response(200)
+        self.send_header("Content-Length", str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+
+    def log_message(self, *args):
+        pass
+
+
+class Server(socketserver.ThreadingMixIn, http.server.HTTPServer):
+    daemon_threads = True
+    allow_reuse_address = True
+    request_queue_size = 1024
+
+
+Server(("127.0.0.1", int(sys.argv[1])), Handler).serve_forever()
@@ -0,0 +1,180 @@
+use clap::{Args, Parser, Subcommand};
+
+use crate::conf::{ReboundConf, ReboundMode, REBOUND_CONF_FILE, REBOUND_DEFAULT_ERROR_FILE, REBOUND_LOG_DIR};
+
+/// Conf file read when neither --config nor REBOUND_CONF_FILE is given
+///
+const DEFAULT_CONF_FILE: &str = "rebound.yaml";
+
+/// Rule based reverse proxy
+///
+#[derive(Parser, Debug)]
+#[command(name = "rebound", version, about, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Serves when no command is given
+    ///
+    #[command(flatten)]
+    pub serve: ServeArgs
+
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+
+    /// Starts the server, the default command
+    Serve(ServeArgs),
+
+    /// Checks the conf, printing every issue found
+    Check(CheckArgs),
+
+    /// Prints the routes built from the rules of the conf
+    Routes(RoutesArgs),
+
+    /// Prints the version
+    Version
+
+}
+
+#[derive(Args, Debug)]
+pub struct ConfArgs {
+
+    /// Conf file
+    #[arg(short, long, env = REBOUND_CONF_FILE, default_value = DEFAULT_CONF_FILE)]
+    pub config: String
+
+}
+
+#[derive(Args, Debug)]
+pub struct CheckArgs {
+
+    #[command(flatten)]
+    pub conf: ConfArgs,
+
+    #[command(flatten)]
+    pub overrides: OverrideArgs
+
+}
+
+#[derive(Args, Debug)]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct RoutesArgs {
+
+    #[command(subcommand)]
+    pub command: Option<RoutesCommand>,
+
+    #[command(flatten)]
+    pub conf: ConfArgs,
+
+    /// Output format, a tree of the rules, or the circuit graph as Graphviz DOT or JSON
+    #[arg(short, long, value_parser = ["tree", "dot", "json"], default_value = "tree")]
+    pub format: String
+
+}
+
+#[derive(Subcommand, Debug)]
+pub enum RoutesCommand {
+
+    /// Explains how a request would be routed, without sending it
+    Explain(ExplainArgs)
+
+}
+
+#[derive(Args, Debug)]
+pub struct ExplainArgs {
+
+    #[command(flatten)]
+    pub conf: ConfArgs,
+
+    /// Request method, like GET
+    pub method: String,
+
+    /// Request url, path and query, like /api/v1/users?x=1
+    pub url: String,
+
+    /// Request header, as Name:value, repeatable
+    #[arg(short = 'H', long = "header")]
+    pub headers: Vec<String>,
+
+    /// Prints the explanation as JSON
+    #[arg(long)]
+    pub json: bool
+
+}
+
+#[derive(Args, Debug)]
+pub struct ServeArgs {
+
+    #[command(flatten)]
+    pub conf: ConfArgs,
+
+    /// Directory of the rolling log files, logs only go to stdout without it
+    #[arg(long, env = REBOUND_LOG_DIR)]
+    pub log_dir: Option<String>,
+
+    /// File served as body of error responses
+    #[arg(long, env = REBOUND_DEFAULT_ERROR_FILE)]
+    pub error_file: Option<String>,
+
+    #[command(flatten)]
+    pub overrides: OverrideArgs
+
+}
+
+/// Flags overriding conf values, checked along with the conf
+///
+#[derive(Args, Debug)]
+pub struct OverrideArgs {
+
+    /// Listening host, overrides the conf
+    #[arg(long)]
+    pub host: Option<String>,
+
+    /// Listening port, overrides the conf
+    #[arg(short, long)]
+    pub port: Option<u16>,
+
+    /// Worker count, overrides the conf
+    #[arg(short, long)]
+    pub workers: Option<usize>,
+
+    /// Execution mode, overrides the conf
+    #[arg(long, value_parser = ["threaded", "async"])]
+    pub mode: Option<String>
+
+}
+
+impl OverrideArgs {
+
+    /// Applies the flags overriding conf values
+    ///
+    /// Returns the conf keys overridden
+    pub fn apply(&self, conf: &mut ReboundConf) -> Vec<&'static str> {
+
+        let mut overridden = Vec::new();
+        if let Some(host) = &self.host {
+            conf.host = host.clone();
+            overridden.push("host");
+        }
+        if let Some(port) = self.port {
+            conf.port = port;
+            overridden.push("port");
+        }
+        if let Some(workers) = self.workers {
+            conf.workers = workers;
+            overridden.push("workers");
+        }
+        match self.mode.as_deref() {
+            Some("threaded") => conf.mode = ReboundMode::Threaded,
+            Some("async") => conf.mode = ReboundMode::Async,
+            _ => {},
+        }
+        if self.mode.is_some() {
+            overridden.push("mode");
+        }
+        overridden
+    }
+}
@@ -0,0 +1,674 @@
+use std::{collections::HashMap, fmt, fs::File, path::Path};
+use config::{Config, ConfigError};
+use regex::Regex;
+
+use crate::{engine::{acl::{self, IpFilter}, auth::Authenticator, circuit::CircuitPath, jwt::JwtValidator, concurrency::check_concurrency, limit, pool::check_tls}, node::tls::build_acceptor};
+
+use super::{ReboundClientAuth, ReboundConf, ReboundLogTarget, ReboundRateLimit, ReboundRateLimitAlgorithm};
+
+/// Worker count above which the configuration is most likely a mistake
+///
+const MAX_WORKERS: usize = 1024;
+
+/// Admin token length below which it is considered weak
+///
+const MIN_ADMIN_TOKEN_LEN: usize = 16;
+
+/// HS256 secret length below which it is considered weak, the size of its digest
+///
+const MIN_JWT_SECRET_LEN: usize = 32;
+
+/// Severity of a configuration issue
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning
+}
+
+/// Issue found in a configuration file
+///
+#[derive(Clone, Debug)]
+pub struct ConfIssue {
+
+    pub severity: Severity,
+
+    /// Configuration file the issue was found in
+    ///
+    pub file: String,
+
+    /// Line of the offending key, when it could be located
+    ///
+    pub line: Option<usize>,
+
+    /// Offending key, like rules[2].upstream
+    ///
+    pub key: Option<String>,
+
+    pub message: String
+
+}
+
+impl fmt::Display for ConfIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: {}", severity, self.file)?;
+        if let Some(line) = self.line {
+            write!(f, ":{}", line)?;
+        }
+        if let Some(key) = &self.key {
+            write!(f, ": {}", key)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Outcome of checking a configuration file
+///
+pub struct ConfReport {
+
+    /// Parsed configuration, unless the file could not be parsed
+    ///
+    pub conf: Option<ReboundConf>,
+
+    /// Every issue found, errors and warnings
+    ///
+    pub issues: Vec<ConfIssue>
+
+}
+
+impl ConfReport {
+
+    pub fn errors(&self) -> usize {
+        self.issues.iter().filter(|i| i.severity == Severity::Error).count()
+    }
+
+    pub fn warnings(&self) -> usize {
+        self.issues.iter().filter(|i| i.severity == Severity::Warning).count()
+    }
+}
+
+/// Parses and validates the whole configuration file, collecting every issue instead of stopping at the first
+///
+pub fn check(file: &str) -> ConfReport {
+    check_with(file, |_| Vec::new())
+}
+
+/// Parses the configuration file and validates it once overridden, e.g. by command line flags
+///
+/// The overrides return the keys they set, whose issues are not located in the file
+pub fn check_with(file: &str, overrides: impl FnOnce(&mut ReboundConf) -> Vec<&'static str>) -> ConfReport {
+
+    let mut checker = Checker {
+        file: String::from(file),
+        source: SourceMap::new(&std::fs::read_to_string(file).unwrap_or_default()),
+        overridden: Vec::new(),
+        issues: Vec::new()
+    };
+
+    let conf = Config::builder()
+        .add_source(config::File::from(Path::new(file)))
+        .build()
+        .and_then(|c| c.try_deserialize::<ReboundConf>());
+
+    let conf = match conf {
+        Ok(mut conf) => {
+            checker.overridden = overrides(&mut conf);
+            checker.check_conf(&conf);
+            Some(conf)
+        },
+        Err(e) => {
+            checker.parse_error(e);
+            None
+        },
+    };
+
+    ConfReport { conf, issues: checker.issues }
+}
+
+struct Checker {
+
+    file: String,
+
+    source: SourceMap,
+
+    /// Keys set outside of the file
+    ///
+    overridden: Vec<&'static str>,
+
+    issues: Vec<ConfIssue>
+
+}
+
+impl Checker {
+
+    fn issue(&mut self, severity: Severity, key: Option<String>, line: Option<usize>, mut message: String) {
+        let line = match key.as_deref() {
+            Some(k) if self.overridden.contains(&k) => {
+                message.push_str(", set on the command line");
+                None
+            },
+            _ => line.or_else(|| key.as_deref().and_then(|k| self.source.line_of(k))),
+        };
+        self.issues.push(ConfIssue { severity, file: self.file.clone(), line, key, message });
+    }
+
+    fn error(&mut self, key: &str, message: String) {
+        self.issue(Severity::Error, Some(String::from(key)), None, message);
+    }
+
+    fn warning(&mut self, key: &str, message: String) {
+        self.issue(Severity::Warning, Some(String::from(key)), None, message);
+    }
+
+    fn parse_error(&mut self, e: ConfigError) {
+        match e {
+            ConfigError::Type { key: Some(key), unexpected, expected, .. } => {
+                // config joins indexes and fields without a dot, like rules[0]upstream
+                let key = Regex::new(r"\](\w)").unwrap().replace_all(&key, "].$1").to_string();
+                self.issue(Severity::Error, Some(key), None, format!("invalid type: {}, expected {}", unexpected, expected));
+            },
+            ConfigError::FileParse { cause, .. } => {
+                let cause = cause.to_string();
+                let line = Regex::new(r"at line (\d+)").unwrap()
+                    .captures(&cause)
+                    .and_then(|c| c[1].parse().ok());
+                self.issue(Severity::Error, None, line, cause);
+            },
+            e => self.issue(Severity::Error, None, None, e.to_string()),
+        }
+    }
+
+    fn check_conf(&mut self, conf: &ReboundConf) {
+
+        if conf.workers == 0 {
+            self.error("workers", String::from("must be at least 1"));
+        }
+        else if conf.workers > MAX_WORKERS {
+            self.warning("workers", format!("{} workers is more than {}, each worker is a thread", conf.workers, MAX_WORKERS));
+        }
+
+        if conf.queue.capacity == 0 {
+            self.error("queue.capacity", String::from("must be at least 1"));
+        }
+
+        if let Some(metrics) = &conf.metrics {
+            if metrics.port == conf.port {
+                self.error("metrics.port", format!("{} is already the port of rebound", metrics.port));
+            }
+            if !metrics.path.starts_with('/') {
+                self.error("metrics.path", format!("{} must start with /", metrics.path));
+            }
+        }
+
+        if let Some(tracing) = &conf.tracing {
+            match surf::Url::parse(&tracing.endpoint) {
+                Ok(url) if ["http", "https"].contains(&url.scheme()) => {},
+                _ => self.error("tracing.endpoint", format!("{} must be an http:// or https:// url", tracing.endpoint)),
+            }
+            if !(0.0..=1.0).contains(&tracing.sampling_ratio) {
+                self.error("tracing.sampling_ratio", format!("{} must be between 0 and 1", tracing.sampling_ratio));
+            }
+        }
+
+        if let Some(Err(e)) = conf.ip_filter.as_ref().map(IpFilter::new) {
+            self.error("ip_filter", e);
+        }
+        if let Err(e) = acl::parse_ranges(&conf.trusted_proxies) {
+            self.error("trusted_proxies", e);
+        }
+
+        if let Some(limit) = &conf.rate_limit {
+            self.check_rate_limit("rate_limit", limit);
+        }
+
+        if let Some(admin) = &conf.admin {
+            if admin.port == conf.port || conf.metrics.as_ref().map(|m| m.port == admin.port).unwrap_or(false) {
+                self.error("admin.port", format!("{} is already used by rebound", admin.port));
+            }
+            if admin.token.trim().is_empty() {
+                self.error("admin.token", String::from("must not be empty"));
+            }
+            else if admin.token.len() < MIN_ADMIN_TOKEN_LEN {
+                self.warning("admin.token", format!("shorter than {} characters, easy to guess", MIN_ADMIN_TOKEN_LEN));
+            }
+            if !["127.0.0.1", "::1", "localhost"].contains(&admin.host.as_str()) {
+                self.warning("admin.host", format!("{} exposes the admin API beyond localhost", admin.host));
+            }
+        }
+
+        if let Some(file) = &conf.logging.log4rs_file {
+            if let Err(e) = File::open(file) {
+                self.error("logging.log4rs_file", format!("cannot read {}: {}", file, e));
+            }
+        }
+        let targets = conf.logging.targets.iter().flatten();
+        if targets.clone().any(|t| *t == ReboundLogTarget::File) && conf.logging.dir.is_none() {
+            self.warning("logging.targets", String::from("file target without logging.dir, rebound must be started with --log-dir"));
+        }
+        if targets.clone().any(|t| *t == ReboundLogTarget::None) && targets.count() > 1 {
+            self.error("logging.targets", String::from("none can not be combined with other targets"));
+        }
+        if let Some(file) = conf.logging.access.as_ref().and_then(|a| a.file.as_ref()) {
+            let dir = Path::new(file).parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            if !dir.is_dir() {
+                self.error("logging.access.file", format!("directory of {} does not exist", file));
+            }
+        }
+
+        if let Some(ssl) = &conf.ssl {
+            if ssl.max_connections == 0 {
+                self.error("ssl.max_connections", String::from("must be at least 1"));
+            }
+            if ssl.idle_timeout_ms == 0 {
+                self.error("ssl.idle_timeout_ms", String::from("must be at least 1"));
+            }
+
+            let mut files = vec![(String::from("ssl.pub_cert"), &ssl.pub_cert), (String::from("ssl.priv_key"), &ssl.priv_key)];
+            if let Some(client_ca) = &ssl.client_ca {
+                files.push((String::from("ssl.client_ca"), client_ca));
+            }
+            for (i, cert) in ssl.certificates.iter().enumerate() {
+                files.push((format!("ssl.certificates[{}].pub_cert", i), &cert.pub_cert));
+                files.push((format!("ssl.certificates[{}].priv_key", i), &cert.priv_key));
+            }
+
+            let mut readable = true;
+            for (key, file) in files {
+                if let Err(e) = File::open(file) {
+                    self.error(&key, format!("cannot read {}: {}", file, e));
+                    readable = false;
+                }
+            }
+
+            // only worth loading once every file can be read
+            if readable {
+                if let Err(e) = build_acceptor(ssl) {
+                    self.error("ssl", e);
+                }
+            }
+        }
+
+        if let Some(Err(e)) = conf.pool.concurrency.as_ref().map(check_concurrency) {
+            self.error("pool.concurrency", e);
+        }
+        if conf.pool.request_timeout_ms == Some(0) {
+            self.error("pool.request_timeout_ms", String::from("must be at least 1, leave it out for no timeout"));
+        }
+        for (host, pool) in conf.upstream_pools.iter() {
+            if let Some(Err(e)) = pool.concurrency.as_ref().map(check_concurrency) {
+                self.error(&format!("upstream_pools.{}.concurrency", host), e);
+            }
+            if pool.request_timeout_ms == Some(0) {
+                self.error(&format!("upstream_pools.{}.request_timeout_ms", host), String::from("must be at least 1, leave it out for no timeout"));
+            }
+        }
+
+        for (host, tls) in conf.upstream_tls.iter() {
+            if let Err(e) = check_tls(tls) {
+                self.error(&format!("upstream_tls.{}", host), e);
+            }
+        }
+
+        let client_auth = conf.ssl.as_ref().map(|ssl| ssl.client_auth.clone()).unwrap_or_default();
+        let mut patterns: HashMap<Vec<String>, usize> = HashMap::new();
+
+        for (i, rule) in conf.rules.iter().flatten().enumerate() {
+
+            let key = format!("rules[{}]", i);
+
+            if !rule.pattern.starts_with('/') {
+                self.error(&format!("{}.pattern", key), format!("{} must start with /", rule.pattern));
+            }
+            if rule.pattern.contains(|c: char| c.is_whitespace() || c == '?' || c == '#') {
+                self.error(&format!("{}.pattern", key), format!("{} must be a plain path, without whitespace, query or fragment", rule.pattern));
+            }
+
+            let path = CircuitPath::from(rule.pattern.as_str()).ordered_path;
+            match patterns.get(&path) {
+                Some(j) => self.error(&format!("{}.pattern", key), format!("{} is the same path as rules[{}], only one of them can match", rule.pattern, j)),
+                None => { patterns.insert(path, i); },
+            }
+
+            match surf::Url::parse(&rule.upstream) {
+                Ok(url) if url.scheme() != "http" && url.scheme() != "https" => {
+                    self.error(&format!("{}.upstream", key), format!("{} must be an http:// or https:// url", rule.upstream));
+                },
+                Ok(url) if url.host_str().unwrap_or_default().is_empty() => {
+                    self.error(&format!("{}.upstream", key), format!("{} has no host", rule.upstream));
+                },
+                Ok(_) => {},
+                Err(e) => self.error(&format!("{}.upstream", key), format!("{} is not a valid url: {}", rule.upstream, e)),
+            }
+
+            for (name, pattern) in [("client_cert_subject", &rule.client_cert_subject), ("client_cert_san", &rule.client_cert_san)] {
+                if let Some(Err(e)) = pattern.as_ref().map(|p| Regex::new(p)) {
+                    self.error(&format!("{}.{}", key, name), format!("invalid regex: {}", e));
+                }
+            }
+
+            if let Some(Err(e)) = rule.ip_filter.as_ref().map(IpFilter::new) {
+                self.error(&format!("{}.ip_filter", key), e);
+            }
+
+            if let Some(auth) = &rule.auth {
+                if let Err(e) = Authenticator::new(auth) {
+                    self.error(&format!("{}.auth", key), e);
+                }
+                if conf.ssl.is_none() {
+                    self.warning(&format!("{}.auth", key), String::from("credentials are sent in clear text without ssl"));
+                }
+            }
+
+            if let Some(jwt) = &rule.jwt {
+                if let Err(e) = JwtValidator::new(jwt) {
+                    self.error(&format!("{}.jwt", key), e);
+                }
+                let short_secret = jwt.keys.iter().any(|k| k.secret.as_ref().map(|s| s.len() < MIN_JWT_SECRET_LEN).unwrap_or(false));
+                if short_secret {
+                    self.warning(&format!("{}.jwt.keys", key), format!("HS256 secret shorter than {} bytes, easy to brute force", MIN_JWT_SECRET_LEN));
+                }
+                if rule.auth.as_ref().map(|a| a.basic.is_some()).unwrap_or(false) {
+                    self.error(&format!("{}.jwt", key), String::from("can not be combined with auth.basic, both read the Authorization header"));
+                }
+                if conf.ssl.is_none() {
+                    self.warning(&format!("{}.jwt", key), String::from("tokens are sent in clear text without ssl"));
+                }
+            }
+
+            if let Some(limit) = &rule.rate_limit {
+                self.check_rate_limit(&format!("{}.rate_limit", key), limit);
+            }
+
+            for method in rule.allowed_methods.iter().flatten() {
+                if method.is_empty() || !method.chars().all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)) {
+                    self.error(&format!("{}.allowed_methods", key), format!("{:?} is not a valid method name", method));
+                }
+            }
+
+            let uses_client_cert = rule.require_client_cert || rule.forward_client_cert
+                || rule.client_cert_subject.is_some() || rule.client_cert_san.is_some();
+            if uses_client_cert && client_auth == ReboundClientAuth::None {
+                self.warning(&key, String::from("uses client certificates, but ssl.client_auth is none so clients never send one"));
+            }
+        }
+    }
+
+    fn check_rate_limit(&mut self, key: &str, limit: &ReboundRateLimit) {
+
+        if let Err(e) = limit::validate(limit) {
+            self.error(key, e);
+        }
+        if limit.burst.is_some() && limit.algorithm == ReboundRateLimitAlgorithm::SlidingWindow {
+            self.warning(&format!("{}.burst", key), String::from("only used by the token_bucket algorithm"));
+        }
+    }
+}
+
+/// Locates keys in a YAML configuration file from its indentation
+///
+/// Only meant to point at the right line in error messages, keys it cannot follow are not located
+struct SourceMap {
+
+    /// Line number, indentation, whether the line starts a list item, and key of every meaningful line
+    ///
+    lines: Vec<(usize, usize, bool, Option<String>)>
+
+}
+
+impl SourceMap {
+
+    fn new(source: &str) -> Self {
+        let lines = source.lines()
+            .enumerate()
+            .filter_map(|(n, line)| {
+                let content = line.trim_start();
+                if content.is_empty() || content.starts_with('#') {
+                    return None;
+                }
+
+                // items are indented like their first key
+                let indent = line.len() - content.len();
+                let (item, content, indent) = match content.strip_prefix('-') {
+                    Some(rest) if rest.is_empty() || rest.starts_with(' ') => {
+                        let rest_trimmed = rest.trim_start();
+                        (true, rest_trimmed, indent + 1 + rest.len() - rest_trimmed.len())
+                    },
+                    _ => (false, content, indent),
+                };
+
+                Some((n + 1, indent, item, yaml_key(content)))
+            })
+            .collect();
+
+        SourceMap { lines }
+    }
+
+    /// Line of a key like rules[2].upstream or upstream_tls.example.com:8443.ca_bundle
+    ///
+    fn line_of(&self, key: &str) -> Option<usize> {
+
+        let pieces: Vec<&str> = key.split('.').collect();
+        let (mut start, mut end) = (0, self.lines.len());
+        let mut line = None;
+        let mut i = 0;
+
+        while i < pieces.len() {
+            let (name, indexes) = match pieces[i].find('[') {
+                Some(pos) => (&pieces[i][..pos], &pieces[i][pos..]),
+                None => (pieces[i], ""),
+            };
+
+            // keys like hosts may contain dots, the longest key found wins
+            let found = (i..pieces.len()).rev().find_map(|j| {
+                let candidate = if j == i { String::from(name) } else { format!("{}.{}", name, pieces[i+1..=j].join(".")) };
+                self.find_key(start, end, &candidate).map(|pos| (j, pos))
+            })?;
+            i = found.0 + 1;
+            let pos = found.1;
+            line = Some(self.lines[pos].0);
+            (start, end) = (pos + 1, self.block_end(pos, end));
+
+            for index in indexes.trim_matches(|c| c == '[' || c == ']').split("][").filter(|s| !s.is_empty()) {
+                let index: usize = index.parse().ok()?;
+                let pos = self.find_item(start, end, index)?;
+                line = Some(self.lines[pos].0);
+                (start, end) = (pos, self.item_end(pos, end));
+            }
+        }
+
+        line
+    }
+
+    /// Position of the key among the direct children of the block
+    ///
+    fn find_key(&self, start: usize, end: usize, key: &str) -> Option<usize> {
+        let indent = self.lines[start..end].iter().map(|l| l.1).min()?;
+        (start..end).find(|&p| self.lines[p].1 == indent && self.lines[p].3.as_deref() == Some(key))
+    }
+
+    /// Position of the nth list item of the block
+    ///
+    fn find_item(&self, start: usize, end: usize, index: usize) -> Option<usize> {
+        let indent = self.lines[start..end].iter().filter(|l| l.2).map(|l| l.1).min()?;
+        (start..end).filter(|&p| self.lines[p].2 && self.lines[p].1 == indent).nth(index)
+    }
+
+    /// End of the block nested under the key at pos
+    ///
+    fn block_end(&self, pos: usize, end: usize) -> usize {
+        let indent = self.lines[pos].1;
+        (pos + 1..end)
+            .find(|&p| self.lines[p].1 <= indent)
+            .unwrap_or(end)
+    }
+
+    /// End of the list item starting at pos
+    ///
+    fn item_end(&self, pos: usize, end: usize) -> usize {
+        let indent = self.lines[pos].1;
+        (pos + 1..end)
+            .find(|&p| self.lines[p].1 < indent || (self.lines[p].1 == indent && self.lines[p].2))
+            .unwrap_or(end)
+    }
+}
+
+/// Key of a YAML mapping line, quoted keys may contain colons
+///
+fn yaml_key(content: &str) -> Option<String> {
+    match content.chars().next() {
+        Some(q) if q == '"' || q == '\'' => {
+            let (key, rest) = content[1..].split_once(q)?;
+            rest.trim_start().starts_with(':').then(|| String::from(key))
+        },
+        _ => content
+            .find(": ")
+            .or_else(|| content.ends_with(':').then(|| content.len() - 1))
+            .map(|pos| content[..pos].trim().to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+
+    /// Writes a configuration file under the temp dir, named after the test so tests do not share files
+    ///
+    fn check_yaml(name: &str, yaml: &str) -> ConfReport {
+        let path = env::temp_dir().join(format!("rebound-check-{}-{}.yaml", std::process::id(), name));
+        fs::write(&path, yaml).unwrap();
+        check(&path.to_string_lossy())
+    }
+
+    fn issue<'a>(report: &'a ConfReport, key: &str) -> &'a ConfIssue {
+        report.issues.iter()
+            .find(|i| i.key.as_deref() == Some(key))
+            .unwrap_or_else(|| panic!("no issue on {} in {:?}", key, report.issues))
+    }
+
+    const RULES: &str = "host: 127.0.0.1
+port: 8080
+workers: 2
+rules:
+  - pattern: /api/
+    upstream: http://localhost:9000
+";
+
+    #[test]
+    fn accepts_valid_conf() {
+        let report = check_yaml("valid", RULES);
+        assert!(report.conf.is_some());
+        assert!(report.issues.is_empty(), "{:?}", report.issues);
+    }
+
+    #[test]
+    fn rejects_duplicate_paths() {
+        let report = check_yaml("duplicate", &format!("{}{}", RULES, "  - pattern: /api/
+    upstream: http://localhost:9001
+"));
+        let issue = issue(&report, "rules[1].pattern");
+        assert_eq!(issue.severity, Severity::Error);
+        assert_eq!(issue.line, Some(7));
+        assert!(issue.message.contains("same path as rules[0]"), "{}", issue.message);
+        assert_eq!(report.errors(), 1);
+    }
+
+    #[test]
+    fn rejects_bad_regex() {
+        let report = check_yaml("regex", &format!("{}{}", RULES, "    client_cert_subject: \"CN=(\"\n"));
+        let regex = issue(&report, "rules[0].client_cert_subject");
+        assert_eq!((regex.severity, regex.line), (Severity::Error, Some(7)));
+        assert!(regex.message.starts_with("invalid regex"), "{}", regex.message);
+        // client certificates are never sent without client_auth
+        assert_eq!(issue(&report, "rules[0]").severity, Severity::Warning);
+    }
+
+    #[test]
+    fn rejects_port_conflicts() {
+        let report = check_yaml("ports", &format!("{}{}", RULES, "metrics:
+  port: 8080
+admin:
+  port: 8080
+  token: 0123456789abcdef0123
+"));
+        assert_eq!(issue(&report, "metrics.port").line, Some(8));
+        assert_eq!(issue(&report, "admin.port").line, Some(10));
+        assert_eq!(report.errors(), 2);
+    }
+
+    #[test]
+    fn rejects_bad_ip_filters() {
+        let report = check_yaml("ip-filter", &format!("{}{}", RULES, "    ip_filter:
+      allow: [10.0.0.0/33]
+ip_filter:
+  deny:
+    - not-an-ip
+"));
+        assert_eq!(issue(&report, "rules[0].ip_filter").line, Some(7));
+        assert_eq!(issue(&report, "ip_filter").line, Some(9));
+        assert_eq!(report.errors(), 2);
+    }
+
+    #[test]
+    fn rejects_bad_auth_and_jwt() {
+        let report = check_yaml("auth", &format!("{}{}", RULES, "    auth:
+      basic:
+        htpasswd: /nonexistent/rebound/htpasswd
+    jwt:
+      keys: []
+"));
+        assert_eq!(issue(&report, "rules[0].auth").line, Some(7));
+        let jwt: Vec<&ConfIssue> = report.issues.iter()
+            .filter(|i| i.key.as_deref() == Some("rules[0].jwt") && i.severity == Severity::Error)
+            .collect();
+        assert!(jwt.iter().any(|i| i.message.contains("no key")), "{:?}", jwt);
+        assert!(jwt.iter().any(|i| i.message.contains("auth.basic")), "{:?}", jwt);
+        assert!(jwt.iter().all(|i| i.line == Some(10)));
+        // neither is worth it without ssl
+        assert_eq!(report.warnings(), 2);
+    }
+
+    #[test]
+    fn locates_parse_errors() {
+        let report = check_yaml("type", "host: 127.0.0.1\nport: 8080\nworkers: many\n");
+        assert!(report.conf.is_none());
+        let issue = issue(&report, "workers");
+        assert_eq!(issue.line, Some(3));
+        assert!(issue.message.starts_with("invalid type"), "{}", issue.message);
+    }
+
+    #[test]
+    fn source_map_follows_nesting() {
+        let map = SourceMap::new("# comment
+port: 8080
+upstream_tls:
+  legacy.example.com:8443:
+    ca_bundle: /etc/ca.pem
+  \"other:8443\":
+    ca_bundle: /etc/other.pem
+rules:
+  - pattern: /a/
+    upstream: http://a
+
+  - pattern: /b/
+    upstream: http://b
+    allowed_methods:
+      - GET
+      - PURGE
+");
+        assert_eq!(map.line_of("port"), Some(2));
+        assert_eq!(map.line_of("upstream_tls.legacy.example.com:8443.ca_bundle"), Some(5));
+        assert_eq!(map.line_of("upstream_tls.other:8443.ca_bundle"), Some(7));
+        assert_eq!(map.line_of("rules[0].upstream"), Some(10));
+        assert_eq!(map.line_of("rules[1]"), Some(12));
+        assert_eq!(map.line_of("rules[1].upstream"), Some(13));
+        assert_eq!(map.line_of("rules[1].allowed_methods[1]"), Some(16));
+        assert_eq!(map.line_of("rules[2]"), None);
+        assert_eq!(map.line_of("admin.port"), None);
+    }
+}
@@ -1,19 +1,25 @@
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use log::LevelFilter;
+use serde::{Deserialize, Deserializer, Serialize, de::Error};
+use std::{collections::HashMap, fmt};
 
+pub mod check;
 pub mod parser;
 
+/// Shown in place of secrets in the Debug output of the conf
+/// 
+const REDACTED: &str = "<redacted>";
+
 /// Rebound Log File
 /// 
-pub const REBOUND_LOG_DIR: &'static str = "REBOUND_LOG_DIR";
+pub const REBOUND_LOG_DIR: &str = "REBOUND_LOG_DIR";
 
 /// Rebound Conf File
 /// 
-pub const REBOUND_CONF_FILE: &'static str = "REBOUND_CONF_FILE";
+pub const REBOUND_CONF_FILE: &str = "REBOUND_CONF_FILE";
 
 /// Rebound Conf File
 /// 
-pub const REBOUND_DEFAULT_ERROR_FILE: &'static str = "REBOUND_DEFAULT_ERROR_FILE";
+pub const REBOUND_DEFAULT_ERROR_FILE: &str = "REBOUND_DEFAULT_ERROR_FILE";
 
 /// Configuration for Rebound Server
 ///
@@ -36,12 +42,89 @@ pub struct ReboundConf {
     /// 
     pub workers: usize,
 
+    /// Rebound execution mode, defaults = threaded
+    /// 
+    #[serde(default)]
+    pub mode: ReboundMode,
+
+    /// Rebound request queue between master and workers
+    /// 
+    #[serde(default)]
+    pub queue: ReboundQueue,
+
+    /// Rebound logging
+    /// 
+    #[serde(default)]
+    pub logging: ReboundLogging,
+
+    /// Rebound metrics listener, metrics are not served when unset
+    /// 
+    pub metrics: Option<ReboundMetrics>,
+
+    /// Rebound admin API listener, the admin API is not served when unset
+    /// 
+    pub admin: Option<ReboundAdmin>,
+
+    /// Rebound distributed tracing, requests are not traced when unset
+    /// 
+    pub tracing: Option<ReboundTracing>,
+
+    /// Client addresses allowed or denied on every request, before the lists of the rules
+    /// 
+    pub ip_filter: Option<ReboundIpFilter>,
+
+    /// Proxies, as IPs or CIDRs, trusted to tell the client address in X-Forwarded-For
+    /// 
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+
+    /// Rate limit of every request, before the limits of the rules, requests are not limited when unset
+    /// 
+    pub rate_limit: Option<ReboundRateLimit>,
+
+    /// Max time queued and in-flight requests get to finish on shutdown, in ms
+    /// defaults = 30000
+    #[serde(default = "drain_timeout_default")]
+    pub drain_timeout_ms: u64,
+
+    /// Connection pool used for every upstream host
+    /// 
+    #[serde(default)]
+    pub pool: ReboundPool,
+
+    /// Connection pools for specific upstream hosts, keyed by host or host:port
+    /// 
+    #[serde(default)]
+    pub upstream_pools: HashMap<String, ReboundPool>,
+
+    /// TLS settings for specific upstream hosts, keyed by host or host:port
+    /// 
+    #[serde(default)]
+    pub upstream_tls: HashMap<String, ReboundUpstreamTls>,
+
     /// Rebound Rules
     /// 
     pub rules: Option<Vec<ReboundRule>>
 
 }
 
+/// Execution mode of Rebound
+/// 
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReboundMode {
+
+    /// Requests are queued to worker threads, each handling one request at a time
+    /// 
+    #[default]
+    Threaded,
+
+    /// Requests are handled as tasks on an async runtime, many in flight at once
+    /// 
+    Async
+
+}
+
 /// SSL configuration for Rebound
 /// 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -51,19 +134,783 @@ pub struct ReboundSSL {
     /// 
     pub pub_cert: String,
 
+    /// File Path for Private Key
+    /// 
+    pub priv_key: String,
+
+    /// File Path for the CA bundle client certificates are verified against
+    /// 
+    #[serde(default)]
+    pub client_ca: Option<String>,
+
+    /// Whether clients have to present a certificate
+    /// defaults = none
+    #[serde(default)]
+    pub client_auth: ReboundClientAuth,
+
+    /// Certificates selected by the server name the client asks for (SNI),
+    /// pub_cert and priv_key are served when none matches
+    #[serde(default)]
+    pub certificates: Vec<ReboundCertificate>,
+
+    /// Max number of client connections, those over it are closed right away
+    /// defaults = 1024
+    #[serde(default = "ssl_max_connections_default")]
+    pub max_connections: usize,
+
+    /// Time in milliseconds after which a connection without traffic is closed
+    /// defaults = 60000
+    #[serde(default = "ssl_idle_timeout_default")]
+    pub idle_timeout_ms: u64
+
+}
+
+/// Certificate served for specific server names
+/// 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReboundCertificate {
+
+    /// Server names served with this certificate, either exact or wildcards like *.example.com
+    /// 
+    pub server_names: Vec<String>,
+
+    /// File Path for Public Certificate
+    /// 
+    pub pub_cert: String,
+
     /// File Path for Private Key
     /// 
     pub priv_key: String
 
 }
 
+/// Client certificate authentication on the Rebound listener
+/// 
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReboundClientAuth {
+
+    /// No client certificate is requested
+    /// 
+    #[default]
+    None,
+
+    /// A client certificate is requested, and verified when presented
+    /// 
+    Optional,
+
+    /// Clients without a valid certificate are refused during the handshake
+    /// 
+    Required
+
+}
+
+/// Request queue configuration for Rebound
+/// 
+/// Requests that can not be queued, or waited in the queue for too long,
+/// are answered with 503 and a Retry-After header
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReboundQueue {
+
+    /// Max number of queued requests
+    /// In async mode, max number of requests in flight
+    /// defaults = 1024
+    #[serde(default = "queue_capacity_default")]
+    pub capacity: usize,
+
+    /// Max time in milliseconds a request may wait in the queue, unlimited when unset
+    /// 
+    #[serde(default)]
+    pub max_wait_ms: Option<u64>,
+
+    /// Seconds sent in the Retry-After header of shed requests
+    /// defaults = 1
+    #[serde(default = "retry_after_default")]
+    pub retry_after: u64
+
+}
+
+impl Default for ReboundQueue {
+    fn default() -> Self {
+        ReboundQueue { capacity: queue_capacity_default(), max_wait_ms: None, retry_after: retry_after_default() }
+    }
+}
+
+/// Metrics listener of Rebound
+/// 
+/// Serves metrics in the Prometheus text format, apart from proxied requests
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReboundMetrics {
+
+    /// Listening host of the metrics
+    /// defaults = 127.0.0.1
+    #[serde(default = "local_host_default")]
+    pub host: String,
+
+    /// Listening port of the metrics
+    /// 
+    pub port: u16,
+
+    /// Path metrics are served on
+    /// defaults = /metrics
+    #[serde(default = "metrics_path_default")]
+    pub path: String
+
+}
+
+/// Admin API listener of Rebound
+/// 
+/// Every admin request must carry the token as `Authorization: Bearer <token>`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReboundAdmin {
+
+    /// Listening host of the admin API
+    /// defaults = 127.0.0.1
+    #[serde(default = "local_host_default")]
+    pub host: String,
+
+    /// Listening port of the admin API
+    /// 
+    pub port: u16,
+
+    /// Bearer token of admin requests
+    /// 
+    pub token: String
+
+}
+
+/// The token is redacted, so it does not show in the logs
+/// 
+impl fmt::Debug for ReboundAdmin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReboundAdmin")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("token", &REDACTED)
+            .finish()
+    }
+}
+
+/// Distributed tracing of Rebound
+/// 
+/// Spans are exported with OTLP over Http, in JSON, to a collector
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReboundTracing {
+
+    /// OTLP/HTTP traces endpoint of the collector
+    /// defaults = http://127.0.0.1:4318/v1/traces
+    #[serde(default = "tracing_endpoint_default")]
+    pub endpoint: String,
+
+    /// Share of new traces recorded, from 0 to 1, traces started upstream keep their sampling decision
+    /// defaults = 1
+    #[serde(default = "tracing_sampling_ratio_default")]
+    pub sampling_ratio: f64,
+
+    /// service.name of the exported spans
+    /// defaults = rebound
+    #[serde(default = "tracing_service_name_default")]
+    pub service_name: String
+
+}
+
+/// Authentication of the clients of a rule
+/// 
+/// Clients with valid credentials of any configured kind are let through,
+/// others are answered with 401
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ReboundAuth {
+
+    /// Http Basic authentication against an htpasswd file
+    /// 
+    #[serde(default)]
+    pub basic: Option<ReboundBasicAuth>,
+
+    /// Static API keys, sent in a header or a query param
+    /// 
+    #[serde(default)]
+    pub api_keys: Option<ReboundApiKeys>,
+
+    /// Remove the credentials from requests sent upstream
+    /// defaults = false
+    #[serde(default)]
+    pub strip_credentials: bool,
+
+    /// Header the authenticated user, or API key name, is sent upstream in
+    /// never taken from clients, the user is not sent when unset
+    #[serde(default)]
+    pub user_header: Option<String>
+
+}
+
+/// Http Basic authentication
+/// 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReboundBasicAuth {
+
+    /// File Path for the htpasswd file, with bcrypt (htpasswd -B) or SHA (htpasswd -s) passwords
+    /// read again when the rules are reloaded
+    pub htpasswd: String,
+
+    /// Realm of the Basic challenge
+    /// defaults = rebound
+    #[serde(default = "auth_realm_default")]
+    pub realm: String
+
+}
+
+/// Static API keys
+/// 
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ReboundApiKeys {
+
+    /// Header the key is sent in, e.g. X-Api-Key
+    /// 
+    #[serde(default)]
+    pub header: Option<String>,
+
+    /// Query param the key is sent in, e.g. api_key
+    /// 
+    #[serde(default)]
+    pub query: Option<String>,
+
+    /// Keys by name, the name is the authenticated user
+    /// never serialized, so keys do not show in the admin API
+    #[serde(default, skip_serializing)]
+    pub keys: HashMap<String, String>
+
+}
+
+/// Only the key names are shown, so keys do not show in the logs
+/// 
+impl fmt::Debug for ReboundApiKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReboundApiKeys")
+            .field("header", &self.header)
+            .field("query", &self.query)
+            .field("keys", &self.keys.keys().collect::<Vec<&String>>())
+            .finish()
+    }
+}
+
+/// Validation of the bearer JWT of the clients of a rule
+/// 
+/// Requests without a valid token are answered with 401,
+/// those whose token misses a required claim with 403
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ReboundJwt {
+
+    /// Keys tokens may be signed with
+    /// 
+    #[serde(default)]
+    pub keys: Vec<ReboundJwtKey>,
+
+    /// File Path for a JWKS file with more keys, read again when the rules are reloaded
+    /// 
+    #[serde(default)]
+    pub jwks_file: Option<String>,
+
+    /// Expected iss claim, any issuer is accepted when unset
+    /// 
+    #[serde(default)]
+    pub issuer: Option<String>,
+
+    /// Accepted aud claims, any audience is accepted when empty
+    /// 
+    #[serde(default)]
+    pub audience: Vec<String>,
+
+    /// Seconds of clock skew tolerated on exp and nbf
+    /// defaults = 30
+    #[serde(default = "jwt_leeway_default")]
+    pub leeway_s: u64,
+
+    /// Claims tokens must have, with their accepted values, any value is accepted when empty
+    /// nested claims are reached with dots, e.g. realm_access.roles
+    #[serde(default)]
+    pub required_claims: HashMap<String, Vec<String>>,
+
+    /// Claims sent upstream, with the header each is sent in, never taken from clients
+    /// 
+    #[serde(default)]
+    pub forward_claims: HashMap<String, String>,
+
+    /// Realm of the Bearer challenge
+    /// defaults = rebound
+    #[serde(default = "auth_realm_default")]
+    pub realm: String
+
+}
+
+/// Key JWTs may be signed with
+/// 
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReboundJwtKey {
+
+    /// Key ID tokens name in their header, the key is tried on every token when unset
+    /// 
+    #[serde(default)]
+    pub kid: Option<String>,
+
+    pub algorithm: ReboundJwtAlgorithm,
+
+    /// Shared secret, for HS256
+    /// never serialized, so secrets do not show in the admin API
+    #[serde(default, skip_serializing)]
+    pub secret: Option<String>,
+
+    /// File Path for the PEM public key, for RS256 and ES256
+    /// 
+    #[serde(default)]
+    pub public_key: Option<String>
+
+}
+
+/// The secret is redacted, so secrets do not show in the logs
+/// 
+impl fmt::Debug for ReboundJwtKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReboundJwtKey")
+            .field("kid", &self.kid)
+            .field("algorithm", &self.algorithm)
+            .field("secret", &self.secret.as_ref().map(|_| REDACTED))
+            .field("public_key", &self.public_key)
+            .finish()
+    }
+}
+
+/// JWT signature algorithm
+/// 
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum ReboundJwtAlgorithm {
+
+    /// HMAC with SHA-256
+    /// 
+    Hs256,
+
+    /// RSA PKCS#1 v1.5 with SHA-256
+    /// 
+    Rs256,
+
+    /// ECDSA on P-256 with SHA-256
+    /// 
+    Es256
+
+}
+
+impl ReboundJwtAlgorithm {
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReboundJwtAlgorithm::Hs256 => "HS256",
+            ReboundJwtAlgorithm::Rs256 => "RS256",
+            ReboundJwtAlgorithm::Es256 => "ES256",
+        }
+    }
+}
+
+/// Client addresses allowed or denied, as IPs or CIDRs, v4 or v6
+/// 
+/// Denied addresses are answered with 403, even when allowed.
+/// When allow is not empty, only the addresses it lists are allowed
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ReboundIpFilter {
+
+    /// Addresses allowed, every address is allowed when empty
+    /// 
+    #[serde(default)]
+    pub allow: Vec<String>,
+
+    /// Addresses denied
+    /// 
+    #[serde(default)]
+    pub deny: Vec<String>
+
+}
+
+/// Rate limit of Rebound requests
+/// 
+/// Requests over the limit are answered with 429, a Retry-After header and RateLimit-* headers.
+/// Limits are kept in memory, shared by every worker, and reset when the rules are reloaded
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReboundRateLimit {
+
+    /// Number of requests allowed per period
+    /// 
+    pub requests: u32,
+
+    /// Period requests are counted over, in ms
+    /// defaults = 1000
+    #[serde(default = "rate_limit_period_default")]
+    pub period_ms: u64,
+
+    /// Requests allowed at once above the rate, with the token bucket algorithm
+    /// defaults = requests
+    #[serde(default)]
+    pub burst: Option<u32>,
+
+    /// Rate limiting algorithm, defaults = token_bucket
+    /// 
+    #[serde(default)]
+    pub algorithm: ReboundRateLimitAlgorithm,
+
+    /// What requests are counted by, e.g. [client_ip] or [client_ip, {header: X-Api-Key}]
+    /// every request is counted together when empty
+    #[serde(default)]
+    pub key: Vec<ReboundRateLimitKey>
+
+}
+
+/// Rate limiting algorithm
+/// 
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReboundRateLimitAlgorithm {
+
+    /// Tokens refill at the rate, up to burst, each request takes one
+    /// 
+    #[default]
+    TokenBucket,
+
+    /// Requests of the last period are counted, weighting the previous period by its overlap
+    /// 
+    SlidingWindow
+
+}
+
+/// Part of the key requests are rate limited by
+/// 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReboundRateLimitKey {
+
+    /// Address of the client, behind the TLS listener
+    /// 
+    ClientIp,
+
+    /// Value of a request header, such as an API key
+    /// 
+    Header(String)
+
+}
+
+/// Logging configuration for Rebound
+/// 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReboundLogging {
+
+    /// Level of every module without its own level
+    /// defaults = info
+    #[serde(default = "log_level_default", deserialize_with = "deserialize_level")]
+    pub level: LevelFilter,
+
+    /// Levels of specific modules, like rebound::engine or isahc
+    /// 
+    #[serde(default, deserialize_with = "deserialize_levels")]
+    pub modules: HashMap<String, LevelFilter>,
+
+    /// Where logs go
+    /// defaults = stdout, plus file when a log dir is set
+    #[serde(default)]
+    pub targets: Option<Vec<ReboundLogTarget>>,
+
+    /// Directory of the rolling log files, the --log-dir flag wins over it
+    /// 
+    #[serde(default)]
+    pub dir: Option<String>,
+
+    /// Size in bytes at which the log file is rolled
+    /// defaults = 5MB
+    #[serde(default = "log_max_size_default")]
+    pub max_size: u64,
+
+    /// Number of rolled log files kept
+    /// defaults = 3
+    #[serde(default = "log_max_files_default")]
+    pub max_files: u32,
+
+    /// Encoding of log lines
+    /// defaults = pattern
+    #[serde(default)]
+    pub format: ReboundLogFormat,
+
+    /// log4rs pattern replacing the default one, for the pattern format
+    /// 
+    #[serde(default)]
+    pub pattern: Option<String>,
+
+    /// Full log4rs YAML configuration, replacing every other logging setting
+    /// 
+    #[serde(default)]
+    pub log4rs_file: Option<String>,
+
+    /// How each handled request is logged
+    /// defaults = summary
+    #[serde(default)]
+    pub requests: ReboundRequestLog,
+
+    /// Access log written after each response, disabled when unset
+    /// 
+    #[serde(default)]
+    pub access: Option<ReboundAccessLog>
+
+}
+
+impl Default for ReboundLogging {
+    fn default() -> Self {
+        ReboundLogging {
+            level: log_level_default(),
+            modules: HashMap::new(),
+            targets: None,
+            dir: None,
+            max_size: log_max_size_default(),
+            max_files: log_max_files_default(),
+            format: ReboundLogFormat::default(),
+            pattern: None,
+            log4rs_file: None,
+            requests: ReboundRequestLog::default(),
+            access: None
+        }
+    }
+}
+
+/// Output of Rebound logs
+/// 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReboundLogTarget {
+
+    Stdout,
+
+    /// Rolling files in the log dir
+    /// 
+    File,
+
+    /// Discards logs
+    /// 
+    None
+
+}
+
+/// Encoding of Rebound log lines
+/// 
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReboundLogFormat {
+
+    #[default]
+    Pattern,
+
+    /// One JSON object per line
+    /// 
+    Json
+
+}
+
+/// Logging of handled requests
+/// 
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReboundRequestLog {
+
+    /// Debug dump of the whole request, headers included
+    /// 
+    Full,
+
+    /// Method, url and client address
+    /// 
+    #[default]
+    Summary,
+
+    None
+
+}
+
+/// Access log of Rebound
+/// 
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ReboundAccessLog {
+
+    /// Format of access log lines
+    /// defaults = combined
+    #[serde(default)]
+    pub format: ReboundAccessLogFormat,
+
+    /// File of the access log, rolled like the other logs
+    /// The access log goes to the logging targets when unset
+    #[serde(default)]
+    pub file: Option<String>
+
+}
+
+/// Format of Rebound access log lines
+/// 
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReboundAccessLogFormat {
+
+    /// NCSA Combined, followed by the Rebound fields as key=value
+    /// 
+    #[default]
+    Combined,
+
+    /// One JSON object per line
+    /// 
+    Json
+
+}
+
+/// Upstream connection pool configuration for Rebound
+/// 
+/// Unset values fall back to the global pool, then to the client defaults
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ReboundPool {
+
+    /// Max number of idle connections kept for reuse
+    /// 
+    #[serde(default)]
+    pub max_idle: Option<usize>,
+
+    /// Milliseconds an idle connection is kept before being closed
+    /// 
+    #[serde(default)]
+    pub idle_timeout_ms: Option<u64>,
+
+    /// Max number of simultaneous connections to a host, unlimited when 0
+    /// 
+    #[serde(default)]
+    pub max_connections_per_host: Option<usize>,
+
+    /// Disable Nagle's algorithm on upstream connections
+    /// 
+    #[serde(default)]
+    pub tcp_nodelay: Option<bool>,
+
+    /// Milliseconds between TCP keepalive probes, disabled when unset
+    /// 
+    #[serde(default)]
+    pub tcp_keepalive_ms: Option<u64>,
+
+    /// Milliseconds a request may take upstream, from connecting to reading the whole response,
+    /// unlimited when unset
+    #[serde(default)]
+    pub request_timeout_ms: Option<u64>,
+
+    /// Limit of requests in flight to a host, unlimited when unset
+    /// 
+    #[serde(default)]
+    pub concurrency: Option<ReboundConcurrency>
+
+}
+
+impl ReboundPool {
+
+    /// Pool settings with unset values taken from the given pool
+    /// 
+    pub fn or(&self, other: &ReboundPool) -> ReboundPool {
+        ReboundPool {
+            max_idle: self.max_idle.or(other.max_idle),
+            idle_timeout_ms: self.idle_timeout_ms.or(other.idle_timeout_ms),
+            max_connections_per_host: self.max_connections_per_host.or(other.max_connections_per_host),
+            tcp_nodelay: self.tcp_nodelay.or(other.tcp_nodelay),
+            tcp_keepalive_ms: self.tcp_keepalive_ms.or(other.tcp_keepalive_ms),
+            request_timeout_ms: self.request_timeout_ms.or(other.request_timeout_ms),
+            concurrency: self.concurrency.clone().or_else(|| other.concurrency.clone())
+        }
+    }
+}
+
+/// Concurrency limit of an upstream host
+/// 
+/// Requests over the limit wait for a request in flight to finish,
+/// and are answered with 503 when none finishes in time
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReboundConcurrency {
+
+    /// Max number of requests in flight to the host
+    /// 
+    pub max_in_flight: usize,
+
+    /// Max time in milliseconds a request may wait for a request in flight to finish
+    /// defaults = 100
+    #[serde(default = "concurrency_queue_timeout_default")]
+    pub queue_timeout_ms: u64,
+
+    /// Adapt the limit to the upstream latency, between min_in_flight and max_in_flight
+    /// the limit stays at max_in_flight when unset
+    #[serde(default)]
+    pub adaptive: Option<ReboundAdaptiveConcurrency>
+
+}
+
+/// Adaptive concurrency limit, increased additively while the upstream keeps up,
+/// and decreased multiplicatively when it slows down or fails
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReboundAdaptiveConcurrency {
+
+    /// Lowest limit of requests in flight
+    /// defaults = 1
+    #[serde(default = "adaptive_min_in_flight_default")]
+    pub min_in_flight: usize,
+
+    /// Upstream latency in milliseconds above which the limit is decreased
+    /// 
+    pub latency_ms: u64,
+
+    /// Factor the limit is multiplied by when decreased, between 0 and 1
+    /// defaults = 0.9
+    #[serde(default = "adaptive_backoff_default")]
+    pub backoff: f64
+
+}
+
+/// TLS configuration for connections to an upstream host
+/// 
+/// Keyed by host:port or by host, a host entry applies to every port of the host without its own entry
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ReboundUpstreamTls {
+
+    /// File Path for the CA bundle trusted instead of the system one
+    /// 
+    #[serde(default)]
+    pub ca_bundle: Option<String>,
+
+    /// File Path for the PEM client certificate presented to the upstream
+    /// 
+    #[serde(default)]
+    pub client_cert: Option<String>,
+
+    /// File Path for the PEM private key of the client certificate
+    /// 
+    #[serde(default)]
+    pub client_key: Option<String>,
+
+    /// Server name sent in SNI and verified, instead of the upstream host
+    /// 
+    #[serde(default)]
+    pub sni: Option<String>,
+
+    /// Minimum TLS version accepted, only "1.2" is supported
+    /// the upstream client can not set protocol versions, "1.2" restricts the cipher list
+    /// so TLS 1.0 and 1.1 fail, and "1.3" can not be enforced
+    #[serde(default)]
+    pub min_version: Option<String>,
+
+    /// Skip certificate and host name verification, for dev environments only
+    /// defaults = false
+    #[serde(default)]
+    pub insecure_skip_verify: bool
+
+}
+
 /// Rebound Rule
 /// 
 /// Describe the Rebound rule with a pattern and which proxy location to send to
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ReboundRule {
 
-    ///
+    /// Path pattern the rule applies to
     /// 
     pub pattern: String,
 
@@ -87,7 +934,53 @@ pub struct ReboundRule {
     #[serde(default)]
     pub additional_query: HashMap<String, String>,
 
-    ///
+    /// Http methods allowed on this rule, including non standard ones
+    /// such as PROPFIND or PURGE. All methods are allowed when unset
+    #[serde(default)]
+    pub allowed_methods: Option<Vec<String>>,
+
+    /// Reject clients without a verified certificate
+    /// defaults = false
+    #[serde(default)]
+    pub require_client_cert: bool,
+
+    /// Regex the client certificate subject has to match, e.g. "CN=billing-.*"
+    /// 
+    #[serde(default)]
+    pub client_cert_subject: Option<String>,
+
+    /// Regex one of the client certificate subject alternative names has to match
+    /// 
+    #[serde(default)]
+    pub client_cert_san: Option<String>,
+
+    /// Forward the client certificate subject, SANs and fingerprint upstream
+    /// as X-Client-Cert-Subject, X-Client-Cert-San and X-Client-Cert-Fingerprint
+    /// defaults = false
+    #[serde(default)]
+    pub forward_client_cert: bool,
+
+    /// Client addresses allowed or denied on the rule
+    /// 
+    #[serde(default)]
+    pub ip_filter: Option<ReboundIpFilter>,
+
+    /// Credentials clients need on the rule, requests are not authenticated when unset
+    /// 
+    #[serde(default)]
+    pub auth: Option<ReboundAuth>,
+
+    /// Bearer JWT clients need on the rule, tokens are not validated when unset
+    /// 
+    #[serde(default)]
+    pub jwt: Option<ReboundJwt>,
+
+    /// Rate limit of the requests routed by the rule, requests are not limited when unset
+    /// 
+    #[serde(default)]
+    pub rate_limit: Option<ReboundRateLimit>,
+
+    /// Upstream location requests are proxied to
     /// 
     pub upstream: String
 
@@ -95,3 +988,38 @@ pub struct ReboundRule {
 
 fn preserve_hdrs_default() -> bool {true}
 fn preserve_query_default() -> bool {true}
+fn queue_capacity_default() -> usize {1024}
+fn ssl_max_connections_default() -> usize {1024}
+fn ssl_idle_timeout_default() -> u64 {60000}
+fn retry_after_default() -> u64 {1}
+fn drain_timeout_default() -> u64 {30000}
+fn local_host_default() -> String {String::from("127.0.0.1")}
+fn tracing_endpoint_default() -> String {String::from("http://127.0.0.1:4318/v1/traces")}
+fn tracing_sampling_ratio_default() -> f64 {1.0}
+fn tracing_service_name_default() -> String {String::from("rebound")}
+fn jwt_leeway_default() -> u64 {30}
+fn auth_realm_default() -> String {String::from("rebound")}
+fn concurrency_queue_timeout_default() -> u64 {100}
+fn adaptive_min_in_flight_default() -> usize {1}
+fn adaptive_backoff_default() -> f64 {0.9}
+fn rate_limit_period_default() -> u64 {1000}
+fn metrics_path_default() -> String {String::from("/metrics")}
+fn log_level_default() -> LevelFilter {LevelFilter::Info}
+fn log_max_size_default() -> u64 {5*1024*1024}
+fn log_max_files_default() -> u32 {3}
+
+// levels are read from strings, config can not hand enums to LevelFilter
+fn deserialize_level<'de, D: Deserializer<'de>>(d: D) -> Result<LevelFilter, D::Error> {
+    let level = String::deserialize(d)?;
+    level.parse().map_err(|_| D::Error::custom(format!("unknown log level {}, expected off, error, warn, info, debug or trace", level)))
+}
+
+fn deserialize_levels<'de, D: Deserializer<'de>>(d: D) -> Result<HashMap<String, LevelFilter>, D::Error> {
+    HashMap::<String, String>::deserialize(d)?
+        .into_iter()
+        .map(|(module, level)| match level.parse() {
+            Ok(l) => Ok((module, l)),
+            Err(_) => Err(D::Error::custom(format!("unknown log level {} for {}, expected off, error, warn, info, debug or trace", level, module))),
+        })
+        .collect()
+}
@@ -1,22 +1,8 @@
-use std::{path::Path, io::Read};
-use config::Config;
+use std::io::Read;
 
-use crate::conf::ReboundConf;
-
-
-pub fn parse(file: String) -> ReboundConf {
-    let conf = Config::builder()
-        .add_source(config::File::from(Path::new(file.as_str())))
-        .build()
-        .unwrap();
-
-    conf.try_deserialize::<ReboundConf>().unwrap()
-}
-
-pub fn read_ssl_file(file: String) -> Vec<u8> {
-    let mut f = std::fs::File::open(&file).expect("no file found");
-    let metadata = std::fs::metadata(&file).expect("unable to read metadata");
-    let mut buffer = vec![0; metadata.len() as usize];
-    f.read(&mut buffer).expect("failed to read to buffer");
-    buffer
+pub fn read_ssl_file(file: &str) -> Result<Vec<u8>, String> {
+    let mut f = std::fs::File::open(file).map_err(|e| format!("failed to open {}: {}", file, e))?;
+    let mut buffer = Vec::new();
+    f.read_to_end(&mut buffer).map_err(|e| format!("failed to read {}: {}", file, e))?;
+    Ok(buffer)
 }
\ No newline at end of file
@@ -0,0 +1,248 @@
+use std::{net::IpAddr, str::FromStr};
+
+use crate::conf::ReboundIpFilter;
+
+use super::error::ReboundError;
+
+/// Range of IP addresses, from an IP or a CIDR, v4 or v6
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IpRange {
+
+    /// First address of the range, host bits cleared
+    ///
+    network: IpAddr,
+
+    prefix: u8
+
+}
+
+impl IpRange {
+
+    /// Whether the address is in the range, IPv4-mapped IPv6 addresses included
+    ///
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.network, addr.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                mask(u32::from(addr) as u128, self.prefix, 32) == u32::from(network) as u128
+            },
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                mask(u128::from(addr), self.prefix, 128) == u128::from(network)
+            },
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+
+        let (addr, prefix) = s.trim().split_once('/').map(|(a, p)| (a, Some(p))).unwrap_or((s.trim(), None));
+        let addr = IpAddr::from_str(addr)
+            .map_err(|_| format!("{} is not an IP or CIDR", s))?
+            .to_canonical();
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse::<u8>()
+                .ok()
+                .filter(|p| *p <= bits)
+                .ok_or_else(|| format!("{} has an invalid prefix length, expected 0 to {}", s, bits))?,
+            None => bits,
+        };
+
+        let network = match addr {
+            IpAddr::V4(a) => IpAddr::V4((mask(u32::from(a) as u128, prefix, 32) as u32).into()),
+            IpAddr::V6(a) => IpAddr::V6(mask(u128::from(a), prefix, 128).into()),
+        };
+        Ok(IpRange { network, prefix })
+    }
+}
+
+/// Keeps the prefix bits of an address of the given width
+///
+fn mask(addr: u128, prefix: u8, bits: u8) -> u128 {
+    match prefix {
+        0 => 0,
+        p => addr & (u128::MAX << (bits - p)) & (u128::MAX >> (128 - bits as u32)),
+    }
+}
+
+/// Parses a list of IPs and CIDRs
+///
+pub fn parse_ranges(ranges: &[String]) -> Result<Vec<IpRange>, String> {
+    ranges.iter().map(|r| IpRange::from_str(r)).collect()
+}
+
+/// Allow and deny lists of client addresses
+///
+#[derive(Clone, Debug)]
+pub struct IpFilter {
+
+    allow: Vec<IpRange>,
+
+    deny: Vec<IpRange>
+
+}
+
+impl IpFilter {
+
+    pub fn new(conf: &ReboundIpFilter) -> Result<Self, String> {
+        Ok(IpFilter { allow: parse_ranges(&conf.allow)?, deny: parse_ranges(&conf.deny)? })
+    }
+
+    /// Checks the client address is allowed, unknown addresses are only allowed without allow list
+    ///
+    pub fn check(&self, addr: Option<IpAddr>) -> Result<(), ReboundError> {
+        match addr {
+            Some(addr) if self.deny.iter().any(|r| r.contains(&addr)) => {
+                Err(ReboundError::Forbidden(format!("client address denied: {}", addr)))
+            },
+            Some(addr) if !self.allow.is_empty() && !self.allow.iter().any(|r| r.contains(&addr)) => {
+                Err(ReboundError::Forbidden(format!("client address not allowed: {}", addr)))
+            },
+            None if !self.allow.is_empty() => Err(ReboundError::Forbidden(String::from("client address unknown"))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Address of the client, read from X-Forwarded-For when the peer is a trusted proxy
+///
+/// Forwarded addresses are walked from the closest one, skipping trusted proxies,
+/// so clients cannot pass themselves off by sending the header
+pub fn client_addr(peer: IpAddr, forwarded_for: &[&str], trusted: &[IpRange]) -> IpAddr {
+
+    let mut addr = peer.to_canonical();
+    let forwarded = forwarded_for
+        .iter()
+        .rev()
+        .flat_map(|h| h.rsplit(','))
+        .map(|a| IpAddr::from_str(a.trim()));
+
+    for next in forwarded {
+        if !trusted.iter().any(|r| r.contains(&addr)) {
+            break;
+        }
+        match next {
+            Ok(next) => addr = next.to_canonical(),
+            Err(_) => break,
+        }
+    }
+    addr
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        IpAddr::from_str(s).unwrap()
+    }
+
+    fn ranges(r: &[&str]) -> Vec<IpRange> {
+        parse_ranges(&r.iter().map(|s| s.to_string()).collect::<Vec<String>>()).unwrap()
+    }
+
+    #[test]
+    fn cidr_clears_host_bits() {
+        assert_eq!(IpRange::from_str("10.1.2.3/8").unwrap(), IpRange::from_str("10.0.0.0/8").unwrap());
+        assert_eq!(IpRange::from_str("2001:db8::1/32").unwrap(), IpRange::from_str("2001:db8::/32").unwrap());
+    }
+
+    #[test]
+    fn cidr_contains() {
+        let range = IpRange::from_str("192.168.1.0/24").unwrap();
+        assert!(range.contains(&ip("192.168.1.0")));
+        assert!(range.contains(&ip("192.168.1.255")));
+        assert!(!range.contains(&ip("192.168.2.1")));
+        assert!(!range.contains(&ip("::1")));
+
+        let range = IpRange::from_str("2001:db8::/32").unwrap();
+        assert!(range.contains(&ip("2001:db8:ffff::1")));
+        assert!(!range.contains(&ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn single_ip_and_any() {
+        let range = IpRange::from_str("10.0.0.1").unwrap();
+        assert!(range.contains(&ip("10.0.0.1")));
+        assert!(!range.contains(&ip("10.0.0.2")));
+
+        assert!(IpRange::from_str("0.0.0.0/0").unwrap().contains(&ip("203.0.113.9")));
+        assert!(IpRange::from_str("::/0").unwrap().contains(&ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_v4_ranges() {
+        assert!(IpRange::from_str("10.0.0.0/8").unwrap().contains(&ip("::ffff:10.2.3.4")));
+        assert!(IpRange::from_str("::ffff:10.0.0.0/104").is_err());
+    }
+
+    #[test]
+    fn invalid_ranges() {
+        assert!(IpRange::from_str("10.0.0.0/33").is_err());
+        assert!(IpRange::from_str("::/129").is_err());
+        assert!(IpRange::from_str("10.0.0.0/x").is_err());
+        assert!(IpRange::from_str("example.com").is_err());
+        assert!(IpRange::from_str("").is_err());
+    }
+
+    #[test]
+    fn filter_deny_wins_over_allow() {
+        let filter = IpFilter::new(&ReboundIpFilter {
+            allow: vec![String::from("10.0.0.0/8")],
+            deny: vec![String::from("10.0.0.13")],
+        }).unwrap();
+
+        assert!(filter.check(Some(ip("10.0.0.1"))).is_ok());
+        assert!(matches!(filter.check(Some(ip("10.0.0.13"))), Err(ReboundError::Forbidden(_))));
+        assert!(matches!(filter.check(Some(ip("192.168.0.1"))), Err(ReboundError::Forbidden(_))));
+        assert!(filter.check(None).is_err());
+    }
+
+    #[test]
+    fn filter_without_allow_list() {
+        let filter = IpFilter::new(&ReboundIpFilter { allow: vec![], deny: vec![String::from("10.0.0.0/8")] }).unwrap();
+
+        assert!(filter.check(Some(ip("192.168.0.1"))).is_ok());
+        assert!(filter.check(None).is_ok());
+        assert!(filter.check(Some(ip("10.9.9.9"))).is_err());
+    }
+
+    #[test]
+    fn forwarded_for_ignored_from_untrusted_peer() {
+        let trusted = ranges(&["10.0.0.0/8"]);
+        assert_eq!(client_addr(ip("203.0.113.1"), &["198.51.100.7"], &trusted), ip("203.0.113.1"));
+        assert_eq!(client_addr(ip("203.0.113.1"), &["198.51.100.7"], &[]), ip("203.0.113.1"));
+    }
+
+    #[test]
+    fn forwarded_for_walks_trusted_proxies() {
+        let trusted = ranges(&["10.0.0.0/8"]);
+
+        assert_eq!(client_addr(ip("10.0.0.1"), &["198.51.100.7"], &trusted), ip("198.51.100.7"));
+        assert_eq!(client_addr(ip("10.0.0.1"), &["198.51.100.7, 10.0.0.2"], &trusted), ip("198.51.100.7"));
+        assert_eq!(client_addr(ip("10.0.0.1"), &["198.51.100.7", "10.0.0.2, 10.0.0.3"], &trusted), ip("198.51.100.7"));
+    }
+
+    #[test]
+    fn forwarded_for_stops_at_first_untrusted_address() {
+        let trusted = ranges(&["10.0.0.0/8"]);
+
+        // the client prepended a spoofed address, only the one the proxy saw counts
+        assert_eq!(client_addr(ip("10.0.0.1"), &["1.2.3.4, 198.51.100.7"], &trusted), ip("198.51.100.7"));
+    }
+
+    #[test]
+    fn forwarded_for_stops_at_invalid_address() {
+        let trusted = ranges(&["10.0.0.0/8"]);
+
+        assert_eq!(client_addr(ip("10.0.0.1"), &["198.51.100.7, garbage"], &trusted), ip("10.0.0.1"));
+        assert_eq!(client_addr(ip("10.0.0.1"), &[], &trusted), ip("10.0.0.1"));
+        assert_eq!(client_addr(ip("::ffff:10.0.0.1"), &["::ffff:198.51.100.7"], &trusted), ip("198.51.100.7"));
+    }
+}
@@ -0,0 +1,435 @@
+use std::{collections::{HashMap, HashSet}, fmt, fs, sync::{Mutex, MutexGuard}};
+use openssl::{base64, hash::{hash, MessageDigest}, memcmp};
+
+use crate::conf::ReboundAuth;
+
+use super::{error::ReboundError, request::ReboundRequest};
+
+const AUTHORIZATION_HDR: &str = "Authorization";
+
+/// Value of the API keys sent as query params, in logs
+///
+const REDACTED_PARAM: &str = "redacted";
+
+/// Max number of credentials remembered as verified, the cache is emptied past it
+///
+const VERIFIED_CACHE_SIZE: usize = 1024;
+
+/// Password of an htpasswd user
+///
+enum PasswordHash {
+
+    Bcrypt(String),
+
+    /// SHA-1 digest, from {SHA} entries
+    ///
+    Sha1(Vec<u8>)
+
+}
+
+/// Authenticates the clients of a rule
+///
+pub struct Authenticator {
+
+    conf: ReboundAuth,
+
+    /// htpasswd users
+    ///
+    users: HashMap<String, PasswordHash>,
+
+    /// Digests of Basic credentials already verified, sparing a bcrypt verification per request
+    ///
+    verified: Mutex<HashSet<Vec<u8>>>
+
+}
+
+impl fmt::Debug for Authenticator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Authenticator")
+            .field("users", &self.users.len())
+            .field("api_keys", &self.conf.api_keys.as_ref().map(|k| k.keys.len()).unwrap_or_default())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Authenticator {
+
+    /// Reads the htpasswd file of the rule, if any
+    ///
+    pub fn new(conf: &ReboundAuth) -> Result<Self, String> {
+
+        if conf.basic.is_none() && conf.api_keys.is_none() {
+            return Err(String::from("one of basic or api_keys is required"));
+        }
+        if let Some(api_keys) = &conf.api_keys {
+            if api_keys.header.is_none() && api_keys.query.is_none() {
+                return Err(String::from("api_keys needs a header or a query param to read keys from"));
+            }
+            if api_keys.keys.values().any(|k| k.is_empty()) {
+                return Err(String::from("api_keys must not be empty"));
+            }
+        }
+
+        let users = match &conf.basic {
+            Some(basic) => read_htpasswd(&basic.htpasswd)?,
+            None => HashMap::new(),
+        };
+
+        Ok(Authenticator { conf: conf.clone(), users, verified: Mutex::new(HashSet::new()) })
+    }
+
+    /// Authenticates the client, returning its user or API key name
+    ///
+    /// A request carrying an API key is only checked against the keys
+    pub fn authenticate(&self, req: &ReboundRequest) -> Result<String, ReboundError> {
+
+        if let Some(api_keys) = &self.conf.api_keys {
+            let given = api_keys.header
+                .as_ref()
+                .and_then(|h| header(req, h))
+                .or_else(|| api_keys.query.as_ref().and_then(|q| req.query_params.get(q)));
+
+            if let Some(given) = given {
+                return api_keys.keys
+                    .iter()
+                    .find(|(_, key)| key.len() == given.len() && memcmp::eq(key.as_bytes(), given.as_bytes()))
+                    .map(|(name, _)| name.clone())
+                    .ok_or_else(|| self.unauthorized("invalid API key"));
+            }
+        }
+
+        if self.conf.basic.is_some() {
+            if let Some(authorization) = header(req, AUTHORIZATION_HDR) {
+                let (user, password) = basic_credentials(authorization)
+                    .ok_or_else(|| self.unauthorized("invalid Basic credentials"))?;
+                return match self.verify(&user, &password) {
+                    true => Ok(user),
+                    false => Err(self.unauthorized(&format!("invalid password for {}", user))),
+                };
+            }
+        }
+
+        Err(self.unauthorized("credentials required"))
+    }
+
+    /// Removes the credentials from the upstream request when configured,
+    /// and sends the authenticated user in place of any the client sent
+    pub fn forward(&self, user: &str, req: &mut ReboundRequest) {
+
+        if self.conf.strip_credentials {
+            if self.conf.basic.is_some() {
+                req.headers.retain(|k, _| !k.eq_ignore_ascii_case(AUTHORIZATION_HDR));
+            }
+            if let Some(api_keys) = &self.conf.api_keys {
+                if let Some(h) = &api_keys.header {
+                    req.headers.retain(|k, _| !k.eq_ignore_ascii_case(h));
+                }
+                if let Some(q) = &api_keys.query {
+                    req.query_params.remove(q);
+                }
+            }
+        }
+
+        if let Some(user_header) = &self.conf.user_header {
+            req.headers.retain(|k, _| !k.eq_ignore_ascii_case(user_header));
+            req.headers.insert(user_header.clone(), user.to_string());
+        }
+    }
+
+    fn verify(&self, user: &str, password: &str) -> bool {
+
+        let expected = match self.users.get(user) {
+            Some(expected) => expected,
+            None => return false,
+        };
+
+        let digest = match hash(MessageDigest::sha256(), format!("{}:{}", user, password).as_bytes()) {
+            Ok(digest) => digest.to_vec(),
+            Err(_) => return false,
+        };
+        if self.verified().contains(&digest) {
+            return true;
+        }
+
+        // not holding the cache, bcrypt is slow on purpose
+        let valid = match expected {
+            PasswordHash::Bcrypt(h) => bcrypt::verify(password, h).unwrap_or(false),
+            PasswordHash::Sha1(d) => hash(MessageDigest::sha1(), password.as_bytes())
+                .map(|given| given.len() == d.len() && memcmp::eq(&given, d))
+                .unwrap_or(false),
+        };
+
+        if valid {
+            let mut verified = self.verified();
+            if verified.len() >= VERIFIED_CACHE_SIZE {
+                verified.clear();
+            }
+            verified.insert(digest);
+        }
+        valid
+    }
+
+    fn verified(&self) -> MutexGuard<'_, HashSet<Vec<u8>>> {
+        match self.verified.lock() {
+            Ok(verified) => verified,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn unauthorized(&self, reason: &str) -> ReboundError {
+        let challenge = self.conf.basic.as_ref().map(|b| format!("Basic realm=\"{}\"", b.realm.replace('"', "")));
+        ReboundError::Unauthorized(String::from(reason), challenge)
+    }
+}
+
+/// Value of a request header, whatever its case
+///
+fn header<'a>(req: &'a ReboundRequest, name: &str) -> Option<&'a String> {
+    req.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v)
+}
+
+/// User and password of a Basic Authorization header
+///
+fn basic_credentials(authorization: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = authorization.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(base64::decode_block(encoded.trim()).ok()?).ok()?;
+    decoded.split_once(':').map(|(u, p)| (u.to_string(), p.to_string()))
+}
+
+/// Reads the users of an htpasswd file
+///
+fn read_htpasswd(file: &str) -> Result<HashMap<String, PasswordHash>, String> {
+
+    let content = fs::read_to_string(file).map_err(|e| format!("cannot read {}: {}", file, e))?;
+    let mut users = HashMap::new();
+
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (user, password) = line
+            .split_once(':')
+            .ok_or_else(|| format!("{}:{}: expected user:password", file, i + 1))?;
+        let password = if ["$2a$", "$2b$", "$2x$", "$2y$"].iter().any(|p| password.starts_with(p)) {
+            PasswordHash::Bcrypt(password.to_string())
+        }
+        else if let Some(digest) = password.strip_prefix("{SHA}") {
+            PasswordHash::Sha1(
+                base64::decode_block(digest)
+                    .ok()
+                    .filter(|d| d.len() == 20)
+                    .ok_or_else(|| format!("{}:{}: invalid SHA password for {}", file, i + 1, user))?
+            )
+        }
+        else {
+            return Err(format!("{}:{}: unsupported password hash for {}, expected bcrypt (htpasswd -B) or SHA (htpasswd -s)", file, i + 1, user));
+        };
+        users.insert(user.to_string(), password);
+    }
+
+    Ok(users)
+}
+
+/// Url with the values of the given query params redacted, so API keys do not show in logs
+///
+pub fn redact_query(url: &str, params: &[String]) -> String {
+
+    let (path, query) = match url.split_once('?') {
+        Some(parts) if !params.is_empty() => parts,
+        _ => return url.to_string(),
+    };
+
+    let query = query
+        .split('&')
+        .map(|pair| match form_urlencoded::parse(pair.as_bytes()).next() {
+            Some((k, _)) if params.iter().any(|p| *p == k) => {
+                format!("{}={}", pair.split('=').next().unwrap_or_default(), REDACTED_PARAM)
+            },
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join("&");
+    format!("{}?{}", path, query)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::env;
+    use serde_json::json;
+    use tiny_http::{Header, Method};
+
+    use crate::engine::request::ReboundIngressRequestBuilder;
+
+    use super::*;
+
+    /// Writes an htpasswd file under the temp dir, named after the test so tests do not share files
+    ///
+    fn htpasswd(name: &str, content: &str) -> String {
+        let path = env::temp_dir().join(format!("rebound-htpasswd-{}-{}", std::process::id(), name));
+        fs::write(&path, content).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    fn sha_entry(user: &str, password: &str) -> String {
+        format!("{}:{{SHA}}{}", user, base64::encode_block(&hash(MessageDigest::sha1(), password.as_bytes()).unwrap()))
+    }
+
+    fn authenticator(conf: serde_json::Value) -> Result<Authenticator, String> {
+        Authenticator::new(&serde_json::from_value(conf).unwrap())
+    }
+
+    fn basic(user: &str, password: &str) -> String {
+        format!("Basic {}", base64::encode_block(format!("{}:{}", user, password).as_bytes()))
+    }
+
+    fn request(url: &str, headers: &[(&str, &str)]) -> ReboundRequest {
+        let headers: Vec<Header> = headers
+            .iter()
+            .map(|(k, v)| Header::from_bytes(k.as_bytes(), v.as_bytes()).unwrap())
+            .collect();
+        ReboundIngressRequestBuilder::new()
+            .with_url(url.to_string())
+            .with_headers(&headers)
+            .with_method(&Method::Get)
+            .build()
+    }
+
+    #[test]
+    fn htpasswd_bcrypt_and_sha() {
+        let file = htpasswd("formats", &format!(
+            "# users\n\nalice:{}\n{}\n",
+            bcrypt::hash("wonderland", 4).unwrap(),
+            sha_entry("bob", "builder")
+        ));
+        let auth = authenticator(json!({ "basic": { "htpasswd": file } })).unwrap();
+
+        assert_eq!(auth.authenticate(&request("/", &[(AUTHORIZATION_HDR, &basic("alice", "wonderland"))])).unwrap(), "alice");
+        assert_eq!(auth.authenticate(&request("/", &[(AUTHORIZATION_HDR, &basic("bob", "builder"))])).unwrap(), "bob");
+        assert!(auth.authenticate(&request("/", &[(AUTHORIZATION_HDR, &basic("alice", "builder"))])).is_err());
+        assert!(auth.authenticate(&request("/", &[(AUTHORIZATION_HDR, &basic("bob", "wonderland"))])).is_err());
+        assert!(auth.authenticate(&request("/", &[(AUTHORIZATION_HDR, &basic("carol", "wonderland"))])).is_err());
+    }
+
+    #[test]
+    fn verified_credentials_are_cached_per_password() {
+        let file = htpasswd("cache", &format!("alice:{}\n", bcrypt::hash("wonderland", 4).unwrap()));
+        let auth = authenticator(json!({ "basic": { "htpasswd": file } })).unwrap();
+
+        assert!(auth.verify("alice", "wonderland"));
+        assert_eq!(auth.verified().len(), 1);
+        assert!(auth.verify("alice", "wonderland"));
+        assert!(!auth.verify("alice", "wonderland!"));
+        assert_eq!(auth.verified().len(), 1);
+    }
+
+    /// Error reading an htpasswd file of the given content
+    ///
+    fn htpasswd_error(name: &str, content: &str) -> String {
+        read_htpasswd(&htpasswd(name, content)).map(|_| ()).unwrap_err()
+    }
+
+    #[test]
+    fn htpasswd_rejects_unsupported_hashes() {
+        assert!(htpasswd_error("crypt", "alice:rl0uE2YOVkMrA\n").contains("unsupported"));
+        assert!(htpasswd_error("md5", "alice:$apr1$xyz$abcdefghijklmnopqrstu/\n").contains("unsupported"));
+        assert!(htpasswd_error("plain", "alice:wonderland\n").contains("unsupported"));
+        assert!(htpasswd_error("short-sha", "alice:{SHA}AAAA\n").contains("invalid SHA"));
+        assert!(htpasswd_error("no-colon", "alice\n").contains("expected user:password"));
+        assert!(read_htpasswd("/nonexistent/rebound/htpasswd").is_err());
+    }
+
+    #[test]
+    fn basic_credentials_parsing() {
+        assert_eq!(basic_credentials(&basic("alice", "pass:word")), Some((String::from("alice"), String::from("pass:word"))));
+        assert_eq!(basic_credentials(&format!("basic  {} ", base64::encode_block(b"alice:pw"))), Some((String::from("alice"), String::from("pw"))));
+        assert_eq!(basic_credentials("Bearer abc"), None);
+        assert_eq!(basic_credentials("Basic !!!"), None);
+        assert_eq!(basic_credentials(&format!("Basic {}", base64::encode_block(b"no-colon"))), None);
+        assert_eq!(basic_credentials("Basic"), None);
+    }
+
+    #[test]
+    fn basic_challenge() {
+        let file = htpasswd("challenge", &sha_entry("bob", "builder"));
+        let auth = authenticator(json!({ "basic": { "htpasswd": file, "realm": "my \"realm\"" } })).unwrap();
+
+        match auth.authenticate(&request("/", &[])) {
+            Err(ReboundError::Unauthorized(_, Some(challenge))) => assert_eq!(challenge, "Basic realm=\"my realm\""),
+            r => panic!("unexpected {:?}", r.map_err(|e| e.kind())),
+        }
+        assert!(auth.authenticate(&request("/", &[(AUTHORIZATION_HDR, "Basic !!!")])).is_err());
+    }
+
+    #[test]
+    fn api_keys_from_header_and_query() {
+        let auth = authenticator(json!({ "api_keys": {
+            "header": "X-Api-Key",
+            "query": "api_key",
+            "keys": { "ci": "key-ci", "ops": "key/ops+1" }
+        } })).unwrap();
+
+        assert_eq!(auth.authenticate(&request("/", &[("x-api-key", "key-ci")])).unwrap(), "ci");
+        assert_eq!(auth.authenticate(&request("/?api_key=key%2Fops%2B1", &[])).unwrap(), "ops");
+        assert!(auth.authenticate(&request("/", &[("X-Api-Key", "key-c")])).is_err());
+        assert!(auth.authenticate(&request("/?api_key=nope", &[])).is_err());
+        assert!(matches!(auth.authenticate(&request("/", &[])), Err(ReboundError::Unauthorized(_, None))));
+    }
+
+    #[test]
+    fn api_key_requests_skip_basic() {
+        let file = htpasswd("api-key-first", &sha_entry("bob", "builder"));
+        let auth = authenticator(json!({
+            "basic": { "htpasswd": file },
+            "api_keys": { "header": "X-Api-Key", "keys": { "ci": "key-ci" } }
+        })).unwrap();
+
+        let both = request("/", &[("X-Api-Key", "wrong"), (AUTHORIZATION_HDR, &basic("bob", "builder"))]);
+        assert!(auth.authenticate(&both).is_err());
+        assert_eq!(auth.authenticate(&request("/", &[(AUTHORIZATION_HDR, &basic("bob", "builder"))])).unwrap(), "bob");
+    }
+
+    #[test]
+    fn invalid_conf() {
+        assert!(authenticator(json!({})).is_err());
+        assert!(authenticator(json!({ "api_keys": { "keys": { "ci": "key-ci" } } })).is_err());
+        assert!(authenticator(json!({ "api_keys": { "header": "X-Api-Key", "keys": { "ci": "" } } })).is_err());
+    }
+
+    #[test]
+    fn forward_strips_credentials_and_sets_user() {
+        let file = htpasswd("forward", &sha_entry("bob", "builder"));
+        let auth = authenticator(json!({
+            "basic": { "htpasswd": file },
+            "api_keys": { "header": "X-Api-Key", "query": "api_key", "keys": { "ci": "key-ci" } },
+            "strip_credentials": true,
+            "user_header": "X-User"
+        })).unwrap();
+        let mut req = request("/?api_key=key-ci&page=2", &[("authorization", "Basic Ym9iOmJ1aWxkZXI="), ("x-api-key", "key-ci"), ("x-user", "admin")]);
+
+        auth.forward("ci", &mut req);
+
+        assert!(header(&req, AUTHORIZATION_HDR).is_none());
+        assert!(header(&req, "X-Api-Key").is_none());
+        assert!(!req.query_params.contains_key("api_key"));
+        assert_eq!(req.query_params.get("page").map(String::as_str), Some("2"));
+        assert_eq!(req.headers.get("X-User").map(String::as_str), Some("ci"));
+        assert_eq!(req.headers.keys().filter(|k| k.eq_ignore_ascii_case("X-User")).count(), 1);
+    }
+
+    #[test]
+    fn redacts_query_params() {
+        let params = vec![String::from("api_key")];
+
+        assert_eq!(redact_query("/a?api_key=secret&page=2", &params), "/a?api_key=redacted&page=2");
+        assert_eq!(redact_query("/a?page=2&api%5Fkey=secret", &params), "/a?page=2&api%5Fkey=redacted");
+        assert_eq!(redact_query("/a?api_key_2=x", &params), "/a?api_key_2=x");
+        assert_eq!(redact_query("/a?api_key=secret", &[]), "/a?api_key=secret");
+        assert_eq!(redact_query("/a", &params), "/a");
+    }
+}
@@ -1,5 +1,13 @@
+use std::sync::Arc;
+use regex::Regex;
+
 use crate::conf::ReboundRule;
 
+use super::acl::IpFilter;
+use super::auth::Authenticator;
+use super::jwt::JwtValidator;
+use super::limit::RateLimiter;
+
 type NodePtr = usize;
 
 #[allow(dead_code)]
@@ -18,13 +26,25 @@ pub struct CircuitNode {
     
     pub rule: Option<ReboundRule>, 
 
-    pub path: Option<CircuitPath>
+    pub path: Option<CircuitPath>,
+
+    pub client_cert_subject: Option<Regex>,
+
+    pub client_cert_san: Option<Regex>,
+
+    pub ip_filter: Option<IpFilter>,
+
+    pub authenticator: Option<Arc<Authenticator>>,
+
+    pub jwt: Option<Arc<JwtValidator>>,
+
+    pub rate_limiter: Option<Arc<RateLimiter>>
     
 }
 
 impl CircuitNode {
     pub fn error() -> Self {
-        CircuitNode { circuit_type: CircuitType::Error, rule: None, path: None }
+        CircuitNode { circuit_type: CircuitType::Error, rule: None, path: None, client_cert_subject: None, client_cert_san: None, ip_filter: None, authenticator: None, jwt: None, rate_limiter: None }
     }
 }
 
@@ -32,22 +52,56 @@ impl PartialEq<CircuitPath> for CircuitNode {
     fn eq(&self, other: &CircuitPath) -> bool {
         let ctype = &self.circuit_type;
         match ctype {
-            CircuitType::Routable => return self.path.clone().unwrap().eq(other),
+            CircuitType::Routable => self.path.clone().unwrap().eq(other),
             CircuitType::Error => true,
         }
     }
 }
 
-impl From<ReboundRule> for CircuitNode {
-    fn from(rule: ReboundRule) -> Self {
+impl TryFrom<ReboundRule> for CircuitNode {
+
+    type Error = String;
+
+    fn try_from(rule: ReboundRule) -> Result<Self, Self::Error> {
         let pattern = rule.pattern.clone();
         let mut cpath = CircuitPath::from(pattern);
         cpath.is_resource_dir = true;
-        CircuitNode { 
+        let client_cert_subject = rule.client_cert_subject
+            .as_ref()
+            .map(|p| Regex::new(p).map_err(|e| format!("rule {}: invalid client_cert_subject regex: {}", rule.pattern, e)))
+            .transpose()?;
+        let client_cert_san = rule.client_cert_san
+            .as_ref()
+            .map(|p| Regex::new(p).map_err(|e| format!("rule {}: invalid client_cert_san regex: {}", rule.pattern, e)))
+            .transpose()?;
+        let ip_filter = rule.ip_filter
+            .as_ref()
+            .map(|f| IpFilter::new(f).map_err(|e| format!("rule {}: invalid ip_filter: {}", rule.pattern, e)))
+            .transpose()?;
+        // files are read again here, they may have changed since the conf was checked
+        let authenticator = rule.auth
+            .as_ref()
+            .map(|a| Authenticator::new(a).map(Arc::new).map_err(|e| format!("rule {}: invalid auth: {}", rule.pattern, e)))
+            .transpose()?;
+        let jwt = rule.jwt
+            .as_ref()
+            .map(|j| JwtValidator::new(j).map(Arc::new).map_err(|e| format!("rule {}: invalid jwt: {}", rule.pattern, e)))
+            .transpose()?;
+        let rate_limiter = rule.rate_limit
+            .as_ref()
+            .map(|l| Arc::new(RateLimiter::new(l)));
+
+        Ok(CircuitNode { 
             circuit_type: CircuitType::Routable,
             rule: Some(rule),
-            path: Some(cpath)
-        }
+            path: Some(cpath),
+            client_cert_subject,
+            client_cert_san,
+            ip_filter,
+            authenticator,
+            jwt,
+            rate_limiter
+        })
     }
 }
 
@@ -57,11 +111,30 @@ pub struct CircuitLink {
     pub to: NodePtr
 }
 
+/// Step of a walk down the circuit
+///
+#[derive(Clone, Debug)]
+pub struct CircuitStep {
+
+    /// Node the step starts from
+    ///
+    pub node: NodePtr,
+
+    /// Nodes linked from it, and whether the path matched them
+    ///
+    pub candidates: Vec<(NodePtr, bool)>
+
+}
+
 #[derive(Clone, Debug)]
 pub struct Circuit {
     pub head_index: NodePtr,
     pub nodes: Vec<CircuitNode>,
-    pub links: Vec<CircuitLink>
+    pub links: Vec<CircuitLink>,
+
+    /// Query params rules read API keys from, redacted from logs
+    ///
+    pub secret_params: Vec<String>
 }
 
 impl Circuit {
@@ -73,26 +146,35 @@ impl Circuit {
     }
 
     fn get_node_ptr(&self, path: impl Into<CircuitPath>) -> NodePtr {
+        self.walk(path, |_| {})
+    }
+
+    /// Walks down the circuit along the path, visiting each step with its candidate nodes
+    ///
+    /// Returns the node the path ends on
+    fn walk(&self, path: impl Into<CircuitPath>, mut visit: impl FnMut(CircuitStep)) -> NodePtr {
         let path: CircuitPath = path.into();
         let mut current_ptr: NodePtr = self.head_index;
         let mut node = self.nodes.get(current_ptr).unwrap();
 
         while node.eq(&path) {
 
-            let nodes = self.links.iter()
+            let candidates = self.links.iter()
                 .filter(|x| x.from == current_ptr)
                 .map(|x| {
                     let to_node = x.to;
                     let n = self.nodes.get(to_node).unwrap();
-                    (to_node, n)
+                    (to_node, n.eq(&path))
                 })
-                .filter(|(_i, x)| (*x).eq(&path) )
-                .collect::<Vec<(usize, &CircuitNode)>>();
+                .collect::<Vec<(usize, bool)>>();
+
+            let next = candidates.iter().find(|(_i, matched)| *matched).map(|(i, _)| *i);
+            visit(CircuitStep { node: current_ptr, candidates });
 
-            match nodes.first() {
-                Some((i, n)) => {
-                    current_ptr = *i;
-                    node = *n;
+            match next {
+                Some(i) => {
+                    current_ptr = i;
+                    node = self.nodes.get(i).unwrap();
                 },
                 None => {
                     break;
@@ -104,18 +186,27 @@ impl Circuit {
         current_ptr
     }
 
+    /// Node the path ends on, along with the steps walked to it
+    ///
+    pub fn trace(&self, path: impl Into<CircuitPath>) -> (&CircuitNode, Vec<CircuitStep>) {
+        let mut steps = Vec::new();
+        let ptr = self.walk(path, |step| steps.push(step));
+        (self.nodes.get(ptr).unwrap(), steps)
+    }
+
     pub fn get_node(&self, path: impl Into<CircuitPath>) -> &CircuitNode {
         let ptr: NodePtr = self.get_node_ptr(path);
         self.nodes.get(ptr).unwrap()
     }
 
-    fn add_rule(&mut self, rule: &ReboundRule) {
-        let node = CircuitNode::from(rule.clone());
+    fn add_rule(&mut self, rule: &ReboundRule) -> Result<(), String> {
+        let node = CircuitNode::try_from(rule.clone())?;
         let path = node.path.clone().unwrap();
         
         let from = self.get_node_ptr(path);
         let to = self.add_node(node);
         self.links.push( CircuitLink{ from, to });
+        Ok(())
     }
 }
 
@@ -134,21 +225,32 @@ impl CircuitBuilder {
         }
     }
 
-    pub fn build(&mut self) -> Circuit {
+    /// Builds the circuit of the rules, failing on rules whose regex, lists or key files are invalid
+    ///
+    /// Rules are expected to be checked with `conf::check::check` beforehand
+    pub fn build(&mut self) -> Result<Circuit, String> {
                     
         let mut circuit = Circuit {
             head_index: 0,
             nodes: Vec::new(),
-            links: Vec::new()
+            links: Vec::new(),
+            secret_params: Vec::new()
         };
 
         circuit.add_node(CircuitNode::error());
 
-        self.rules
+        for rule in self.rules.iter() {
+            circuit.add_rule(rule)?;
+        }
+
+        circuit.secret_params = self.rules
             .iter()
-            .for_each(|x| { circuit.add_rule(x); });
+            .filter_map(|r| r.auth.as_ref()?.api_keys.as_ref()?.query.clone())
+            .collect();
+        circuit.secret_params.sort();
+        circuit.secret_params.dedup();
 
-        circuit
+        Ok(circuit)
     }
 
 }
@@ -159,11 +261,11 @@ pub enum CircuitUpstreamSchema {
     Https
 }
 
-pub fn get_circuit_schema(c_upstream: &String) -> CircuitUpstreamSchema {
-    if c_upstream.starts_with(CircuitUpstreamSchema::Http.into_str()) {
+pub fn get_circuit_schema(c_upstream: &str) -> CircuitUpstreamSchema {
+    if c_upstream.starts_with(CircuitUpstreamSchema::Http.as_str()) {
         CircuitUpstreamSchema::Http
     }
-    else if c_upstream.starts_with(CircuitUpstreamSchema::Https.into_str()) {
+    else if c_upstream.starts_with(CircuitUpstreamSchema::Https.as_str()) {
         CircuitUpstreamSchema::Https
     }
     else {
@@ -172,7 +274,7 @@ pub fn get_circuit_schema(c_upstream: &String) -> CircuitUpstreamSchema {
 }
 
 impl CircuitUpstreamSchema {
-    fn into_str(&self) -> &str {
+    fn as_str(&self) -> &str {
         match self {
             CircuitUpstreamSchema::Http => "http://",
             CircuitUpstreamSchema::Https => "https://",
@@ -195,13 +297,13 @@ impl From<String> for CircuitUpstream {
     fn from(upstream: String) -> Self {
 
         let schema = get_circuit_schema(&upstream);
-        let path_upstream = upstream.strip_prefix(schema.into_str());
+        let path_upstream = upstream.strip_prefix(schema.as_str());
         let mut cpath = CircuitPath::from(path_upstream.unwrap());
 
         // host[:port] will be first in split('/')
         let host = cpath.ordered_path.remove(0); 
 
-        CircuitUpstream { schema: schema, host: host, path: cpath }
+        CircuitUpstream { schema, host, path: cpath }
     }
 }
 
@@ -214,21 +316,21 @@ impl CircuitUpstream {
     }
 
     pub fn path_undefined(&self) -> bool {
-        return self.path.ordered_path.is_empty()
+        self.path.ordered_path.is_empty()
     }
 }
 
-impl Into<String> for CircuitUpstream {
-    fn into(self) -> String {
+impl From<CircuitUpstream> for String {
+    fn from(upstream: CircuitUpstream) -> Self {
 
         let full_uri = 
         [
-            self.host,
-            self.path.into()
+            upstream.host,
+            upstream.path.into()
         ]
         .join("/");
 
-        format!("{}{}", self.schema.into_str(), full_uri)
+        format!("{}{}", upstream.schema.as_str(), full_uri)
     }
 }
 
@@ -267,10 +369,10 @@ impl CircuitPath {
     }
 }
 
-impl Into<String> for CircuitPath {
-    fn into(self) -> String {
-        let needs_dir = self.is_resource_dir && !self.ordered_path.is_empty();
-        let mut ret = self.ordered_path.join("/");
+impl From<CircuitPath> for String {
+    fn from(path: CircuitPath) -> Self {
+        let needs_dir = path.is_resource_dir && !path.ordered_path.is_empty();
+        let mut ret = path.ordered_path.join("/");
         if !ret.ends_with("/") && needs_dir {
             ret += "/"
         } 
@@ -300,7 +402,7 @@ impl From<String> for CircuitPath {
         let ordered_path: Vec<String> = str_path
                             .trim_matches('/')
                             .split('/')
-                            .map(|x| String::from(x))
+                            .map(String::from)
                             .filter(|x| !x.is_empty())
                             .collect();
 
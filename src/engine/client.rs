@@ -1,19 +1,284 @@
-use std::error::Error;
+use std::{collections::{HashMap, HashSet}, io, sync::{Arc, Mutex, RwLock}, time::Instant};
+use async_std::net::ToSocketAddrs;
+use futures::AsyncReadExt;
+use isahc::ResponseExt;
+use log::{debug, error, info};
+
+use crate::conf::{ReboundConf, ReboundPool, ReboundUpstreamTls};
+
+use super::concurrency::{check_concurrency, is_overloaded};
+use super::error::ReboundError;
+use super::pool::{UpstreamPool, check_tls};
+use super::request::ReboundRequest;
 use super::response::ReboundResponse;
 
+const HOST_HDR: &str = "Host";
+
+/// Client sending requests upstream, shared by all workers
+///
+/// Keeps one connection pool per upstream host, created on first use
 pub struct ReboundClient {
-    client: surf::Client
+
+    /// Pool settings for every upstream host
+    ///
+    pool: ReboundPool,
+
+    /// Pool settings for specific upstream hosts
+    ///
+    upstream_pools: HashMap<String, ReboundPool>,
+
+    /// TLS settings for specific upstream hosts
+    ///
+    upstream_tls: HashMap<String, ReboundUpstreamTls>,
+
+    /// Pools by upstream host:port
+    ///
+    pools: Mutex<HashMap<String, Arc<UpstreamPool>>>,
+
+    /// Upstream hosts or host:port no request is sent to
+    ///
+    drained: RwLock<HashSet<String>>
+
 }
 
 impl ReboundClient {
 
-    pub fn new() -> Self {
-        ReboundClient { client: surf::client() }
+    pub fn new(conf: &ReboundConf) -> Result<Self, String> {
+
+        for (host, tls) in conf.upstream_tls.iter() {
+            check_tls(tls).map_err(|e| format!("upstream_tls {}: {}", host, e))?;
+            if tls.insecure_skip_verify {
+                error!("!!! upstream_tls {}: insecure_skip_verify is set, upstream certificates will NOT be verified !!!", host);
+            }
+        }
+
+        if let Some(concurrency) = &conf.pool.concurrency {
+            check_concurrency(concurrency).map_err(|e| format!("pool: {}", e))?;
+        }
+        for (host, pool) in conf.upstream_pools.iter() {
+            if let Some(concurrency) = &pool.concurrency {
+                check_concurrency(concurrency).map_err(|e| format!("upstream_pools {}: {}", host, e))?;
+            }
+        }
+
+        Ok(ReboundClient {
+            pool: conf.pool.clone(),
+            upstream_pools: conf.upstream_pools.clone(),
+            upstream_tls: conf.upstream_tls.clone(),
+            pools: Mutex::new(HashMap::new()),
+            drained: RwLock::new(HashSet::new())
+        })
     }
 
+    /// Pools created so far, by upstream host:port
+    ///
+    pub fn pools(&self) -> Vec<Arc<UpstreamPool>> {
+        let mut pools: Vec<Arc<UpstreamPool>> = self.pools.lock().unwrap().values().cloned().collect();
+        pools.sort_by(|a, b| a.host.cmp(&b.host));
+        pools
+    }
+
+    /// Stops sending requests to the upstream host or host:port, they get a 503 instead
+    ///
+    pub fn drain(&self, upstream: &str) {
+        self.drained.write().unwrap().insert(upstream.to_lowercase());
+    }
 
-    pub async fn send(&self, req: impl Into<surf::Request>) -> Result<ReboundResponse, Box<dyn Error>> {
-        let res = self.client.send(req).await?;
-        Ok(ReboundResponse::from(res).await)
+    /// Sends requests to a drained upstream again
+    ///
+    /// Returns whether the upstream was drained
+    pub fn enable(&self, upstream: &str) -> bool {
+        self.drained.write().unwrap().remove(&upstream.to_lowercase())
     }
-}
\ No newline at end of file
+
+    pub fn drained(&self) -> Vec<String> {
+        let mut drained: Vec<String> = self.drained.read().unwrap().iter().cloned().collect();
+        drained.sort();
+        drained
+    }
+
+    /// Whether the upstream host:port was drained, by itself or with its host
+    ///
+    pub fn is_drained(&self, key: &str) -> bool {
+        let drained = self.drained.read().unwrap();
+        drained.contains(key) || key.rsplit_once(':').map(|(host, _)| drained.contains(host)).unwrap_or(false)
+    }
+
+    fn get_pool(&self, req: &ReboundRequest) -> Result<Arc<UpstreamPool>, ReboundError> {
+
+        let url = req.full_url()?;
+        let host = url.host_str().unwrap_or_default();
+        let key = format!("{}:{}", host, url.port_or_known_default().unwrap_or_default());
+
+        let mut pools = self.pools.lock().unwrap();
+        if let Some(pool) = pools.get(&key) {
+            return Ok(pool.clone());
+        }
+
+        let conf = self.upstream_pools
+            .get(&key)
+            .or_else(|| self.upstream_pools.get(host))
+            .map(|p| p.or(&self.pool))
+            .unwrap_or_else(|| self.pool.clone());
+
+        let tls = self.upstream_tls
+            .get(&key)
+            .or_else(|| self.upstream_tls.get(host));
+
+        info!("creating upstream pool for {}: {:?}, tls: {:?}", key, conf, tls);
+        let pool = Arc::new(
+            UpstreamPool::new(key.clone(), &conf, tls)
+                .map_err(ReboundError::Upstream)?
+        );
+        pools.insert(key, pool.clone());
+        Ok(pool)
+    }
+
+    pub async fn send(&self, req: ReboundRequest) -> Result<ReboundResponse, ReboundError> {
+
+        let pool = self.get_pool(&req)?;
+        if self.is_drained(&pool.host) {
+            return Err(ReboundError::UpstreamDrained(pool.host.clone()));
+        }
+
+        let mut permit = match &pool.limiter {
+            Some(limiter) => Some(
+                limiter
+                    .acquire()
+                    .await
+                    .ok_or_else(|| ReboundError::UpstreamBusy(pool.host.clone()))?
+            ),
+            None => None,
+        };
+        pool.stats.record_start();
+        let started = Instant::now();
+
+        let r = if let Some(sni) = &pool.sni {
+            send_sni(&pool, sni, req).await
+        }
+        else if req.is_extension_method() {
+            send_extension(&pool, req).await
+        }
+        else {
+            send_surf(&pool, req).await
+        };
+
+        match &r {
+            Ok((_, metrics)) => pool.stats.record_end(metrics.as_ref(), false),
+            Err(_) => pool.stats.record_end(None, true),
+        }
+
+        // upstreams shedding load are as overloaded as failing ones
+        if let Some(permit) = permit.as_mut() {
+            let overloaded = r.as_ref().map(|(res, _)| is_overloaded(res.status)).unwrap_or(true);
+            permit.record(started.elapsed(), overloaded);
+        }
+
+        debug!(
+            "pool {}: requests {}, in flight {}, new connections {}, reused connections {}, errors {}",
+            pool.host,
+            pool.stats.requests(),
+            pool.stats.in_flight(),
+            pool.stats.new_connections(),
+            pool.stats.reused_connections(),
+            pool.stats.errors()
+        );
+
+        r.map(|(res, _)| res)
+    }
+}
+
+async fn send_surf(pool: &UpstreamPool, req: ReboundRequest) -> Result<(ReboundResponse, Option<isahc::Metrics>), ReboundError> {
+    let req = surf::Request::try_from(req)?;
+    let res = pool.client
+        .send(req)
+        .await
+        .map_err(|e| match e.downcast_ref::<isahc::Error>() {
+            Some(e) => upstream_error(e),
+            None => ReboundError::Upstream(e.to_string()),
+        })?;
+
+    let metrics = res.ext::<isahc::Metrics>().cloned();
+    let res = ReboundResponse::from(res)
+        .await
+        .map_err(|e| match e.downcast_ref::<io::Error>() {
+            Some(e) => body_error(e),
+            None => ReboundError::Upstream(e.to_string()),
+        })?;
+    Ok((res, metrics))
+}
+
+/// Sends the request addressed to the sni name, so curl sends it in SNI and verifies it,
+/// and dialed to the upstream host, resolved on every request to follow DNS changes
+///
+/// The Host header stays the one of the upstream, unless the client sent its own
+async fn send_sni(pool: &UpstreamPool, sni: &str, mut req: ReboundRequest) -> Result<(ReboundResponse, Option<isahc::Metrics>), ReboundError> {
+
+    let addr = pool.host
+        .to_socket_addrs()
+        .await
+        .map_err(|e| ReboundError::UpstreamConnect(format!("failed to resolve {}: {}", pool.host, e)))?
+        .next()
+        .ok_or_else(|| ReboundError::UpstreamConnect(format!("failed to resolve {}", pool.host)))?;
+
+    if !req.headers.keys().any(|k| k.eq_ignore_ascii_case(HOST_HDR)) {
+        let url = req.full_url()?;
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+        req.headers.insert(String::from(HOST_HDR), host);
+    }
+    req.set_host(sni)?;
+
+    send_isahc(pool, req.into_isahc(Some(addr))?).await
+}
+
+async fn send_extension(pool: &UpstreamPool, req: ReboundRequest) -> Result<(ReboundResponse, Option<isahc::Metrics>), ReboundError> {
+    send_isahc(pool, isahc::http::Request::<Vec<u8>>::try_from(req)?).await
+}
+
+async fn send_isahc(pool: &UpstreamPool, req: isahc::http::Request<Vec<u8>>) -> Result<(ReboundResponse, Option<isahc::Metrics>), ReboundError> {
+    let res = pool.http_client
+        .send_async(req)
+        .await
+        .map_err(|e| upstream_error(&e))?;
+
+    let metrics = res.metrics().cloned();
+    let (parts, mut body) = res.into_parts();
+    let mut bytes = Vec::new();
+    body.read_to_end(&mut bytes)
+        .await
+        .map_err(|e| body_error(&e))?;
+
+    let rebound_res = ReboundResponse {
+        status: parts.status.as_u16(),
+        headers: parts.headers
+            .iter()
+            .filter_map(|(k, v)| v.to_str().ok().map(|v| (k.to_string(), v.to_string())))
+            .collect(),
+        body: bytes
+    };
+    Ok((rebound_res, metrics))
+}
+
+/// Tells connect failures and timeouts apart from other upstream errors
+///
+fn upstream_error(e: &isahc::Error) -> ReboundError {
+    match e {
+        isahc::Error::Timeout => ReboundError::UpstreamTimeout(e.to_string()),
+        isahc::Error::ConnectFailed
+        | isahc::Error::CouldntResolveHost
+        | isahc::Error::SSLConnectFailed(_) => ReboundError::UpstreamConnect(e.to_string()),
+        _ => ReboundError::Upstream(e.to_string()),
+    }
+}
+
+/// Tells timeouts apart from other failures reading the response body
+///
+fn body_error(e: &io::Error) -> ReboundError {
+    match e.kind() {
+        io::ErrorKind::TimedOut => ReboundError::UpstreamTimeout(e.to_string()),
+        _ => ReboundError::Upstream(e.to_string()),
+    }
+}
@@ -0,0 +1,270 @@
+use std::{sync::{Mutex, MutexGuard, atomic::{AtomicUsize, Ordering}}, time::{Duration, Instant}};
+use flume::{Receiver, Sender};
+
+use crate::conf::ReboundConcurrency;
+
+/// Requests in flight and current limit of an upstream host
+///
+#[derive(Debug)]
+struct LimiterState {
+
+    in_flight: usize,
+
+    /// Limit of requests in flight, fractional so it can grow additively
+    ///
+    limit: f64
+
+}
+
+/// Concurrency limiter of an upstream host, shared by every worker
+///
+/// Requests over the limit wait for a request in flight to finish, up to the queue timeout
+pub struct ConcurrencyLimiter {
+
+    conf: ReboundConcurrency,
+
+    state: Mutex<LimiterState>,
+
+    /// Wakes waiting requests when a request in flight finishes
+    ///
+    released: (Sender<()>, Receiver<()>),
+
+    /// Requests rejected after waiting too long
+    ///
+    rejected: AtomicUsize
+
+}
+
+impl ConcurrencyLimiter {
+
+    pub fn new(conf: &ReboundConcurrency) -> Self {
+        ConcurrencyLimiter {
+            conf: conf.clone(),
+            state: Mutex::new(LimiterState { in_flight: 0, limit: conf.max_in_flight as f64 }),
+            released: flume::bounded(conf.max_in_flight.max(1)),
+            rejected: AtomicUsize::new(0)
+        }
+    }
+
+    /// Current limit of requests in flight
+    ///
+    pub fn limit(&self) -> usize {
+        (self.state().limit as usize).max(1)
+    }
+
+    pub fn max_in_flight(&self) -> usize {
+        self.conf.max_in_flight
+    }
+
+    pub fn rejected(&self) -> usize {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Waits for the request to be allowed in flight, none when the queue timeout is reached
+    ///
+    pub async fn acquire(&self) -> Option<ConcurrencyPermit<'_>> {
+
+        let deadline = Instant::now() + Duration::from_millis(self.conf.queue_timeout_ms);
+        loop {
+            if self.try_acquire() {
+                return Some(ConcurrencyPermit { limiter: self, outcome: None });
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+            // wake ups can be stale, the limit is checked again either way
+            let _ = async_std::future::timeout(remaining, self.released.1.recv_async()).await;
+        }
+    }
+
+    fn try_acquire(&self) -> bool {
+        let mut state = self.state();
+        if state.in_flight < (state.limit as usize).max(1) {
+            state.in_flight += 1;
+            true
+        }
+        else {
+            false
+        }
+    }
+
+    /// Ends a request in flight, adapting the limit to its latency and whether it failed
+    ///
+    fn release(&self, outcome: Option<(Duration, bool)>) {
+
+        let mut state = self.state();
+        state.in_flight -= 1;
+
+        if let (Some(adaptive), Some((latency, failed))) = (&self.conf.adaptive, outcome) {
+            state.limit = if failed || latency > Duration::from_millis(adaptive.latency_ms) {
+                (state.limit * adaptive.backoff).max(adaptive.min_in_flight as f64)
+            }
+            else {
+                (state.limit + 1.0 / state.limit).min(self.conf.max_in_flight as f64)
+            };
+        }
+        drop(state);
+
+        let _ = self.released.0.try_send(());
+    }
+
+    fn state(&self) -> MutexGuard<'_, LimiterState> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+/// Request allowed in flight, until dropped
+///
+pub struct ConcurrencyPermit<'a> {
+
+    limiter: &'a ConcurrencyLimiter,
+
+    /// Latency of the request and whether the upstream failed or was overloaded
+    ///
+    outcome: Option<(Duration, bool)>
+
+}
+
+impl ConcurrencyPermit<'_> {
+
+    pub fn record(&mut self, latency: Duration, failed: bool) {
+        self.outcome = Some((latency, failed));
+    }
+}
+
+impl Drop for ConcurrencyPermit<'_> {
+    fn drop(&mut self) {
+        self.limiter.release(self.outcome);
+    }
+}
+
+/// Whether the status of an upstream response tells it is shedding load
+///
+pub fn is_overloaded(status: u16) -> bool {
+    status == 429 || status == 503
+}
+
+/// Checks a concurrency limit can be enforced
+///
+pub fn check_concurrency(conf: &ReboundConcurrency) -> Result<(), String> {
+
+    if conf.max_in_flight == 0 {
+        return Err(String::from("concurrency max_in_flight must be at least 1"));
+    }
+    if let Some(adaptive) = &conf.adaptive {
+        if adaptive.min_in_flight == 0 || adaptive.min_in_flight > conf.max_in_flight {
+            return Err(format!("concurrency adaptive min_in_flight must be between 1 and max_in_flight {}", conf.max_in_flight));
+        }
+        if adaptive.latency_ms == 0 {
+            return Err(String::from("concurrency adaptive latency_ms must be at least 1"));
+        }
+        if !(adaptive.backoff > 0.0 && adaptive.backoff < 1.0) {
+            return Err(format!("concurrency adaptive backoff {} must be between 0 and 1", adaptive.backoff));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::engine::error::ReboundError;
+
+    fn limiter(conf: serde_json::Value) -> ConcurrencyLimiter {
+        ConcurrencyLimiter::new(&serde_json::from_value(conf).unwrap())
+    }
+
+    fn adaptive() -> ConcurrencyLimiter {
+        limiter(json!({
+            "max_in_flight": 10,
+            "adaptive": { "min_in_flight": 2, "latency_ms": 50, "backoff": 0.5 }
+        }))
+    }
+
+    fn complete(limiter: &ConcurrencyLimiter, latency_ms: u64, failed: bool) {
+        let mut permit = async_std::task::block_on(limiter.acquire()).unwrap();
+        permit.record(Duration::from_millis(latency_ms), failed);
+    }
+
+    #[test]
+    fn decreases_multiplicatively() {
+        let limiter = adaptive();
+        complete(&limiter, 80, false);
+        assert_eq!(limiter.limit(), 5);
+        complete(&limiter, 10, true);
+        assert_eq!(limiter.limit(), 2);
+        complete(&limiter, 80, false);
+        assert_eq!(limiter.limit(), 2, "the limit does not go under min_in_flight");
+    }
+
+    #[test]
+    fn increases_additively() {
+        let limiter = adaptive();
+        complete(&limiter, 80, false);
+        assert_eq!(limiter.limit(), 5);
+        // one request per unit of the limit raises it by one
+        for _ in 0..5 {
+            complete(&limiter, 10, false);
+        }
+        assert_eq!(limiter.limit(), 5);
+        complete(&limiter, 10, false);
+        assert_eq!(limiter.limit(), 6);
+        for _ in 0..100 {
+            complete(&limiter, 10, false);
+        }
+        assert_eq!(limiter.limit(), 10, "the limit does not go over max_in_flight");
+    }
+
+    #[test]
+    fn fixed_without_adaptive() {
+        let limiter = limiter(json!({ "max_in_flight": 4 }));
+        complete(&limiter, 1000, true);
+        assert_eq!(limiter.limit(), 4);
+    }
+
+    #[test]
+    fn shedding_load_is_overload() {
+        assert!(is_overloaded(429));
+        assert!(is_overloaded(503));
+        for status in [200, 404, 500, 502, 504] {
+            assert!(!is_overloaded(status), "{}", status);
+        }
+    }
+
+    #[test]
+    fn waits_for_a_request_in_flight() {
+        let limiter = limiter(json!({ "max_in_flight": 1, "queue_timeout_ms": 1000 }));
+        async_std::task::block_on(async {
+            let permit = limiter.acquire().await.unwrap();
+            let started = Instant::now();
+            let (waiting, _) = futures::join!(limiter.acquire(), async {
+                async_std::task::sleep(Duration::from_millis(50)).await;
+                drop(permit);
+            });
+            assert!(waiting.is_some());
+            assert!(started.elapsed() < Duration::from_millis(1000));
+        });
+        assert_eq!(limiter.rejected(), 0);
+    }
+
+    #[test]
+    fn rejects_after_queue_timeout() {
+        let limiter = limiter(json!({ "max_in_flight": 1, "queue_timeout_ms": 100 }));
+        async_std::task::block_on(async {
+            let _permit = limiter.acquire().await.unwrap();
+            let started = Instant::now();
+            assert!(limiter.acquire().await.is_none());
+            assert!(started.elapsed() >= Duration::from_millis(100));
+        });
+        assert_eq!(limiter.rejected(), 1);
+        // the client answers rejected requests with a 503
+        assert_eq!(ReboundError::UpstreamBusy(String::from("up")).status_code(), 503);
+    }
+}
@@ -0,0 +1,151 @@
+use std::fmt;
+
+use super::limit::RateLimitExceeded;
+
+/// Errors raised while routing a request through Rebound
+///
+/// Each error maps to the Http status code sent back to the client
+#[derive(Clone, Debug)]
+pub enum ReboundError {
+
+    /// No rule matched the request
+    ///
+    NoRoute,
+
+    /// The matched rule does not allow the request method
+    ///
+    MethodNotAllowed(Vec<String>),
+
+    /// The client did not authenticate on the matched rule,
+    /// with the reason and the WWW-Authenticate challenge to send
+    Unauthorized(String, Option<String>),
+
+    /// The client is not allowed on the matched rule
+    ///
+    Forbidden(String),
+
+    /// The request method cannot be forwarded upstream
+    ///
+    UnsupportedMethod(String),
+
+    /// The upstream request could not be built
+    ///
+    InvalidUpstreamRequest(String),
+
+    /// The upstream request failed
+    ///
+    Upstream(String),
+
+    /// The upstream could not be connected to
+    ///
+    UpstreamConnect(String),
+
+    /// The upstream did not answer in time
+    ///
+    UpstreamTimeout(String),
+
+    /// The upstream was drained from the admin API
+    ///
+    UpstreamDrained(String),
+
+    /// The upstream had too many requests in flight for the request to wait
+    ///
+    UpstreamBusy(String),
+
+    /// The client went over a rate limit
+    ///
+    RateLimited(RateLimitExceeded),
+
+    /// Rebound failed while handling the request
+    ///
+    Internal,
+
+    /// Rebound is overloaded, the client should retry after the given seconds
+    ///
+    Overloaded(u64)
+
+}
+
+impl ReboundError {
+
+    pub fn status_code(&self) -> u16 {
+        match self {
+            ReboundError::NoRoute => 502,
+            ReboundError::MethodNotAllowed(_) => 405,
+            ReboundError::Unauthorized(_, _) => 401,
+            ReboundError::Forbidden(_) => 403,
+            ReboundError::UnsupportedMethod(_) => 501,
+            ReboundError::InvalidUpstreamRequest(_) => 502,
+            ReboundError::Upstream(_) => 502,
+            ReboundError::UpstreamConnect(_) => 502,
+            ReboundError::UpstreamTimeout(_) => 504,
+            ReboundError::UpstreamDrained(_) => 503,
+            ReboundError::UpstreamBusy(_) => 503,
+            ReboundError::RateLimited(_) => 429,
+            ReboundError::Internal => 500,
+            ReboundError::Overloaded(_) => 503,
+        }
+    }
+
+    /// Kind of the error, as reported in metrics
+    ///
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ReboundError::NoRoute => "no_route",
+            ReboundError::MethodNotAllowed(_) => "method_not_allowed",
+            ReboundError::Unauthorized(_, _) => "unauthorized",
+            ReboundError::Forbidden(_) => "forbidden",
+            ReboundError::UnsupportedMethod(_) => "unsupported_method",
+            ReboundError::InvalidUpstreamRequest(_) => "invalid_upstream_request",
+            ReboundError::Upstream(_) => "upstream",
+            ReboundError::UpstreamConnect(_) => "connect_failure",
+            ReboundError::UpstreamTimeout(_) => "timeout",
+            ReboundError::UpstreamDrained(_) => "drained",
+            ReboundError::UpstreamBusy(_) => "upstream_busy",
+            ReboundError::RateLimited(_) => "rate_limited",
+            ReboundError::Internal => "internal",
+            ReboundError::Overloaded(_) => "overloaded",
+        }
+    }
+
+    /// Additional Http headers to send along with the error response
+    ///
+    pub fn headers(&self) -> Vec<(String, String)> {
+        match self {
+            ReboundError::MethodNotAllowed(allowed) => vec![(String::from("Allow"), allowed.join(", "))],
+            ReboundError::Overloaded(retry_after) => vec![(String::from("Retry-After"), retry_after.to_string())],
+            ReboundError::Unauthorized(_, Some(challenge)) => vec![(String::from("WWW-Authenticate"), challenge.clone())],
+            ReboundError::RateLimited(exceeded) => vec![
+                (String::from("Retry-After"), exceeded.retry_after.to_string()),
+                (String::from("RateLimit-Limit"), exceeded.limit.to_string()),
+                (String::from("RateLimit-Remaining"), String::from("0")),
+                (String::from("RateLimit-Reset"), exceeded.retry_after.to_string()),
+                (String::from("RateLimit-Policy"), format!("{};w={}", exceeded.limit, exceeded.window)),
+            ],
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for ReboundError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReboundError::NoRoute => write!(f, "no route"),
+            ReboundError::MethodNotAllowed(allowed) => write!(f, "method not allowed, allowed: [{}]", allowed.join(", ")),
+            ReboundError::Unauthorized(reason, _) => write!(f, "unauthorized: {}", reason),
+            ReboundError::Forbidden(reason) => write!(f, "forbidden: {}", reason),
+            ReboundError::UnsupportedMethod(m) => write!(f, "unsupported method: {}", m),
+            ReboundError::InvalidUpstreamRequest(e) => write!(f, "invalid upstream request: {}", e),
+            ReboundError::Upstream(e) => write!(f, "upstream error: {}", e),
+            ReboundError::UpstreamConnect(e) => write!(f, "upstream connect failure: {}", e),
+            ReboundError::UpstreamTimeout(e) => write!(f, "upstream timeout: {}", e),
+            ReboundError::UpstreamDrained(host) => write!(f, "upstream {} is drained", host),
+            ReboundError::UpstreamBusy(host) => write!(f, "upstream {} has too many requests in flight", host),
+            ReboundError::RateLimited(exceeded) => write!(f, "rate limited, retry after {}s", exceeded.retry_after),
+            ReboundError::Internal => write!(f, "internal error"),
+            ReboundError::Overloaded(retry_after) => write!(f, "overloaded, retry after {}s", retry_after),
+        }
+    }
+}
+
+impl std::error::Error for ReboundError {}
@@ -0,0 +1,301 @@
+use std::{collections::BTreeMap, fmt, str::FromStr};
+use serde::Serialize;
+use tiny_http::{Header, Method};
+
+use super::circuit::{Circuit, CircuitType};
+use super::request::{ReboundIngressRequestBuilder, ReboundRequest};
+
+/// Explanation of how a request would be routed, without sending it
+///
+#[derive(Serialize, Clone, Debug)]
+pub struct RouteExplanation {
+
+    pub method: String,
+
+    pub url: String,
+
+    /// Steps walked down the circuit, from the root
+    ///
+    pub steps: Vec<ExplainStep>,
+
+    /// Pattern of the rule the request ended on, none when no rule matched
+    ///
+    pub rule: Option<String>,
+
+    /// Error the request would be answered with, none when it would be sent upstream
+    ///
+    pub error: Option<ExplainError>,
+
+    /// Request that would be sent upstream
+    ///
+    pub upstream: Option<ExplainUpstream>
+
+}
+
+/// Step of the walk down the circuit, with the rules considered from a node
+///
+#[derive(Serialize, Clone, Debug)]
+pub struct ExplainStep {
+
+    /// Pattern of the node the step starts from, none for the root
+    ///
+    pub from: Option<String>,
+
+    pub candidates: Vec<ExplainCandidate>
+
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ExplainCandidate {
+
+    pub pattern: String,
+
+    pub upstream: String,
+
+    /// Whether the walk went on to this rule
+    ///
+    pub chosen: bool,
+
+    /// Why the rule was chosen or rejected
+    ///
+    pub reason: String
+
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ExplainError {
+
+    pub status: u16,
+
+    pub message: String
+
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ExplainUpstream {
+
+    pub method: String,
+
+    pub url: String,
+
+    pub headers: BTreeMap<String, String>,
+
+    pub query: BTreeMap<String, String>
+
+}
+
+/// Builds the client request to explain from its method, url and `Name: value` headers
+///
+pub fn explain_request(method: &str, url: &str, headers: &[String]) -> Result<ReboundRequest, String> {
+
+    let method = Method::from_str(method).map_err(|_| format!("invalid method {}", method))?;
+    if !url.starts_with('/') {
+        return Err(format!("{} must start with /", url));
+    }
+    let headers = headers
+        .iter()
+        .map(|h| Header::from_str(h).map_err(|_| format!("invalid header {}, expected Name: value", h)))
+        .collect::<Result<Vec<Header>, String>>()?;
+
+    Ok(
+        ReboundIngressRequestBuilder::new()
+            .with_method(&method)
+            .with_url(url.to_string())
+            .with_headers(&headers)
+            .build()
+    )
+}
+
+/// Explains how the circuit routes the request, with the upstream request `ReboundRequest::apply` produces
+///
+/// Client certificates and addresses are not known here, so rules requiring one reject the request
+pub fn explain(circuit: &Circuit, method: &str, url: &str, req: &ReboundRequest) -> RouteExplanation {
+
+    let (cnode, steps) = circuit.trace(req.uri.as_str());
+    let pattern = |ptr: usize| circuit.nodes[ptr].rule.as_ref().map(|r| r.pattern.clone());
+
+    let steps = steps
+        .iter()
+        .map(|step| {
+            let mut went_on = false;
+            let candidates = step.candidates
+                .iter()
+                .filter_map(|(ptr, matched)| {
+                    let rule = circuit.nodes[*ptr].rule.as_ref()?;
+                    let (chosen, reason) = match (matched, went_on) {
+                        (true, false) => (true, "path matches"),
+                        (true, true) => (false, "path matches, an earlier rule was chosen"),
+                        (false, _) => (false, "path does not match"),
+                    };
+                    went_on |= chosen;
+                    Some(ExplainCandidate {
+                        pattern: rule.pattern.clone(),
+                        upstream: rule.upstream.clone(),
+                        chosen,
+                        reason: String::from(reason)
+                    })
+                })
+                .collect();
+            ExplainStep { from: pattern(step.node), candidates }
+        })
+        .collect();
+
+    let (error, upstream) = match req.apply(cnode) {
+        Ok(upstream_req) => {
+            let upstream = ExplainUpstream {
+                method: upstream_req.method.as_str().to_string(),
+                url: upstream_req.full_url().map(|u| u.as_str().trim_end_matches('?').to_string()).unwrap_or_else(|_| upstream_req.uri.clone()),
+                headers: upstream_req.headers.into_iter().collect(),
+                query: upstream_req.query_params.into_iter().collect()
+            };
+            (None, Some(upstream))
+        },
+        Err(e) => (Some(ExplainError { status: e.status_code(), message: e.to_string() }), None),
+    };
+
+    RouteExplanation {
+        method: method.to_uppercase(),
+        url: url.to_string(),
+        steps,
+        rule: match cnode.circuit_type {
+            CircuitType::Routable => cnode.rule.as_ref().map(|r| r.pattern.clone()),
+            CircuitType::Error => None,
+        },
+        error,
+        upstream
+    }
+}
+
+impl fmt::Display for RouteExplanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+
+        writeln!(f, "{} {}", self.method, self.url)?;
+        for step in self.steps.iter().filter(|s| !s.candidates.is_empty()) {
+            writeln!(f, "  from {}", step.from.as_deref().unwrap_or("root"))?;
+            for c in step.candidates.iter() {
+                let mark = if c.chosen { "+" } else { "-" };
+                writeln!(f, "    {} {} -> {}: {}", mark, c.pattern, c.upstream, c.reason)?;
+            }
+        }
+
+        match &self.rule {
+            Some(rule) => writeln!(f, "rule: {}", rule)?,
+            None => writeln!(f, "rule: none")?,
+        }
+
+        if let Some(e) = &self.error {
+            writeln!(f, "answered {}: {}", e.status, e.message)?;
+        }
+
+        if let Some(upstream) = &self.upstream {
+            writeln!(f, "upstream: {} {}", upstream.method, upstream.url)?;
+            writeln!(f, "headers:")?;
+            for (k, v) in upstream.headers.iter() {
+                writeln!(f, "  {}: {}", k, v)?;
+            }
+            writeln!(f, "query:")?;
+            for (k, v) in upstream.query.iter() {
+                writeln!(f, "  {}={}", k, v)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use serde_json::json;
+
+    use crate::engine::circuit::{Circuit, CircuitBuilder};
+
+    use super::*;
+
+    fn circuit() -> Circuit {
+        let rules = json!([
+            {
+                "pattern": "/api/",
+                "upstream": "http://api:8080/v1/",
+                "additional_hdrs": { "X-Env": "test" },
+                "additional_query": { "source": "rebound" },
+                "allowed_methods": ["GET", "POST"]
+            },
+            {
+                "pattern": "/static/",
+                "upstream": "http://cdn",
+                "preserve_hdrs": false,
+                "preserve_query": false
+            }
+        ]);
+        CircuitBuilder::new(serde_json::from_value(rules).unwrap()).build().unwrap()
+    }
+
+    fn explain_url(method: &str, url: &str) -> RouteExplanation {
+        let req = explain_request(method, url, &[String::from("X-Client: yes")]).unwrap();
+        explain(&circuit(), method, url, &req)
+    }
+
+    #[test]
+    fn explains_routed_request() {
+        let explanation = explain_url("GET", "/api/users?page=2");
+        assert_eq!(explanation.method, "GET");
+        assert_eq!(explanation.rule.as_deref(), Some("/api/"));
+        assert!(explanation.error.is_none());
+
+        let chosen: Vec<&str> = explanation.steps.iter()
+            .flat_map(|s| s.candidates.iter())
+            .filter(|c| c.chosen)
+            .map(|c| c.pattern.as_str())
+            .collect();
+        assert_eq!(chosen, ["/api/"]);
+
+        let upstream = explanation.upstream.unwrap();
+        assert_eq!(upstream.method, "GET");
+        // query parameters are not kept in order
+        let (path, query) = upstream.url.split_once('?').unwrap();
+        assert_eq!(path, "http://api:8080/v1/users");
+        let mut query: Vec<&str> = query.split('&').collect();
+        query.sort();
+        assert_eq!(query, ["page=2", "source=rebound"]);
+        assert_eq!(upstream.headers.get("X-Client").map(String::as_str), Some("yes"));
+        assert_eq!(upstream.headers.get("X-Env").map(String::as_str), Some("test"));
+        assert_eq!(upstream.query, BTreeMap::from([
+            (String::from("page"), String::from("2")),
+            (String::from("source"), String::from("rebound"))
+        ]));
+    }
+
+    #[test]
+    fn explains_dropped_headers_and_query() {
+        let upstream = explain_url("GET", "/static/app.js?v=3").upstream.unwrap();
+        assert_eq!(upstream.url, "http://cdn/static/app.js");
+        assert!(upstream.headers.is_empty(), "{:?}", upstream.headers);
+        assert!(upstream.query.is_empty(), "{:?}", upstream.query);
+    }
+
+    #[test]
+    fn explains_method_not_allowed() {
+        let explanation = explain_url("DELETE", "/api/users");
+        assert_eq!(explanation.rule.as_deref(), Some("/api/"));
+        assert_eq!(explanation.error.as_ref().map(|e| e.status), Some(405));
+        assert!(explanation.upstream.is_none());
+        assert!(explanation.to_string().contains("answered 405"));
+    }
+
+    #[test]
+    fn explains_no_route() {
+        let explanation = explain_url("GET", "/other");
+        assert!(explanation.rule.is_none());
+        assert_eq!(explanation.error.as_ref().map(|e| e.status), Some(502));
+        assert!(explanation.upstream.is_none());
+        assert!(explanation.steps.iter().flat_map(|s| s.candidates.iter()).all(|c| !c.chosen));
+        assert!(explanation.to_string().contains("rule: none"));
+    }
+
+    #[test]
+    fn rejects_invalid_requests() {
+        assert!(explain_request("GET", "api", &[]).is_err());
+        assert!(explain_request("GET", "/api", &[String::from("no colon")]).is_err());
+    }
+}
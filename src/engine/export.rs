@@ -0,0 +1,296 @@
+use std::collections::BTreeMap;
+use serde::Serialize;
+
+use crate::conf::{ReboundIpFilter, ReboundRateLimit};
+
+use super::circuit::{Circuit, CircuitType};
+
+/// Version of the exported JSON schema, bumped on incompatible changes
+///
+pub const GRAPH_SCHEMA_VERSION: u32 = 1;
+
+/// Circuit as a graph, in a stable form for JSON export
+///
+/// Nodes keep the order of the rules, maps are sorted, so exports of the same rules are identical
+#[derive(Serialize, Clone, Debug)]
+pub struct CircuitGraph {
+
+    pub version: u32,
+
+    /// Node routing starts from
+    ///
+    pub head: usize,
+
+    pub nodes: Vec<GraphNode>,
+
+    pub links: Vec<GraphLink>
+
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct GraphNode {
+
+    pub id: usize,
+
+    /// routable, or error for the root answering requests no rule matched
+    ///
+    #[serde(rename = "type")]
+    pub node_type: &'static str,
+
+    pub pattern: Option<String>,
+
+    pub upstream: Option<String>,
+
+    /// Conditions a request must meet on top of its path
+    ///
+    pub predicates: GraphPredicates,
+
+    /// Changes made to requests sent upstream
+    ///
+    pub rewrites: GraphRewrites
+
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct GraphPredicates {
+
+    pub methods: Option<Vec<String>>,
+
+    pub require_client_cert: bool,
+
+    pub client_cert_subject: Option<String>,
+
+    pub client_cert_san: Option<String>,
+
+    pub ip_filter: Option<ReboundIpFilter>,
+
+    /// Kinds of credentials accepted, basic or api_key
+    ///
+    pub auth: Vec<&'static str>,
+
+    /// Whether a valid bearer JWT is required
+    ///
+    pub jwt: bool,
+
+    pub rate_limit: Option<ReboundRateLimit>
+
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct GraphRewrites {
+
+    pub preserve_hdrs: bool,
+
+    pub additional_hdrs: BTreeMap<String, String>,
+
+    pub preserve_query: bool,
+
+    pub additional_query: BTreeMap<String, String>,
+
+    pub forward_client_cert: bool
+
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct GraphLink {
+
+    pub from: usize,
+
+    pub to: usize
+
+}
+
+impl From<&Circuit> for CircuitGraph {
+    fn from(circuit: &Circuit) -> Self {
+
+        let nodes = circuit.nodes
+            .iter()
+            .enumerate()
+            .map(|(id, n)| {
+                let rule = n.rule.as_ref();
+                GraphNode {
+                    id,
+                    node_type: match n.circuit_type {
+                        CircuitType::Routable => "routable",
+                        CircuitType::Error => "error",
+                    },
+                    pattern: rule.map(|r| r.pattern.clone()),
+                    upstream: rule.map(|r| r.upstream.clone()),
+                    predicates: rule
+                        .map(|r| GraphPredicates {
+                            methods: r.allowed_methods.clone(),
+                            require_client_cert: r.require_client_cert,
+                            client_cert_subject: r.client_cert_subject.clone(),
+                            client_cert_san: r.client_cert_san.clone(),
+                            ip_filter: r.ip_filter.clone(),
+                            auth: r.auth
+                                .as_ref()
+                                .map(|a| [("basic", a.basic.is_some()), ("api_key", a.api_keys.is_some())]
+                                    .into_iter()
+                                    .filter_map(|(kind, set)| set.then_some(kind))
+                                    .collect())
+                                .unwrap_or_default(),
+                            jwt: r.jwt.is_some(),
+                            rate_limit: r.rate_limit.clone()
+                        })
+                        .unwrap_or_default(),
+                    rewrites: rule
+                        .map(|r| GraphRewrites {
+                            preserve_hdrs: r.preserve_hdrs,
+                            additional_hdrs: r.additional_hdrs.clone().into_iter().collect(),
+                            preserve_query: r.preserve_query,
+                            additional_query: r.additional_query.clone().into_iter().collect(),
+                            forward_client_cert: r.forward_client_cert
+                        })
+                        .unwrap_or_default()
+                }
+            })
+            .collect();
+
+        let links = circuit.links
+            .iter()
+            .map(|l| GraphLink { from: l.from, to: l.to })
+            .collect();
+
+        CircuitGraph { version: GRAPH_SCHEMA_VERSION, head: circuit.head_index, nodes, links }
+    }
+}
+
+impl CircuitGraph {
+
+    /// Graph in the Graphviz DOT language
+    ///
+    pub fn to_dot(&self) -> String {
+
+        let mut dot = String::from("digraph rebound {\n    rankdir=LR;\n    node [shape=box, fontname=\"monospace\"];\n");
+
+        for n in self.nodes.iter() {
+            let label = match &n.pattern {
+                None => String::from("no route"),
+                Some(pattern) => {
+                    let mut lines = vec![pattern.clone(), format!("-> {}", n.upstream.as_deref().unwrap_or_default())];
+                    if let Some(methods) = &n.predicates.methods {
+                        lines.push(format!("methods: {}", methods.join(", ")));
+                    }
+                    if n.predicates.require_client_cert {
+                        lines.push(String::from("client cert required"));
+                    }
+                    if let Some(subject) = &n.predicates.client_cert_subject {
+                        lines.push(format!("subject: {}", subject));
+                    }
+                    if let Some(san) = &n.predicates.client_cert_san {
+                        lines.push(format!("san: {}", san));
+                    }
+                    if let Some(filter) = &n.predicates.ip_filter {
+                        if !filter.allow.is_empty() {
+                            lines.push(format!("allow: {}", filter.allow.join(", ")));
+                        }
+                        if !filter.deny.is_empty() {
+                            lines.push(format!("deny: {}", filter.deny.join(", ")));
+                        }
+                    }
+                    if !n.predicates.auth.is_empty() {
+                        lines.push(format!("auth: {}", n.predicates.auth.join(", ")));
+                    }
+                    if n.predicates.jwt {
+                        lines.push(String::from("jwt required"));
+                    }
+                    if let Some(limit) = &n.predicates.rate_limit {
+                        lines.push(format!("rate limit: {}/{}ms", limit.requests, limit.period_ms));
+                    }
+                    lines.join("\n")
+                }
+            };
+            let shape = if n.id == self.head { ", shape=ellipse" } else { "" };
+            dot.push_str(&format!("    n{} [label=\"{}\"{}];\n", n.id, escape(&label), shape));
+        }
+
+        for l in self.links.iter() {
+            dot.push_str(&format!("    n{} -> n{};\n", l.from, l.to));
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// Escapes a DOT label, keeping line breaks
+///
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+
+    use serde_json::json;
+
+    use crate::engine::circuit::CircuitBuilder;
+
+    use super::*;
+
+    fn circuit(rules: serde_json::Value) -> Circuit {
+        CircuitBuilder::new(serde_json::from_value(rules).unwrap()).build().unwrap()
+    }
+
+    fn rules() -> serde_json::Value {
+        json!([
+            {
+                "pattern": "/api/",
+                "upstream": "http://api:8080",
+                "additional_hdrs": { "X-D": "4", "X-B": "2", "X-A": "1", "X-C": "3", "X-E": "5" },
+                "additional_query": { "z": "1", "a": "2", "m": "3" },
+                "allowed_methods": ["GET"],
+                "auth": { "api_keys": { "header": "X-Api-Key", "keys": { "alice": "k" } } }
+            },
+            { "pattern": "/api/admin/", "upstream": "http://admin:8080" }
+        ])
+    }
+
+    #[test]
+    fn json_is_stable() {
+        // rules keep their maps in hash maps, seeded differently each time they are read
+        let exports: Vec<String> = (0..8)
+            .map(|_| serde_json::to_string(&CircuitGraph::from(&circuit(rules()))).unwrap())
+            .collect();
+        assert!(exports.windows(2).all(|w| w[0] == w[1]));
+
+        let graph: serde_json::Value = serde_json::from_str(&exports[0]).unwrap();
+        assert_eq!(graph["version"], GRAPH_SCHEMA_VERSION);
+        let api = graph["nodes"].as_array().unwrap().iter().find(|n| n["pattern"] == "/api/").unwrap();
+        let headers: Vec<&String> = api["rewrites"]["additional_hdrs"].as_object().unwrap().keys().collect();
+        assert_eq!(headers, ["X-A", "X-B", "X-C", "X-D", "X-E"]);
+        assert_eq!(api["predicates"]["auth"], json!(["api_key"]));
+        assert_eq!(api["predicates"]["methods"], json!(["GET"]));
+    }
+
+    #[test]
+    fn json_links_rules() {
+        let graph = CircuitGraph::from(&circuit(rules()));
+        let id = |pattern: &str| graph.nodes.iter().find(|n| n.pattern.as_deref() == Some(pattern)).unwrap().id;
+        let links: Vec<(usize, usize)> = graph.links.iter().map(|l| (l.from, l.to)).collect();
+
+        assert_eq!(graph.nodes[graph.head].node_type, "error");
+        assert!(links.contains(&(graph.head, id("/api/"))), "{:?}", links);
+        assert!(links.contains(&(id("/api/"), id("/api/admin/"))), "{:?}", links);
+    }
+
+    #[test]
+    fn dot_escapes_labels() {
+        let graph = CircuitGraph::from(&circuit(json!([{
+            "pattern": "/a/",
+            "upstream": "http://a",
+            "client_cert_subject": "CN=\"a\\\\b\"\nO=x"
+        }])));
+        let dot = graph.to_dot();
+
+        assert!(dot.starts_with("digraph rebound {\n"));
+        assert!(dot.contains(r#"label="/a/\n-> http://a\nsubject: CN=\"a\\\\b\"\nO=x"];"#), "{}", dot);
+        assert!(dot.contains("label=\"no route\", shape=ellipse];"), "{}", dot);
+        // every statement stays on its own line
+        assert!(dot.lines().skip(1).all(|l| l == "}" || l.ends_with(';')), "{}", dot);
+    }
+}
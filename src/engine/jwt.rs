@@ -0,0 +1,653 @@
+use std::{fmt, fs};
+use openssl::{base64, bn::BigNum, ec::{EcGroup, EcKey}, ecdsa::EcdsaSig, hash::{hash, MessageDigest}, memcmp, nid::Nid, pkey::{Id, PKey, Public}, rsa::Rsa, sign::{Signer, Verifier}};
+use serde_json::Value;
+
+use crate::conf::{ReboundJwt, ReboundJwtAlgorithm, ReboundJwtKey};
+
+use super::{error::ReboundError, request::ReboundRequest};
+
+const AUTHORIZATION_HDR: &str = "Authorization";
+
+/// Key tokens are verified with
+///
+enum VerifyKey {
+
+    Hmac(Vec<u8>),
+
+    Rsa(PKey<Public>),
+
+    Ec(EcKey<Public>)
+
+}
+
+struct JwtKey {
+
+    kid: Option<String>,
+
+    algorithm: ReboundJwtAlgorithm,
+
+    key: VerifyKey
+
+}
+
+/// Validates the bearer JWT of the clients of a rule
+///
+pub struct JwtValidator {
+
+    conf: ReboundJwt,
+
+    keys: Vec<JwtKey>
+
+}
+
+impl fmt::Debug for JwtValidator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JwtValidator")
+            .field("keys", &self.keys.iter().map(|k| (k.kid.clone(), k.algorithm)).collect::<Vec<_>>())
+            .finish_non_exhaustive()
+    }
+}
+
+impl JwtValidator {
+
+    /// Loads the configured keys and those of the JWKS file
+    ///
+    pub fn new(conf: &ReboundJwt) -> Result<Self, String> {
+
+        let mut keys = conf.keys
+            .iter()
+            .map(load_key)
+            .collect::<Result<Vec<JwtKey>, String>>()?;
+
+        if let Some(file) = &conf.jwks_file {
+            keys.extend(load_jwks(file)?);
+        }
+        if keys.is_empty() {
+            return Err(String::from("no key to verify tokens with, set keys or jwks_file"));
+        }
+
+        Ok(JwtValidator { conf: conf.clone(), keys })
+    }
+
+    /// Validates the bearer token of the request, returning its claims
+    ///
+    pub fn validate(&self, req: &ReboundRequest) -> Result<Value, ReboundError> {
+
+        let token = req.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(AUTHORIZATION_HDR))
+            .and_then(|(_, v)| v.trim().split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Bearer"))
+            .map(|(_, token)| token.trim())
+            .ok_or_else(|| ReboundError::Unauthorized(String::from("bearer token required"), Some(format!("Bearer realm=\"{}\"", self.realm()))))?;
+
+        let claims = self.verify(token).map_err(|e| self.invalid_token(&e))?;
+        self.check_claims(&claims)?;
+        Ok(claims)
+    }
+
+    /// Sends the configured claims upstream, in place of any header the client sent
+    ///
+    pub fn forward(&self, claims: &Value, req: &mut ReboundRequest) {
+        for (claim, header) in self.conf.forward_claims.iter() {
+            req.headers.retain(|k, _| !k.eq_ignore_ascii_case(header));
+            let value = match claim_value(claims, claim) {
+                Some(Value::String(s)) => s.clone(),
+                Some(Value::Array(values)) => values.iter().map(value_str).collect::<Vec<String>>().join(","),
+                Some(Value::Null) | None => continue,
+                Some(v) => v.to_string(),
+            };
+            req.headers.insert(header.clone(), value);
+        }
+    }
+
+    /// Verifies the signature and registered claims of the token
+    ///
+    fn verify(&self, token: &str) -> Result<Value, String> {
+
+        let mut parts = token.split('.');
+        let (header, payload, signature) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(h), Some(p), Some(s), None) => (h, p, s),
+            _ => return Err(String::from("malformed token")),
+        };
+
+        let header: Value = serde_json::from_slice(&base64url_decode(header)?).map_err(|_| String::from("malformed token header"))?;
+        let alg = header.get("alg").and_then(Value::as_str).unwrap_or_default();
+        let kid = header.get("kid").and_then(Value::as_str);
+        // no extension is understood, so tokens marking any as critical are refused
+        match header.get("crit") {
+            None => {},
+            Some(Value::Array(crit)) if !crit.is_empty() && crit.iter().all(Value::is_string) => {
+                return Err(format!("unsupported critical header {}", crit.iter().filter_map(Value::as_str).collect::<Vec<&str>>().join(",")));
+            },
+            Some(_) => return Err(String::from("malformed crit header")),
+        }
+        let signature = base64url_decode(signature)?;
+        let signed = &token[..header_payload_len(token)];
+
+        // the algorithm of the key wins, so tokens can not downgrade it
+        let verified = self.keys
+            .iter()
+            .filter(|k| k.algorithm.as_str() == alg)
+            .filter(|k| kid.is_none() || k.kid.is_none() || k.kid.as_deref() == kid)
+            .any(|k| verify_signature(&k.key, signed.as_bytes(), &signature).unwrap_or(false));
+        if !verified {
+            return Err(format!("signature not verified by any {} key", if alg.is_empty() { "known" } else { alg }));
+        }
+
+        let claims: Value = serde_json::from_slice(&base64url_decode(payload)?).map_err(|_| String::from("malformed token claims"))?;
+        // NumericDate values may have a fractional part
+        let now = chrono::Utc::now().timestamp_millis() as f64 / 1000.0;
+        let leeway = self.conf.leeway_s as f64;
+
+        match claims.get("exp").and_then(Value::as_f64) {
+            Some(exp) if exp + leeway <= now => return Err(String::from("token expired")),
+            Some(_) => {},
+            None => return Err(String::from("token without exp")),
+        }
+        if let Some(nbf) = claims.get("nbf").and_then(Value::as_f64) {
+            if nbf - leeway > now {
+                return Err(String::from("token not valid yet"));
+            }
+        }
+        if let Some(issuer) = &self.conf.issuer {
+            if claims.get("iss").and_then(Value::as_str) != Some(issuer.as_str()) {
+                return Err(String::from("unexpected issuer"));
+            }
+        }
+        if !self.conf.audience.is_empty() {
+            let matches = match claims.get("aud") {
+                Some(Value::String(aud)) => self.conf.audience.contains(aud),
+                Some(Value::Array(auds)) => auds.iter().filter_map(Value::as_str).any(|a| self.conf.audience.iter().any(|e| e == a)),
+                _ => false,
+            };
+            if !matches {
+                return Err(String::from("unexpected audience"));
+            }
+        }
+
+        Ok(claims)
+    }
+
+    /// Checks the token has the required claims, with one of their accepted values
+    ///
+    /// String claims also match on any of their space separated values, as OAuth scopes
+    fn check_claims(&self, claims: &Value) -> Result<(), ReboundError> {
+        for (claim, accepted) in self.conf.required_claims.iter() {
+            let value = claim_value(claims, claim)
+                .filter(|v| !v.is_null())
+                .ok_or_else(|| ReboundError::Forbidden(format!("token without {} claim", claim)))?;
+
+            let values: Vec<String> = match value {
+                Value::Array(values) => values.iter().map(value_str).collect(),
+                Value::String(s) => s.split_whitespace().map(String::from).chain(std::iter::once(s.clone())).collect(),
+                v => vec![value_str(v)],
+            };
+            if !accepted.is_empty() && !values.iter().any(|v| accepted.contains(v)) {
+                return Err(ReboundError::Forbidden(format!("{} claim not allowed", claim)));
+            }
+        }
+        Ok(())
+    }
+
+    fn invalid_token(&self, reason: &str) -> ReboundError {
+        ReboundError::Unauthorized(
+            format!("invalid bearer token: {}", reason),
+            Some(format!("Bearer realm=\"{}\", error=\"invalid_token\"", self.realm()))
+        )
+    }
+
+    fn realm(&self) -> String {
+        self.conf.realm.replace('"', "")
+    }
+}
+
+/// Length of the signed part of a token, its header and payload
+///
+fn header_payload_len(token: &str) -> usize {
+    token.rfind('.').unwrap_or_default()
+}
+
+/// Claim at a dotted path, e.g. realm_access.roles
+///
+fn claim_value<'a>(claims: &'a Value, path: &str) -> Option<&'a Value> {
+    claims.get(path).or_else(|| path.split('.').try_fold(claims, |v, name| v.get(name)))
+}
+
+fn value_str(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+fn verify_signature(key: &VerifyKey, signed: &[u8], signature: &[u8]) -> Result<bool, openssl::error::ErrorStack> {
+    match key {
+        VerifyKey::Hmac(secret) => {
+            let pkey = PKey::hmac(secret)?;
+            let expected = Signer::new(MessageDigest::sha256(), &pkey)?.sign_oneshot_to_vec(signed)?;
+            Ok(expected.len() == signature.len() && memcmp::eq(&expected, signature))
+        },
+        VerifyKey::Rsa(pkey) => Verifier::new(MessageDigest::sha256(), pkey)?.verify_oneshot(signature, signed),
+        VerifyKey::Ec(ec) => {
+            // JWS signatures are r and s side by side, not DER
+            if signature.len() != 64 {
+                return Ok(false);
+            }
+            let sig = EcdsaSig::from_private_components(BigNum::from_slice(&signature[..32])?, BigNum::from_slice(&signature[32..])?)?;
+            sig.verify(&hash(MessageDigest::sha256(), signed)?, ec)
+        },
+    }
+}
+
+/// Decodes unpadded base64url, as used by JWS and JWKS
+///
+fn base64url_decode(s: &str) -> Result<Vec<u8>, String> {
+    let mut b64: String = s.chars().map(|c| match c { '-' => '+', '_' => '/', c => c }).collect();
+    while !b64.len().is_multiple_of(4) {
+        b64.push('=');
+    }
+    base64::decode_block(&b64).map_err(|_| String::from("invalid base64url"))
+}
+
+fn load_key(conf: &ReboundJwtKey) -> Result<JwtKey, String> {
+
+    let name = conf.kid.clone().unwrap_or_else(|| String::from(conf.algorithm.as_str()));
+    let key = match conf.algorithm {
+        ReboundJwtAlgorithm::Hs256 => VerifyKey::Hmac(
+            conf.secret
+                .as_ref()
+                .filter(|s| !s.is_empty())
+                .ok_or_else(|| format!("key {}: HS256 needs a secret", name))?
+                .as_bytes()
+                .to_vec()
+        ),
+        alg => {
+            let file = conf.public_key
+                .as_ref()
+                .ok_or_else(|| format!("key {}: {} needs a public_key", name, alg.as_str()))?;
+            let pem = fs::read(file).map_err(|e| format!("key {}: cannot read {}: {}", name, file, e))?;
+            let pkey = PKey::public_key_from_pem(&pem).map_err(|e| format!("key {}: invalid public key {}: {}", name, file, e))?;
+            public_key(pkey, alg).map_err(|e| format!("key {}: {}", name, e))?
+        },
+    };
+
+    Ok(JwtKey { kid: conf.kid.clone(), algorithm: conf.algorithm, key })
+}
+
+/// Key for the algorithm, checking the key type matches it
+///
+fn public_key(pkey: PKey<Public>, algorithm: ReboundJwtAlgorithm) -> Result<VerifyKey, String> {
+    match (algorithm, pkey.id()) {
+        (ReboundJwtAlgorithm::Rs256, Id::RSA) => Ok(VerifyKey::Rsa(pkey)),
+        (ReboundJwtAlgorithm::Es256, Id::EC) => {
+            let ec = pkey.ec_key().map_err(|e| e.to_string())?;
+            match ec.group().curve_name() {
+                Some(Nid::X9_62_PRIME256V1) => Ok(VerifyKey::Ec(ec)),
+                _ => Err(String::from("ES256 needs a P-256 key")),
+            }
+        },
+        (alg, _) => Err(format!("key type does not match {}", alg.as_str())),
+    }
+}
+
+/// Reads the keys of a JWKS file, skipping keys of other types and uses
+///
+fn load_jwks(file: &str) -> Result<Vec<JwtKey>, String> {
+
+    let content = fs::read(file).map_err(|e| format!("cannot read {}: {}", file, e))?;
+    let jwks: Value = serde_json::from_slice(&content).map_err(|e| format!("invalid JWKS {}: {}", file, e))?;
+    let entries = jwks.get("keys")
+        .and_then(Value::as_array)
+        .ok_or_else(|| format!("invalid JWKS {}: no keys", file))?;
+
+    let mut keys = Vec::new();
+    for (i, jwk) in entries.iter().enumerate() {
+
+        let field = |name: &str| jwk.get(name).and_then(Value::as_str);
+        if field("use").map(|u| u != "sig").unwrap_or(false) {
+            continue;
+        }
+        let decode = |name: &str| {
+            field(name)
+                .ok_or_else(|| format!("{}: key {} has no {}", file, i, name))
+                .and_then(|v| base64url_decode(v).map_err(|e| format!("{}: key {} {}: {}", file, i, name, e)))
+        };
+
+        let (algorithm, key) = match field("kty") {
+            Some("RSA") => {
+                let rsa = Rsa::from_public_components(
+                    BigNum::from_slice(&decode("n")?).map_err(|e| e.to_string())?,
+                    BigNum::from_slice(&decode("e")?).map_err(|e| e.to_string())?
+                ).map_err(|e| format!("{}: key {}: {}", file, i, e))?;
+                (ReboundJwtAlgorithm::Rs256, VerifyKey::Rsa(PKey::from_rsa(rsa).map_err(|e| e.to_string())?))
+            },
+            Some("EC") if field("crv") == Some("P-256") => {
+                let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).map_err(|e| e.to_string())?;
+                let x = BigNum::from_slice(&decode("x")?).map_err(|e| e.to_string())?;
+                let y = BigNum::from_slice(&decode("y")?).map_err(|e| e.to_string())?;
+                let ec = EcKey::from_public_key_affine_coordinates(&group, &x, &y)
+                    .and_then(|ec| ec.check_key().map(|_| ec))
+                    .map_err(|e| format!("{}: key {}: {}", file, i, e))?;
+                (ReboundJwtAlgorithm::Es256, VerifyKey::Ec(ec))
+            },
+            Some("oct") => (ReboundJwtAlgorithm::Hs256, VerifyKey::Hmac(decode("k")?)),
+            _ => continue,
+        };
+
+        // keys naming another algorithm are not used for this one
+        if field("alg").map(|a| a != algorithm.as_str()).unwrap_or(false) {
+            continue;
+        }
+        keys.push(JwtKey { kid: field("kid").map(String::from), algorithm, key });
+    }
+
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::{env, path::PathBuf};
+    use openssl::{ec::EcKey, pkey::Private, rsa::Rsa};
+    use serde_json::json;
+    use tiny_http::{Header, Method};
+
+    use crate::engine::request::ReboundIngressRequestBuilder;
+
+    use super::*;
+
+    const SECRET: &str = "a-long-enough-shared-secret";
+
+    fn base64url(data: &[u8]) -> String {
+        base64::encode_block(data).replace('+', "-").replace('/', "_").trim_end_matches('=').to_string()
+    }
+
+    fn now() -> i64 {
+        chrono::Utc::now().timestamp()
+    }
+
+    /// Token with the given header and claims, signed by the given function
+    ///
+    fn token(header: Value, claims: Value, sign: impl Fn(&[u8]) -> Vec<u8>) -> String {
+        let signed = format!("{}.{}", base64url(header.to_string().as_bytes()), base64url(claims.to_string().as_bytes()));
+        format!("{}.{}", signed, base64url(&sign(signed.as_bytes())))
+    }
+
+    fn hs256(secret: &str) -> impl Fn(&[u8]) -> Vec<u8> + '_ {
+        move |data| {
+            let pkey = PKey::hmac(secret.as_bytes()).unwrap();
+            Signer::new(MessageDigest::sha256(), &pkey).unwrap().sign_oneshot_to_vec(data).unwrap()
+        }
+    }
+
+    fn rs256(key: &PKey<Private>) -> impl Fn(&[u8]) -> Vec<u8> + '_ {
+        move |data| Signer::new(MessageDigest::sha256(), key).unwrap().sign_oneshot_to_vec(data).unwrap()
+    }
+
+    fn es256(key: &EcKey<Private>) -> impl Fn(&[u8]) -> Vec<u8> + '_ {
+        move |data| {
+            let sig = EcdsaSig::sign(&hash(MessageDigest::sha256(), data).unwrap(), key).unwrap();
+            [sig.r().to_vec_padded(32).unwrap(), sig.s().to_vec_padded(32).unwrap()].concat()
+        }
+    }
+
+    fn claims() -> Value {
+        json!({ "sub": "alice", "exp": now() + 60 })
+    }
+
+    fn validator(conf: Value) -> JwtValidator {
+        JwtValidator::new(&serde_json::from_value(conf).unwrap()).unwrap()
+    }
+
+    fn hs256_validator(extra: Value) -> JwtValidator {
+        let mut conf = json!({ "keys": [{ "algorithm": "HS256", "secret": SECRET }] });
+        if let (Some(conf), Value::Object(extra)) = (conf.as_object_mut(), extra) {
+            conf.extend(extra);
+        }
+        validator(conf)
+    }
+
+    fn hs256_token(claims: Value) -> String {
+        token(json!({ "alg": "HS256", "typ": "JWT" }), claims, hs256(SECRET))
+    }
+
+    /// Writes a test file under the temp dir, named after the test so tests do not share files
+    ///
+    fn temp_file(name: &str, content: &[u8]) -> String {
+        let path: PathBuf = env::temp_dir().join(format!("rebound-jwt-{}-{}", std::process::id(), name));
+        fs::write(&path, content).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    fn request(authorization: Option<&str>) -> ReboundRequest {
+        let headers: Vec<Header> = authorization
+            .map(|v| Header::from_bytes(AUTHORIZATION_HDR.as_bytes(), v.as_bytes()).unwrap())
+            .into_iter()
+            .collect();
+        ReboundIngressRequestBuilder::new()
+            .with_url(String::from("/api"))
+            .with_headers(&headers)
+            .with_method(&Method::Get)
+            .build()
+    }
+
+    #[test]
+    fn hs256_accepts_signed_token() {
+        let claims = hs256_validator(json!({})).verify(&hs256_token(claims())).unwrap();
+        assert_eq!(claims["sub"], "alice");
+    }
+
+    #[test]
+    fn hs256_rejects_other_secret() {
+        let t = token(json!({ "alg": "HS256" }), claims(), hs256("another-secret"));
+        assert!(hs256_validator(json!({})).verify(&t).is_err());
+    }
+
+    #[test]
+    fn rejects_tampered_claims() {
+        let t = hs256_token(claims());
+        let mut parts: Vec<&str> = t.split('.').collect();
+        let forged = base64url(json!({ "sub": "admin", "exp": now() + 60 }).to_string().as_bytes());
+        parts[1] = &forged;
+        assert!(hs256_validator(json!({})).verify(&parts.join(".")).is_err());
+    }
+
+    #[test]
+    fn rejects_unsigned_and_other_algorithms() {
+        let v = hs256_validator(json!({}));
+
+        let none = format!("{}.{}.", base64url(br#"{"alg":"none"}"#), base64url(claims().to_string().as_bytes()));
+        assert!(v.verify(&none).is_err());
+
+        // an HS256 key is only used for HS256, whatever the token header says
+        let t = token(json!({ "alg": "RS256" }), claims(), hs256(SECRET));
+        assert!(v.verify(&t).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_tokens() {
+        let v = hs256_validator(json!({}));
+        assert!(v.verify("").is_err());
+        assert!(v.verify("a.b").is_err());
+        assert!(v.verify("a.b.c.d").is_err());
+        assert!(v.verify("!!.??.**").is_err());
+    }
+
+    #[test]
+    fn rejects_critical_headers() {
+        let v = hs256_validator(json!({}));
+
+        let t = token(json!({ "alg": "HS256", "crit": ["b64"], "b64": false }), claims(), hs256(SECRET));
+        assert!(v.verify(&t).unwrap_err().contains("critical"));
+
+        let t = token(json!({ "alg": "HS256", "crit": [] }), claims(), hs256(SECRET));
+        assert!(v.verify(&t).is_err());
+    }
+
+    #[test]
+    fn checks_expiry_with_leeway() {
+        let v = hs256_validator(json!({ "leeway_s": 30 }));
+
+        assert!(v.verify(&hs256_token(json!({ "exp": now() - 10 }))).is_ok());
+        assert_eq!(v.verify(&hs256_token(json!({ "exp": now() - 60 }))).unwrap_err(), "token expired");
+        assert_eq!(v.verify(&hs256_token(json!({ "sub": "alice" }))).unwrap_err(), "token without exp");
+        assert!(v.verify(&hs256_token(json!({ "exp": now() as f64 + 60.5 }))).is_ok());
+    }
+
+    #[test]
+    fn checks_not_before() {
+        let v = hs256_validator(json!({ "leeway_s": 0 }));
+
+        assert!(v.verify(&hs256_token(json!({ "exp": now() + 60, "nbf": now() - 1 }))).is_ok());
+        assert_eq!(v.verify(&hs256_token(json!({ "exp": now() + 600, "nbf": now() + 300 }))).unwrap_err(), "token not valid yet");
+    }
+
+    #[test]
+    fn checks_issuer_and_audience() {
+        let v = hs256_validator(json!({ "issuer": "https://idp", "audience": ["api", "web"] }));
+        let exp = now() + 60;
+
+        assert!(v.verify(&hs256_token(json!({ "exp": exp, "iss": "https://idp", "aud": "api" }))).is_ok());
+        assert!(v.verify(&hs256_token(json!({ "exp": exp, "iss": "https://idp", "aud": ["other", "web"] }))).is_ok());
+        assert_eq!(v.verify(&hs256_token(json!({ "exp": exp, "iss": "https://evil", "aud": "api" }))).unwrap_err(), "unexpected issuer");
+        assert_eq!(v.verify(&hs256_token(json!({ "exp": exp, "iss": "https://idp", "aud": "other" }))).unwrap_err(), "unexpected audience");
+        assert_eq!(v.verify(&hs256_token(json!({ "exp": exp, "iss": "https://idp" }))).unwrap_err(), "unexpected audience");
+    }
+
+    #[test]
+    fn selects_keys_by_kid() {
+        let v = validator(json!({ "keys": [
+            { "kid": "one", "algorithm": "HS256", "secret": "secret-one" },
+            { "kid": "two", "algorithm": "HS256", "secret": "secret-two" }
+        ] }));
+
+        assert!(v.verify(&token(json!({ "alg": "HS256", "kid": "two" }), claims(), hs256("secret-two"))).is_ok());
+        assert!(v.verify(&token(json!({ "alg": "HS256" }), claims(), hs256("secret-one"))).is_ok());
+        assert!(v.verify(&token(json!({ "alg": "HS256", "kid": "one" }), claims(), hs256("secret-two"))).is_err());
+        assert!(v.verify(&token(json!({ "alg": "HS256", "kid": "three" }), claims(), hs256("secret-one"))).is_err());
+    }
+
+    #[test]
+    fn rs256_public_key_file() {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let other = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let file = temp_file("rs256.pem", &key.public_key_to_pem().unwrap());
+        let v = validator(json!({ "keys": [{ "algorithm": "RS256", "public_key": file }] }));
+
+        assert!(v.verify(&token(json!({ "alg": "RS256" }), claims(), rs256(&key))).is_ok());
+        assert!(v.verify(&token(json!({ "alg": "RS256" }), claims(), rs256(&other))).is_err());
+    }
+
+    #[test]
+    fn es256_public_key_file() {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = EcKey::generate(&group).unwrap();
+        let file = temp_file("es256.pem", &key.public_key_to_pem().unwrap());
+        let v = validator(json!({ "keys": [{ "algorithm": "ES256", "public_key": file }] }));
+
+        assert!(v.verify(&token(json!({ "alg": "ES256" }), claims(), es256(&key))).is_ok());
+
+        // DER encoded signatures are not JWS signatures
+        let der = |data: &[u8]| EcdsaSig::sign(&hash(MessageDigest::sha256(), data).unwrap(), &key).unwrap().to_der().unwrap();
+        assert!(v.verify(&token(json!({ "alg": "ES256" }), claims(), der)).is_err());
+    }
+
+    #[test]
+    fn rejects_keys_not_matching_algorithm() {
+        let ec = EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap();
+        let file = temp_file("mismatch.pem", &ec.public_key_to_pem().unwrap());
+        let conf = json!({ "keys": [{ "algorithm": "RS256", "public_key": file }] });
+        assert!(JwtValidator::new(&serde_json::from_value(conf).unwrap()).is_err());
+
+        let p384 = EcKey::generate(&EcGroup::from_curve_name(Nid::SECP384R1).unwrap()).unwrap();
+        let file = temp_file("p384.pem", &p384.public_key_to_pem().unwrap());
+        let conf = json!({ "keys": [{ "algorithm": "ES256", "public_key": file }] });
+        assert!(JwtValidator::new(&serde_json::from_value(conf).unwrap()).is_err());
+
+        let conf = json!({ "keys": [{ "algorithm": "HS256", "secret": "" }] });
+        assert!(JwtValidator::new(&serde_json::from_value(conf).unwrap()).is_err());
+        assert!(JwtValidator::new(&ReboundJwt::default()).is_err());
+    }
+
+    #[test]
+    fn jwks_keys() {
+        let rsa = Rsa::generate(2048).unwrap();
+        let ec = EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap();
+        let (mut x, mut y, mut ctx) = (BigNum::new().unwrap(), BigNum::new().unwrap(), openssl::bn::BigNumContext::new().unwrap());
+        ec.public_key().affine_coordinates(ec.group(), &mut x, &mut y, &mut ctx).unwrap();
+
+        let jwks = json!({ "keys": [
+            { "kty": "RSA", "kid": "rsa", "use": "sig", "n": base64url(&rsa.n().to_vec()), "e": base64url(&rsa.e().to_vec()) },
+            { "kty": "EC", "kid": "ec", "crv": "P-256", "x": base64url(&x.to_vec_padded(32).unwrap()), "y": base64url(&y.to_vec_padded(32).unwrap()) },
+            { "kty": "oct", "kid": "oct", "k": base64url(SECRET.as_bytes()) },
+            { "kty": "oct", "kid": "enc", "use": "enc", "k": base64url(b"encryption-key") },
+            { "kty": "oct", "kid": "other-alg", "alg": "HS512", "k": base64url(b"hs512-key") },
+            { "kty": "OKP", "kid": "unknown", "crv": "Ed25519", "x": "AA" }
+        ] });
+        let file = temp_file("jwks.json", jwks.to_string().as_bytes());
+        let v = validator(json!({ "jwks_file": file }));
+        let rsa = PKey::from_rsa(rsa).unwrap();
+
+        assert!(v.verify(&token(json!({ "alg": "RS256", "kid": "rsa" }), claims(), rs256(&rsa))).is_ok());
+        assert!(v.verify(&token(json!({ "alg": "ES256", "kid": "ec" }), claims(), es256(&ec))).is_ok());
+        assert!(v.verify(&token(json!({ "alg": "HS256", "kid": "oct" }), claims(), hs256(SECRET))).is_ok());
+        assert!(v.verify(&token(json!({ "alg": "HS256" }), claims(), hs256("encryption-key"))).is_err());
+        assert!(v.verify(&token(json!({ "alg": "HS256" }), claims(), hs256("hs512-key"))).is_err());
+    }
+
+    #[test]
+    fn invalid_jwks() {
+        let file = temp_file("invalid-jwks.json", b"{\"no\": \"keys\"}");
+        assert!(JwtValidator::new(&serde_json::from_value(json!({ "jwks_file": file })).unwrap()).is_err());
+
+        let conf = json!({ "jwks_file": "/nonexistent/rebound/jwks.json" });
+        assert!(JwtValidator::new(&serde_json::from_value(conf).unwrap()).is_err());
+    }
+
+    #[test]
+    fn validate_needs_bearer_token() {
+        let v = hs256_validator(json!({}));
+        let t = hs256_token(claims());
+
+        assert!(v.validate(&request(Some(&format!("Bearer {}", t)))).is_ok());
+        assert!(v.validate(&request(Some(&format!("bearer  {} ", t)))).is_ok());
+        assert!(matches!(v.validate(&request(None)), Err(ReboundError::Unauthorized(_, Some(_)))));
+        assert!(matches!(v.validate(&request(Some("Basic YWxpY2U6cHc="))), Err(ReboundError::Unauthorized(_, _))));
+        assert!(matches!(v.validate(&request(Some("Bearer nope"))), Err(ReboundError::Unauthorized(_, Some(c))) if c.contains("invalid_token")));
+    }
+
+    #[test]
+    fn required_claims() {
+        let v = hs256_validator(json!({ "required_claims": {
+            "scope": ["read"],
+            "realm_access.roles": ["admin"],
+            "tenant": []
+        } }));
+        let exp = now() + 60;
+
+        let ok = hs256_token(json!({ "exp": exp, "scope": "openid read", "realm_access": { "roles": ["user", "admin"] }, "tenant": 7 }));
+        assert!(v.validate(&request(Some(&format!("Bearer {}", ok)))).is_ok());
+
+        let no_role = hs256_token(json!({ "exp": exp, "scope": "read", "realm_access": { "roles": ["user"] }, "tenant": 7 }));
+        assert!(matches!(v.validate(&request(Some(&format!("Bearer {}", no_role)))), Err(ReboundError::Forbidden(_))));
+
+        let no_tenant = hs256_token(json!({ "exp": exp, "scope": "read", "realm_access": { "roles": ["admin"] }, "tenant": null }));
+        assert!(matches!(v.validate(&request(Some(&format!("Bearer {}", no_tenant)))), Err(ReboundError::Forbidden(_))));
+    }
+
+    #[test]
+    fn forwards_claims_over_client_headers() {
+        let v = hs256_validator(json!({ "forward_claims": { "sub": "X-User", "groups": "X-Groups", "missing": "X-Missing" } }));
+        let mut req = request(None);
+        req.headers.insert(String::from("x-user"), String::from("spoofed"));
+        req.headers.insert(String::from("X-Missing"), String::from("spoofed"));
+
+        v.forward(&json!({ "sub": "alice", "groups": ["a", "b"] }), &mut req);
+
+        assert_eq!(req.headers.get("X-User").map(String::as_str), Some("alice"));
+        assert_eq!(req.headers.get("X-Groups").map(String::as_str), Some("a,b"));
+        assert!(!req.headers.contains_key("x-user"));
+        assert!(!req.headers.contains_key("X-Missing"));
+    }
+}
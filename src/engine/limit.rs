@@ -0,0 +1,364 @@
+use std::{collections::HashMap, sync::Mutex, time::{Duration, Instant}};
+
+use crate::conf::{ReboundRateLimit, ReboundRateLimitAlgorithm, ReboundRateLimitKey};
+
+use super::{error::ReboundError, request::ReboundRequest};
+
+/// Number of checks between two sweeps of the idle keys
+///
+const SWEEP_INTERVAL: u64 = 1024;
+
+/// Key part of requests without the client address or header the limit is keyed by
+///
+const MISSING_KEY: &str = "-";
+
+/// Rate limit a request went over, as told to the client
+///
+#[derive(Clone, Debug)]
+pub struct RateLimitExceeded {
+
+    /// Requests allowed per window
+    ///
+    pub limit: u32,
+
+    /// Window of the limit, in seconds
+    ///
+    pub window: u64,
+
+    /// Seconds until a request is allowed again
+    ///
+    pub retry_after: u64
+
+}
+
+/// State of the requests of a key
+///
+#[derive(Debug)]
+enum LimitState {
+
+    Bucket { tokens: f64, updated: Instant },
+
+    Window { start: Instant, current: u32, previous: u32 }
+
+}
+
+#[derive(Debug)]
+struct LimitStates {
+
+    states: HashMap<String, LimitState>,
+
+    checks: u64
+
+}
+
+/// In-memory rate limiter, shared by every worker
+///
+#[derive(Debug)]
+pub struct RateLimiter {
+
+    conf: ReboundRateLimit,
+
+    states: Mutex<LimitStates>
+
+}
+
+impl RateLimiter {
+
+    pub fn new(conf: &ReboundRateLimit) -> Self {
+        RateLimiter {
+            conf: conf.clone(),
+            states: Mutex::new(LimitStates { states: HashMap::new(), checks: 0 })
+        }
+    }
+
+    /// Counts the request, failing when it goes over the limit of its key
+    ///
+    pub fn check(&self, req: &ReboundRequest) -> Result<(), ReboundError> {
+        self.check_at(req, Instant::now())
+    }
+
+    fn check_at(&self, req: &ReboundRequest, now: Instant) -> Result<(), ReboundError> {
+
+        let key = self.key(req);
+        let period = self.period();
+
+        let mut states = match self.states.lock() {
+            Ok(states) => states,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        states.checks += 1;
+        if states.checks % SWEEP_INTERVAL == 0 {
+            states.states.retain(|_, state| !self.is_idle(state, now));
+        }
+
+        let state = states.states
+            .entry(key)
+            .or_insert_with(|| match self.conf.algorithm {
+                ReboundRateLimitAlgorithm::TokenBucket => LimitState::Bucket { tokens: self.capacity(), updated: now },
+                ReboundRateLimitAlgorithm::SlidingWindow => LimitState::Window { start: now, current: 0, previous: 0 },
+            });
+
+        let wait = match state {
+            LimitState::Bucket { tokens, updated } => {
+                *tokens = (*tokens + now.duration_since(*updated).as_secs_f64() * self.rate()).min(self.capacity());
+                *updated = now;
+                if *tokens >= 1.0 {
+                    *tokens -= 1.0;
+                    None
+                }
+                else {
+                    Some(Duration::from_secs_f64((1.0 - *tokens) / self.rate()))
+                }
+            },
+            LimitState::Window { start, current, previous } => {
+                let elapsed = now.duration_since(*start);
+                if elapsed >= period {
+                    let windows = (elapsed.as_nanos() / period.as_nanos()) as u32;
+                    *previous = if windows == 1 { *current } else { 0 };
+                    *current = 0;
+                    *start += period * windows;
+                }
+
+                let limit = self.conf.requests as f64;
+                let overlap = 1.0 - now.duration_since(*start).as_secs_f64() / period.as_secs_f64();
+                if *previous as f64 * overlap + *current as f64 + 1.0 <= limit {
+                    *current += 1;
+                    None
+                }
+                else if (*current as f64) + 1.0 <= limit {
+                    // the previous window weighs less as time goes by
+                    let needed = (limit - *current as f64 - 1.0) / *previous as f64;
+                    Some(period.mul_f64((overlap - needed).max(0.0)))
+                }
+                else {
+                    // the current window has to become the previous one, and weigh less
+                    let needed = (limit - 1.0) / *current as f64;
+                    Some(period.mul_f64(overlap + (1.0 - needed).max(0.0)))
+                }
+            },
+        };
+
+        match wait {
+            None => Ok(()),
+            Some(wait) => Err(ReboundError::RateLimited(RateLimitExceeded {
+                limit: self.conf.requests,
+                window: ceil_secs(period),
+                retry_after: ceil_secs(wait)
+            })),
+        }
+    }
+
+    /// Key the request is counted by, from the parts the limit is keyed by
+    ///
+    fn key(&self, req: &ReboundRequest) -> String {
+        self.conf.key
+            .iter()
+            .map(|part| match part {
+                ReboundRateLimitKey::ClientIp => req.client_addr
+                    .map(|ip| ip.to_string())
+                    .unwrap_or_else(|| String::from(MISSING_KEY)),
+                ReboundRateLimitKey::Header(name) => req.headers
+                    .iter()
+                    .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                    .map(|(_, v)| v.clone())
+                    .unwrap_or_else(|| String::from(MISSING_KEY)),
+            })
+            .collect::<Vec<String>>()
+            .join("\0")
+    }
+
+    /// Whether the state is back to the one of a new key, and can be dropped
+    ///
+    fn is_idle(&self, state: &LimitState, now: Instant) -> bool {
+        match state {
+            LimitState::Bucket { tokens, updated } => {
+                tokens + now.duration_since(*updated).as_secs_f64() * self.rate() >= self.capacity()
+            },
+            LimitState::Window { start, .. } => now.duration_since(*start) >= self.period() * 2,
+        }
+    }
+
+    fn period(&self) -> Duration {
+        Duration::from_millis(self.conf.period_ms.max(1))
+    }
+
+    /// Tokens refilled per second
+    ///
+    fn rate(&self) -> f64 {
+        self.conf.requests as f64 / self.period().as_secs_f64()
+    }
+
+    fn capacity(&self) -> f64 {
+        self.conf.burst.unwrap_or(self.conf.requests) as f64
+    }
+}
+
+/// Checks a rate limit can be enforced
+///
+pub fn validate(conf: &ReboundRateLimit) -> Result<(), String> {
+
+    if conf.requests == 0 {
+        return Err(String::from("requests must be at least 1"));
+    }
+    if conf.period_ms == 0 {
+        return Err(String::from("period_ms must be at least 1"));
+    }
+    if conf.burst == Some(0) {
+        return Err(String::from("burst must be at least 1, leave it out to default to requests"));
+    }
+    if conf.key.iter().any(|k| matches!(k, ReboundRateLimitKey::Header(name) if name.trim().is_empty())) {
+        return Err(String::from("key header name must not be empty"));
+    }
+    Ok(())
+}
+
+/// Whole seconds of a duration, rounded up to at least one
+///
+fn ceil_secs(d: Duration) -> u64 {
+    (d.as_secs_f64().ceil() as u64).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::net::IpAddr;
+    use serde_json::json;
+    use tiny_http::Method;
+
+    use crate::engine::request::ReboundIngressRequestBuilder;
+
+    use super::*;
+
+    fn limiter(conf: serde_json::Value) -> RateLimiter {
+        let conf: ReboundRateLimit = serde_json::from_value(conf).unwrap();
+        validate(&conf).unwrap();
+        RateLimiter::new(&conf)
+    }
+
+    fn request(client: Option<&str>, headers: &[(&str, &str)]) -> ReboundRequest {
+        let mut req = ReboundIngressRequestBuilder::new()
+            .with_url(String::from("/"))
+            .with_method(&Method::Get)
+            .build();
+        req.client_addr = client.map(|c| c.parse::<IpAddr>().unwrap());
+        req.headers.extend(headers.iter().map(|(k, v)| (k.to_string(), v.to_string())));
+        req
+    }
+
+    /// Number of requests allowed in a row at the given instant
+    ///
+    fn allowed(limiter: &RateLimiter, req: &ReboundRequest, at: Instant) -> u32 {
+        (0..1000).take_while(|_| limiter.check_at(req, at).is_ok()).count() as u32
+    }
+
+    fn retry_after(limiter: &RateLimiter, req: &ReboundRequest, at: Instant) -> u64 {
+        match limiter.check_at(req, at) {
+            Err(ReboundError::RateLimited(exceeded)) => exceeded.retry_after,
+            _ => panic!("request not rate limited"),
+        }
+    }
+
+    #[test]
+    fn token_bucket_burst_and_refill() {
+        let limiter = limiter(json!({ "requests": 10, "period_ms": 10000, "burst": 5 }));
+        let req = request(Some("10.0.0.1"), &[]);
+        let t0 = Instant::now();
+
+        assert_eq!(allowed(&limiter, &req, t0), 5);
+        assert_eq!(retry_after(&limiter, &req, t0), 1);
+
+        // one token per second
+        assert_eq!(allowed(&limiter, &req, t0 + Duration::from_millis(2500)), 2);
+        assert_eq!(retry_after(&limiter, &req, t0 + Duration::from_millis(2500)), 1);
+
+        // refills up to the burst only
+        assert_eq!(allowed(&limiter, &req, t0 + Duration::from_secs(100)), 5);
+    }
+
+    #[test]
+    fn token_bucket_burst_defaults_to_requests() {
+        let limiter = limiter(json!({ "requests": 3, "period_ms": 60000 }));
+        let req = request(Some("10.0.0.1"), &[]);
+        let t0 = Instant::now();
+
+        assert_eq!(allowed(&limiter, &req, t0), 3);
+        match limiter.check_at(&req, t0) {
+            Err(ReboundError::RateLimited(exceeded)) => {
+                assert_eq!((exceeded.limit, exceeded.window, exceeded.retry_after), (3, 60, 20));
+            },
+            _ => panic!("request not rate limited"),
+        }
+    }
+
+    #[test]
+    fn sliding_window_weights_previous_window() {
+        let limiter = limiter(json!({ "requests": 10, "period_ms": 10000, "algorithm": "sliding_window" }));
+        let req = request(Some("10.0.0.1"), &[]);
+        let t0 = Instant::now();
+
+        assert_eq!(allowed(&limiter, &req, t0), 10);
+        // the full window has to slide out before the first request of the next one
+        assert_eq!(retry_after(&limiter, &req, t0), 11);
+
+        // halfway through the next window, the previous one counts for half
+        let half = t0 + Duration::from_secs(15);
+        assert_eq!(allowed(&limiter, &req, half), 5);
+        assert_eq!(retry_after(&limiter, &req, half), 1);
+        assert_eq!(allowed(&limiter, &req, half + Duration::from_millis(1500)), 1);
+    }
+
+    #[test]
+    fn sliding_window_forgets_old_windows() {
+        let limiter = limiter(json!({ "requests": 10, "period_ms": 10000, "algorithm": "sliding_window" }));
+        let req = request(Some("10.0.0.1"), &[]);
+        let t0 = Instant::now();
+
+        assert_eq!(allowed(&limiter, &req, t0), 10);
+        assert_eq!(allowed(&limiter, &req, t0 + Duration::from_secs(25)), 10);
+    }
+
+    #[test]
+    fn keys_by_client_and_header() {
+        let limiter = limiter(json!({ "requests": 1, "period_ms": 60000, "key": ["client_ip", { "header": "X-Api-Key" }] }));
+        let t0 = Instant::now();
+
+        assert_eq!(allowed(&limiter, &request(Some("10.0.0.1"), &[("x-api-key", "a")]), t0), 1);
+        assert_eq!(allowed(&limiter, &request(Some("10.0.0.1"), &[("X-Api-Key", "a")]), t0), 0);
+        assert_eq!(allowed(&limiter, &request(Some("10.0.0.1"), &[("X-Api-Key", "b")]), t0), 1);
+        assert_eq!(allowed(&limiter, &request(Some("10.0.0.2"), &[("X-Api-Key", "a")]), t0), 1);
+
+        // requests without the key parts share theirs
+        assert_eq!(allowed(&limiter, &request(None, &[]), t0), 1);
+        assert_eq!(allowed(&limiter, &request(None, &[]), t0), 0);
+    }
+
+    #[test]
+    fn idle_states() {
+        let limiter = limiter(json!({ "requests": 10, "period_ms": 10000 }));
+        let t0 = Instant::now();
+
+        assert!(!limiter.is_idle(&LimitState::Bucket { tokens: 5.0, updated: t0 }, t0 + Duration::from_secs(4)));
+        assert!(limiter.is_idle(&LimitState::Bucket { tokens: 5.0, updated: t0 }, t0 + Duration::from_secs(5)));
+        assert!(!limiter.is_idle(&LimitState::Window { start: t0, current: 3, previous: 0 }, t0 + Duration::from_secs(19)));
+        assert!(limiter.is_idle(&LimitState::Window { start: t0, current: 3, previous: 0 }, t0 + Duration::from_secs(20)));
+    }
+
+    #[test]
+    fn invalid_limits() {
+        let invalid = |conf: serde_json::Value| validate(&serde_json::from_value(conf).unwrap()).is_err();
+
+        assert!(invalid(json!({ "requests": 0 })));
+        assert!(invalid(json!({ "requests": 1, "period_ms": 0 })));
+        assert!(invalid(json!({ "requests": 1, "burst": 0 })));
+        assert!(invalid(json!({ "requests": 1, "key": [{ "header": " " }] })));
+        assert!(!invalid(json!({ "requests": 1, "burst": 1, "key": ["client_ip"] })));
+    }
+
+    #[test]
+    fn ceil_secs_rounds_up() {
+        assert_eq!(ceil_secs(Duration::ZERO), 1);
+        assert_eq!(ceil_secs(Duration::from_millis(1001)), 2);
+        assert_eq!(ceil_secs(Duration::from_secs(3)), 3);
+    }
+}
@@ -1,27 +1,75 @@
+pub mod acl;
+pub mod auth;
 pub mod client;
 pub mod request;
 pub mod response;
 pub mod circuit;
+pub mod concurrency;
+pub mod error;
+pub mod explain;
+pub mod export;
+pub mod jwt;
+pub mod limit;
+pub mod pool;
 
 
-use self::{request::ReboundRequest, circuit::Circuit};
+use std::sync::Arc;
+use arc_swap::ArcSwap;
+
+use self::{acl::IpFilter, request::ReboundRequest, circuit::{Circuit, CircuitNode}, error::ReboundError, limit::RateLimiter};
+
+/// Circuit shared by every engine, swapped when the rules are reloaded
+///
+pub type SharedCircuit = Arc<ArcSwap<Circuit>>;
 
 pub struct ReboundEngine {
 
-    circuit: Circuit
+    circuit: SharedCircuit,
+
+    /// Client addresses allowed or denied on every request, before the lists of the rules
+    ///
+    ip_filter: Option<Arc<IpFilter>>,
+
+    /// Rate limit of every request, before the limits of the rules
+    ///
+    rate_limiter: Option<Arc<RateLimiter>>
 
 }
 
 impl ReboundEngine {
 
-    pub fn new(circuit: Circuit) -> Self {
-        ReboundEngine { circuit }
+    pub fn new(circuit: SharedCircuit, ip_filter: Option<Arc<IpFilter>>, rate_limiter: Option<Arc<RateLimiter>>) -> Self {
+        ReboundEngine { circuit, ip_filter, rate_limiter }
     }
 
-    pub fn get(&mut self, req: impl Into<ReboundRequest>) -> Option<ReboundRequest> {
+    /// Routes the request to its upstream
+    ///
+    /// Returns the pattern of the matched rule along with the upstream request,
+    /// so the rule is known even when the request is refused
+    pub fn get(&self, req: impl Into<ReboundRequest>) -> (Option<String>, Result<ReboundRequest, ReboundError>) {
 
         let req: ReboundRequest = req.into();
-        let cnode = self.circuit.get_node(req.uri.as_str());
-        req.apply(cnode)
+        let circuit = self.circuit.load();
+        let cnode = circuit.get_node(req.uri.as_str());
+        let rule = cnode.rule.as_ref().map(|r| r.pattern.clone());
+        (rule, self.route(req, cnode))
+    }
+
+    fn route(&self, req: ReboundRequest, cnode: &CircuitNode) -> Result<ReboundRequest, ReboundError> {
+
+        if let Some(filter) = &self.ip_filter {
+            filter.check(req.client_addr)?;
+        }
+        if let Some(limiter) = &self.rate_limiter {
+            limiter.check(&req)?;
+        }
+
+        let upstream_req = req.apply(cnode)?;
+
+        // counted once the request is known to be allowed on the rule
+        if let Some(limiter) = &cnode.rate_limiter {
+            limiter.check(&req)?;
+        }
+        Ok(upstream_req)
     }
-}
\ No newline at end of file
+}
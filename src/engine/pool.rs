@@ -0,0 +1,258 @@
+use std::{path::Path, sync::atomic::{AtomicUsize, Ordering}, time::Duration};
+use isahc::{HttpClientBuilder, config::{CaCertificate, ClientCertificate, Configurable, PrivateKey, SslOption}};
+use log::error;
+
+use crate::conf::{ReboundPool, ReboundUpstreamTls};
+
+use super::concurrency::ConcurrencyLimiter;
+
+/// AEAD ciphers only defined from TLS 1.2 on, restricting the cipher list to them
+/// refuses TLS 1.0 and 1.1 handshakes, TLS 1.3 keeps its own cipher suites
+const TLS12_CIPHERS: [&str; 8] = [
+    "ECDHE-ECDSA-AES128-GCM-SHA256",
+    "ECDHE-RSA-AES128-GCM-SHA256",
+    "ECDHE-ECDSA-AES256-GCM-SHA384",
+    "ECDHE-RSA-AES256-GCM-SHA384",
+    "ECDHE-ECDSA-CHACHA20-POLY1305",
+    "ECDHE-RSA-CHACHA20-POLY1305",
+    "DHE-RSA-AES128-GCM-SHA256",
+    "DHE-RSA-AES256-GCM-SHA384",
+];
+
+/// Counters of an upstream connection pool
+///
+#[derive(Default, Debug)]
+pub struct PoolStats {
+
+    /// Requests sent through the pool
+    ///
+    requests: AtomicUsize,
+
+    /// Requests that had to open a new connection
+    ///
+    new_connections: AtomicUsize,
+
+    /// Requests that reused a pooled connection
+    ///
+    reused_connections: AtomicUsize,
+
+    /// Requests currently in flight
+    ///
+    in_flight: AtomicUsize,
+
+    /// Requests that failed
+    ///
+    errors: AtomicUsize,
+
+    /// Requests that failed since the last successful one
+    ///
+    consecutive_errors: AtomicUsize
+
+}
+
+impl PoolStats {
+
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    pub fn new_connections(&self) -> usize {
+        self.new_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn reused_connections(&self) -> usize {
+        self.reused_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    pub fn errors(&self) -> usize {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    pub fn consecutive_errors(&self) -> usize {
+        self.consecutive_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn record_start(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a finished request, with the connect metrics of the response when available
+    ///
+    pub fn record_end(&self, metrics: Option<&isahc::Metrics>, failed: bool) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+
+        if failed {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+            self.consecutive_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        else {
+            self.consecutive_errors.store(0, Ordering::Relaxed);
+        }
+
+        // curl reports no connect time when the connection was reused
+        if let Some(m) = metrics {
+            if m.connect_time().is_zero() {
+                self.reused_connections.fetch_add(1, Ordering::Relaxed);
+            }
+            else {
+                self.new_connections.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Connection pool to a single upstream host
+///
+pub struct UpstreamPool {
+
+    /// Upstream host[:port] the pool connects to
+    ///
+    pub host: String,
+
+    /// Client used for the registered Http methods
+    ///
+    pub client: surf::Client,
+
+    /// Underlying client, used directly for methods surf does not know
+    ///
+    pub http_client: isahc::HttpClient,
+
+    /// Server name sent in SNI and verified instead of the host
+    ///
+    pub sni: Option<String>,
+
+    /// Pool counters
+    ///
+    pub stats: PoolStats,
+
+    /// Limit of requests in flight to the host, when set
+    ///
+    pub limiter: Option<ConcurrencyLimiter>
+
+}
+
+impl UpstreamPool {
+
+    pub fn new(host: String, conf: &ReboundPool, tls: Option<&ReboundUpstreamTls>) -> Result<Self, String> {
+
+        let mut builder = isahc::HttpClient::builder().metrics(true);
+
+        if let Some(max_idle) = conf.max_idle {
+            builder = builder.connection_cache_size(max_idle);
+        }
+        if let Some(idle_timeout) = conf.idle_timeout_ms {
+            builder = builder.connection_cache_ttl(Duration::from_millis(idle_timeout));
+        }
+        if let Some(max_connections) = conf.max_connections_per_host {
+            builder = builder.max_connections_per_host(max_connections);
+        }
+        if conf.tcp_nodelay.unwrap_or(false) {
+            builder = builder.tcp_nodelay();
+        }
+        if let Some(keepalive) = conf.tcp_keepalive_ms {
+            builder = builder.tcp_keepalive(Duration::from_millis(keepalive));
+        }
+        if let Some(timeout) = conf.request_timeout_ms {
+            builder = builder.timeout(Duration::from_millis(timeout));
+        }
+
+        if let Some(tls) = tls {
+            builder = configure_tls(builder, &host, tls)?;
+        }
+
+        let http_client = builder.build().map_err(|e| e.to_string())?;
+        Ok(UpstreamPool {
+            client: surf::Client::with_http_client(http_client::isahc::IsahcClient::from_client(http_client.clone())),
+            http_client,
+            sni: tls.and_then(|tls| tls.sni.clone()),
+            host,
+            stats: PoolStats::default(),
+            limiter: conf.concurrency.as_ref().map(ConcurrencyLimiter::new)
+        })
+    }
+}
+
+/// Checks the upstream TLS configuration without building a client
+///
+pub fn check_tls(tls: &ReboundUpstreamTls) -> Result<(), String> {
+
+    for file in [&tls.ca_bundle, &tls.client_cert, &tls.client_key].into_iter().flatten() {
+        if !Path::new(file).is_file() {
+            return Err(format!("tls file not found: {}", file));
+        }
+    }
+
+    if tls.client_cert.is_some() != tls.client_key.is_some() {
+        return Err(String::from("tls client_cert and client_key must be set together"));
+    }
+
+    // the client can not set protocol versions, only 1.2 can be enforced through the cipher list
+    match tls.min_version.as_deref() {
+        None | Some("1.2") => Ok(()),
+        Some(v) => Err(format!(
+            "unsupported tls min_version: {}, only \"1.2\" can be enforced, the upstream client can not set protocol versions and negotiates TLS 1.3 when the upstream offers it",
+            v
+        )),
+    }
+}
+
+fn configure_tls(mut builder: HttpClientBuilder, host: &str, tls: &ReboundUpstreamTls) -> Result<HttpClientBuilder, String> {
+
+    check_tls(tls)?;
+
+    if let Some(ca_bundle) = &tls.ca_bundle {
+        builder = builder.ssl_ca_certificate(CaCertificate::file(ca_bundle));
+    }
+
+    if let (Some(cert), Some(key)) = (&tls.client_cert, &tls.client_key) {
+        builder = builder.ssl_client_certificate(ClientCertificate::pem_file(cert, PrivateKey::pem_file(key, None)));
+    }
+
+    if tls.min_version.as_deref() == Some("1.2") {
+        builder = builder.ssl_ciphers(TLS12_CIPHERS);
+    }
+
+    if tls.insecure_skip_verify {
+        error!("!!! tls verification DISABLED for upstream {}, insecure_skip_verify must not be used outside dev environments !!!", host);
+        builder = builder.ssl_options(SslOption::DANGER_ACCEPT_INVALID_CERTS | SslOption::DANGER_ACCEPT_INVALID_HOSTS);
+    }
+
+    Ok(builder)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use serde_json::json;
+
+    use super::*;
+
+    fn tls(min_version: Option<&str>) -> ReboundUpstreamTls {
+        serde_json::from_value(json!({ "min_version": min_version })).unwrap()
+    }
+
+    #[test]
+    fn enforces_tls12_only() {
+        assert!(check_tls(&tls(None)).is_ok());
+        assert!(check_tls(&tls(Some("1.2"))).is_ok());
+        for version in ["1.0", "1.1", "1.3", "TLSv1.2"] {
+            let e = check_tls(&tls(Some(version))).unwrap_err();
+            assert!(e.contains(version) && e.contains("only \"1.2\" can be enforced"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn requires_client_cert_and_key_together() {
+        let key = env!("CARGO_MANIFEST_DIR").to_string() + "/Cargo.toml";
+        let tls: ReboundUpstreamTls = serde_json::from_value(json!({ "client_key": key })).unwrap();
+        assert!(check_tls(&tls).unwrap_err().contains("set together"));
+
+        let tls: ReboundUpstreamTls = serde_json::from_value(json!({ "client_key": "/nonexistent/rebound.key" })).unwrap();
+        assert!(check_tls(&tls).unwrap_err().contains("not found"));
+    }
+}
@@ -1,7 +1,11 @@
 use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use isahc::config::{Configurable, Dialer};
 use tiny_http::{Header, Method, Request};
 
 use super::circuit::{CircuitNode, CircuitPath, CircuitType, CircuitUpstream};
+use super::error::ReboundError;
 
 #[derive(serde::Serialize, Clone, Debug)]
 pub enum ReboundRequestType {
@@ -16,11 +20,52 @@ pub enum ReboundRequestType {
     Trace,
     Options,
 
-    // not standard method type
+    // non standard method type, e.g. WebDAV PROPFIND or PURGE
+    Extension(String),
+
+    // no method type
     Invalid
 
 }
 
+impl ReboundRequestType {
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            ReboundRequestType::Get => "GET",
+            ReboundRequestType::Post => "POST",
+            ReboundRequestType::Patch => "PATCH",
+            ReboundRequestType::Put => "PUT",
+            ReboundRequestType::Delete => "DELETE",
+            ReboundRequestType::Head => "HEAD",
+            ReboundRequestType::Connect => "CONNECT",
+            ReboundRequestType::Trace => "TRACE",
+            ReboundRequestType::Options => "OPTIONS",
+            ReboundRequestType::Extension(m) => m.as_str(),
+            ReboundRequestType::Invalid => "",
+        }
+    }
+}
+
+/// Client certificate verified on the Rebound listener
+/// 
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct ReboundClientCert {
+
+    pub subject: String,
+
+    pub san: Vec<String>,
+
+    pub fingerprint: String
+
+}
+
+/// Headers carrying the client certificate upstream, never taken from clients
+/// 
+pub const CLIENT_CERT_SUBJECT_HDR: &str = "X-Client-Cert-Subject";
+pub const CLIENT_CERT_SAN_HDR: &str = "X-Client-Cert-San";
+pub const CLIENT_CERT_FINGERPRINT_HDR: &str = "X-Client-Cert-Fingerprint";
+
 #[derive(serde::Serialize, Clone, Debug)]
 pub struct ReboundRequest {
 
@@ -32,25 +77,129 @@ pub struct ReboundRequest {
 
     pub query_params: HashMap<String, String>,
 
-    pub body: Option<Vec<u8>>
+    pub body: Option<Vec<u8>>,
+
+    pub client_cert: Option<ReboundClientCert>,
+
+    /// Address of the client, behind the TLS listener
+    ///
+    pub client_addr: Option<IpAddr>
 
 }
 
 impl ReboundRequest {
 
 
-    pub fn apply(&self, cnode: &CircuitNode) -> Option<ReboundRequest> {
+    fn check_client_cert(&self, cnode: &CircuitNode) -> Result<(), ReboundError> {
+
+        let rule = cnode.rule.as_ref().unwrap();
+        let needs_cert = rule.require_client_cert
+            || cnode.client_cert_subject.is_some()
+            || cnode.client_cert_san.is_some();
+
+        if !needs_cert {
+            return Ok(());
+        }
+
+        let cert = self.client_cert
+            .as_ref()
+            .ok_or_else(|| ReboundError::Forbidden(String::from("client certificate required")))?;
+
+        if let Some(re) = &cnode.client_cert_subject {
+            if !re.is_match(&cert.subject) {
+                return Err(ReboundError::Forbidden(format!("client certificate subject not allowed: {}", cert.subject)));
+            }
+        }
+
+        if let Some(re) = &cnode.client_cert_san {
+            if !cert.san.iter().any(|san| re.is_match(san)) {
+                return Err(ReboundError::Forbidden(format!("client certificate san not allowed: [{}]", cert.san.join(", "))));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Upstream url including query params
+    /// 
+    pub fn full_url(&self) -> Result<surf::Url, ReboundError> {
+        surf::Url
+            ::parse_with_params(
+                self.uri.as_str(),
+                    self.query_params.iter().map(|(k, v)| -> (String, String) { (k.to_string(), v.to_string()) })
+            )
+            .map_err(|e| ReboundError::InvalidUpstreamRequest(e.to_string()))
+    }
+
+    /// Readdresses the upstream url to another host, keeping its port
+    /// 
+    pub fn set_host(&mut self, host: &str) -> Result<(), ReboundError> {
+        let mut url = surf::Url
+            ::parse(self.uri.as_str())
+            .map_err(|e| ReboundError::InvalidUpstreamRequest(e.to_string()))?;
+        url.set_host(Some(host))
+            .map_err(|e| ReboundError::InvalidUpstreamRequest(e.to_string()))?;
+        self.uri = url.into();
+        Ok(())
+    }
+
+    /// Whether the request method has to bypass surf, which only knows
+    /// the registered Http methods
+    pub fn is_extension_method(&self) -> bool {
+        match &self.method {
+            ReboundRequestType::Extension(m) => surf::http::Method::from_str(m).is_err(),
+            _ => false,
+        }
+    }
+
+    pub fn apply(&self, cnode: &CircuitNode) -> Result<ReboundRequest, ReboundError> {
 
         let ctype = &cnode.circuit_type;
 
         match ctype {
             CircuitType::Routable => {
+                if let Some(filter) = &cnode.ip_filter {
+                    filter.check(self.client_addr)?;
+                }
+
+                let user = cnode.authenticator
+                    .as_ref()
+                    .map(|a| a.authenticate(self))
+                    .transpose()?;
+
+                let claims = cnode.jwt
+                    .as_ref()
+                    .map(|j| j.validate(self))
+                    .transpose()?;
+
+                if let Some(allowed) = &cnode.rule.as_ref().unwrap().allowed_methods {
+                    if !allowed.iter().any(|m| m.eq_ignore_ascii_case(self.method.as_str())) {
+                        return Err(ReboundError::MethodNotAllowed(allowed.clone()));
+                    }
+                }
+
+                self.check_client_cert(cnode)?;
+
                 let mut new_req = self.clone();
 
                 if !cnode.rule.as_ref().unwrap().preserve_hdrs {
                     new_req.headers.clear();
                 }
 
+                new_req.headers.retain(|k, _| {
+                    ![CLIENT_CERT_SUBJECT_HDR, CLIENT_CERT_SAN_HDR, CLIENT_CERT_FINGERPRINT_HDR]
+                        .iter()
+                        .any(|h| h.eq_ignore_ascii_case(k))
+                });
+
+                if cnode.rule.as_ref().unwrap().forward_client_cert {
+                    if let Some(cert) = &self.client_cert {
+                        new_req.headers.insert(String::from(CLIENT_CERT_SUBJECT_HDR), cert.subject.clone());
+                        new_req.headers.insert(String::from(CLIENT_CERT_SAN_HDR), cert.san.join(","));
+                        new_req.headers.insert(String::from(CLIENT_CERT_FINGERPRINT_HDR), cert.fingerprint.clone());
+                    }
+                }
+
                 for (k, v) in &cnode.rule.as_ref().unwrap().additional_hdrs {
                     new_req.headers.insert(k.to_string(), v.to_string());
                 }
@@ -62,6 +211,14 @@ impl ReboundRequest {
                 for (k, v) in &cnode.rule.as_ref().unwrap().additional_query {
                     new_req.query_params.insert(k.to_string(), v.to_string());
                 }
+
+                if let (Some(authenticator), Some(user)) = (&cnode.authenticator, &user) {
+                    authenticator.forward(user, &mut new_req);
+                }
+
+                if let (Some(jwt), Some(claims)) = (&cnode.jwt, &claims) {
+                    jwt.forward(claims, &mut new_req);
+                }
                 
                 let upstream = cnode.rule.as_ref().unwrap().upstream.clone();
                 let upstream_path = CircuitUpstream::from(upstream);
@@ -76,21 +233,62 @@ impl ReboundRequest {
                     new_req.uri = upstream_path.join(&diff_path).into();
                 }
 
-                Some(new_req)
+                Ok(new_req)
             },
             
-            CircuitType::Error => None,
+            CircuitType::Error => Err(ReboundError::NoRoute),
         }
 
         
     }
 }
 
-impl Into<surf::Request> for ReboundRequest {
+impl TryFrom<ReboundRequest> for isahc::http::Request<Vec<u8>> {
+
+    type Error = ReboundError;
+
+    fn try_from(req: ReboundRequest) -> Result<Self, Self::Error> {
+        req.into_isahc(None)
+    }
+}
+
+impl ReboundRequest {
+
+    /// Upstream request for isahc, dialed to the given address instead of the url host when set
+    ///
+    pub fn into_isahc(self, dial: Option<SocketAddr>) -> Result<isahc::http::Request<Vec<u8>>, ReboundError> {
+
+        let method = isahc::http::Method
+            ::from_bytes(self.method.as_str().as_bytes())
+            .map_err(|_| ReboundError::UnsupportedMethod(self.method.as_str().to_string()))?;
 
-    fn into(self) -> surf::Request {
+        let full_url = self.full_url()?;
 
-        let method = match self.method {
+        let mut builder = isahc::http::Request
+            ::builder()
+            .method(method)
+            .uri(full_url.as_str());
+        if let Some(addr) = dial {
+            builder = builder.dial(Dialer::ip_socket(addr));
+        }
+
+        for (k, v) in self.headers.iter() {
+            builder = builder.header(k.as_str(), v.as_str());
+        }
+
+        builder
+            .body(self.body.unwrap_or_default())
+            .map_err(|e| ReboundError::InvalidUpstreamRequest(e.to_string()))
+    }
+}
+
+impl TryFrom<ReboundRequest> for surf::Request {
+
+    type Error = ReboundError;
+
+    fn try_from(req: ReboundRequest) -> Result<Self, Self::Error> {
+
+        let method = match req.method {
             ReboundRequestType::Get => surf::http::Method::Get,
             ReboundRequestType::Post => surf::http::Method::Post,
             ReboundRequestType::Patch => surf::http::Method::Patch,
@@ -100,29 +298,26 @@ impl Into<surf::Request> for ReboundRequest {
             ReboundRequestType::Connect => surf::http::Method::Connect,
             ReboundRequestType::Trace => surf::http::Method::Trace,
             ReboundRequestType::Options => surf::http::Method::Options,
-            ReboundRequestType::Invalid => panic!(),
+            ReboundRequestType::Extension(ref m) => surf::http::Method
+                ::from_str(m)
+                .map_err(|_| ReboundError::UnsupportedMethod(m.clone()))?,
+            ReboundRequestType::Invalid => return Err(ReboundError::UnsupportedMethod(String::default())),
         };
 
-        let full_url = 
-        surf::Url
-            ::parse_with_params(
-                self.uri.as_str(),
-                    self.query_params.iter().map(|(k, v)| -> (String, String) { (k.to_string(), v.to_string()) })
-            )
-            .unwrap();
+        let full_url = req.full_url()?;
 
         let mut upstream_req = surf::Request
             ::builder(method, full_url)
-            .body(self.body.unwrap_or_default())
+            .body(req.body.unwrap_or_default())
             .build();
 
         upstream_req.remove_header(surf::http::headers::CONTENT_TYPE);
 
-        self.headers.iter().for_each(|(k, v)| {
+        req.headers.iter().for_each(|(k, v)| {
             upstream_req.set_header(k.as_str(), v.as_str());
         });
 
-        upstream_req
+        Ok(upstream_req)
     }
 }
 
@@ -198,7 +393,9 @@ impl ReboundIngressRequestBuilder {
             headers: self.build_hdrs(),
             query_params: self.build_query_params(), 
             method: self.build_method(),
-            body: self.body.clone()
+            body: self.body.clone(),
+            client_cert: None,
+            client_addr: None
         }
 
     }
@@ -228,7 +425,7 @@ impl ReboundIngressRequestBuilder {
                 Method::Options => ReboundRequestType::Options,
                 Method::Trace => ReboundRequestType::Trace,
                 Method::Patch => ReboundRequestType::Patch,
-                Method::NonStandard(_) => ReboundRequestType::Invalid,
+                Method::NonStandard(m) => ReboundRequestType::Extension(m.to_string()),
             }
         }
 
@@ -264,8 +461,11 @@ impl ReboundIngressRequestBuilder {
                     let all_params = &url[index+1..url.len()];
                     for query in all_params.split("&") {
 
-                        if let Some((k, v)) = query.split_once("=") {
-                            params.insert(String::from(k), String::from(v));
+                        // decoded, full_url encodes them again
+                        if query.contains('=') {
+                            if let Some((k, v)) = form_urlencoded::parse(query.as_bytes()).next() {
+                                params.insert(k.into_owned(), v.into_owned());
+                            }
                         }
                     }
                 }
@@ -276,3 +476,105 @@ impl ReboundIngressRequestBuilder {
         params
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use serde_json::json;
+    use tiny_http::Method;
+
+    use crate::engine::circuit::{Circuit, CircuitBuilder};
+
+    use super::*;
+
+    fn circuit(rules: serde_json::Value) -> Circuit {
+        CircuitBuilder::new(serde_json::from_value(rules).unwrap()).build().unwrap()
+    }
+
+    fn request(method: &str, url: &str) -> ReboundRequest {
+        ReboundIngressRequestBuilder::new()
+            .with_url(url.to_string())
+            .with_headers(&[Header::from_bytes("X-Client", "yes").unwrap()])
+            .with_method(&Method::from_str(method).unwrap())
+            .build()
+    }
+
+    #[test]
+    fn non_standard_methods_are_extensions() {
+        assert!(matches!(request("GET", "/").method, ReboundRequestType::Get));
+        assert!(matches!(request("PROPFIND", "/").method, ReboundRequestType::Extension(ref m) if m == "PROPFIND"));
+        assert!(matches!(request("PURGE", "/").method, ReboundRequestType::Extension(ref m) if m == "PURGE"));
+        assert!(matches!(ReboundIngressRequestBuilder::new().build().method, ReboundRequestType::Invalid));
+
+        // surf knows the WebDAV methods, only the others bypass it
+        assert!(!request("PROPFIND", "/").is_extension_method());
+        assert!(request("PURGE", "/").is_extension_method());
+        assert!(!request("GET", "/").is_extension_method());
+    }
+
+    #[test]
+    fn extension_methods_pass_through_surf_and_isahc() {
+        let mut req = request("PROPFIND", "/dav?depth=1");
+        req.uri = String::from("http://upstream/dav");
+        let upstream = surf::Request::try_from(req).unwrap();
+        assert_eq!(upstream.method().to_string(), "PROPFIND");
+
+        let mut req = request("PURGE", "/cache?key=a%20b");
+        req.uri = String::from("http://upstream/cache");
+        req.body = Some(b"body".to_vec());
+        let upstream = isahc::http::Request::<Vec<u8>>::try_from(req.clone()).unwrap();
+        assert_eq!(upstream.method().as_str(), "PURGE");
+        assert_eq!(upstream.uri().to_string(), "http://upstream/cache?key=a+b");
+        assert_eq!(upstream.headers().get("x-client").map(|v| v.to_str().unwrap()), Some("yes"));
+        assert_eq!(upstream.body(), b"body");
+
+        assert!(matches!(surf::Request::try_from(req), Err(ReboundError::UnsupportedMethod(m)) if m == "PURGE"));
+    }
+
+    #[test]
+    fn invalid_methods_are_refused() {
+        let mut req = request("GET", "/");
+        req.uri = String::from("http://upstream/");
+
+        req.method = ReboundRequestType::Invalid;
+        assert!(matches!(surf::Request::try_from(req.clone()), Err(ReboundError::UnsupportedMethod(_))));
+        req.method = ReboundRequestType::Extension(String::from("BAD METHOD"));
+        assert!(matches!(isahc::http::Request::<Vec<u8>>::try_from(req), Err(ReboundError::UnsupportedMethod(_))));
+    }
+
+    #[test]
+    fn allowed_methods() {
+        let circuit = circuit(json!([{
+            "pattern": "/dav/",
+            "upstream": "http://upstream/files/",
+            "allowed_methods": ["get", "PROPFIND"]
+        }]));
+        let node = circuit.get_node("/dav/a/b");
+
+        let routed = request("PROPFIND", "/dav/a/b?depth=1").apply(node).unwrap();
+        assert!(matches!(routed.method, ReboundRequestType::Extension(ref m) if m == "PROPFIND"));
+        assert_eq!(routed.full_url().unwrap().as_str(), "http://upstream/files/a/b?depth=1");
+        assert!(request("GET", "/dav/a").apply(node).is_ok());
+
+        let err = request("DELETE", "/dav/a").apply(node).unwrap_err();
+        assert_eq!(err.status_code(), 405);
+        assert_eq!(err.headers(), vec![(String::from("Allow"), String::from("get, PROPFIND"))]);
+        assert!(matches!(request("PURGE", "/dav/a").apply(node), Err(ReboundError::MethodNotAllowed(_))));
+    }
+
+    #[test]
+    fn every_method_allowed_by_default() {
+        let circuit = circuit(json!([{ "pattern": "/", "upstream": "http://upstream/" }]));
+        let node = circuit.get_node("/any");
+
+        for method in ["GET", "POST", "DELETE", "PROPFIND", "PURGE"] {
+            assert!(request(method, "/any").apply(node).is_ok(), "{} refused", method);
+        }
+    }
+
+    #[test]
+    fn unrouted_requests() {
+        let circuit = circuit(json!([{ "pattern": "/api/", "upstream": "http://upstream/", "allowed_methods": ["GET"] }]));
+        assert!(matches!(request("DELETE", "/other").apply(circuit.get_node("/other")), Err(ReboundError::NoRoute)));
+    }
+}
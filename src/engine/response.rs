@@ -9,35 +9,37 @@ pub struct ReboundResponse {
     
     pub headers: HashMap<String, String>,
 
-    pub body: Box<Vec<u8>>
+    pub body: Vec<u8>
 
 }
 
 impl ReboundResponse {
-        pub async fn from(mut res: surf::Response) -> Self {
+        /// Reads the response of surf, failing when its body can not be read
+        ///
+        pub async fn from(mut res: surf::Response) -> Result<Self, surf::Error> {
 
         let sc: u16 = res.status().into();
         let hdrs_vec: Vec<(String, String)> = res.header_names().map(|h| (String::from(h.as_str()), String::from(res.header(h).expect("failed to get header").as_str()))).collect();
-        ReboundResponse {
+        Ok(ReboundResponse {
             status: sc,
             headers: hdrs_vec.into_iter().collect(),
-            body: Box::from(res.body_bytes().await.unwrap_or_default())
-        }
+            body: res.body_bytes().await?
+        })
     }
 }
 
-impl Into<Response<Cursor<Vec<u8>>>> for ReboundResponse {
-    fn into(self) -> Response<Cursor<Vec<u8>>> {
-        let rebound_res_body_size = self.body.len();
+impl From<ReboundResponse> for Response<Cursor<Vec<u8>>> {
+    fn from(res: ReboundResponse) -> Self {
+        let rebound_res_body_size = res.body.len();
         Response::new(
-            self.status.into(),
-            self.headers
+            res.status.into(),
+            res.headers
                 .iter()
                 .map(|(k, v)| { 
                     Header::from_str(format!("{}:{}", k.as_str(), v.as_str()).as_str()).unwrap()
                 })
                 .collect::<Vec<Header>>(),
-            Cursor::new(*self.body), 
+            Cursor::new(res.body), 
             Some(rebound_res_body_size),
             None
         )
@@ -0,0 +1,123 @@
+use log::LevelFilter;
+use log4rs::Config;
+use log4rs::append::console::ConsoleAppender;
+use log4rs::append::rolling_file::RollingFileAppender;
+use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
+use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
+use log4rs::append::rolling_file::policy::compound::trigger::size::SizeTrigger;
+use log4rs::config::{Appender, Logger, Root};
+use log4rs::encode::Encode;
+use log4rs::encode::json::JsonEncoder;
+use log4rs::encode::pattern::PatternEncoder;
+
+use crate::conf::{ReboundLogFormat, ReboundLogTarget, ReboundLogging};
+use crate::node::access::ACCESS_LOG_TARGET;
+
+/// Pattern of console lines
+///
+const STDOUT_PATTERN: &str = "{d(%+)(utc)} [{f}:{L}] {h({l})} {M}:{m}{n}";
+
+/// Pattern of file lines
+///
+const FILE_PATTERN: &str = "{d} {l}::{m}{n}";
+
+/// Pattern of access log lines, already formatted
+///
+const ACCESS_PATTERN: &str = "{m}{n}";
+
+/// Sets up the global logger from the logging conf
+///
+/// The log dir given on the command line wins over the one in the conf
+pub fn setup(conf: &ReboundLogging, log_dir: Option<&str>) -> Result<(), String> {
+
+    if let Some(file) = &conf.log4rs_file {
+        return log4rs::init_file(file, Default::default()).map_err(|e| format!("invalid log4rs file {}: {}", file, e));
+    }
+
+    let log_dir = log_dir.or(conf.dir.as_deref());
+    let targets = conf.targets.clone().unwrap_or_else(|| {
+        let mut targets = vec![ReboundLogTarget::Stdout];
+        if log_dir.is_some() {
+            targets.push(ReboundLogTarget::File);
+        }
+        targets
+    });
+
+    let mut config = Config::builder();
+    let mut root = Root::builder();
+
+    if targets.contains(&ReboundLogTarget::Stdout) {
+        let stdout = ConsoleAppender::builder()
+            .encoder(encoder(conf, STDOUT_PATTERN))
+            .build();
+
+        config = config.appender(Appender::builder().build("stdout", Box::new(stdout)));
+        root = root.appender("stdout");
+    }
+
+    if targets.contains(&ReboundLogTarget::File) {
+        let log_dir = log_dir.ok_or_else(|| String::from("logging to file needs a log dir, set logging.dir or --log-dir"))?;
+
+        let compound_policy = CompoundPolicy::new
+        (
+            Box::new(SizeTrigger::new(conf.max_size)),
+            Box::new(
+                FixedWindowRoller::builder()
+                    .build(format!("{}/rebound.{{}}.log", log_dir).as_str(), conf.max_files)
+                    .map_err(|e| e.to_string())?
+            )
+        );
+
+        let file_appender = RollingFileAppender::builder()
+            .encoder(encoder(conf, FILE_PATTERN))
+            .build(format!("{}/rebound.log", log_dir), Box::new(compound_policy))
+            .map_err(|e| format!("failed to open log file in {}: {}", log_dir, e))?;
+
+        config = config.appender(Appender::builder().build("file_appender", Box::new(file_appender)));
+        root = root.appender("file_appender");
+    }
+
+    for (module, level) in conf.modules.iter() {
+        config = config.logger(Logger::builder().build(module, *level));
+    }
+
+    // access lines are logged whatever the level, to their own file when given one
+    if let Some(access) = &conf.access {
+        let logger = match &access.file {
+            Some(file) => {
+                let compound_policy = CompoundPolicy::new
+                (
+                    Box::new(SizeTrigger::new(conf.max_size)),
+                    Box::new(
+                        FixedWindowRoller::builder()
+                            .build(format!("{}.{{}}", file).as_str(), conf.max_files)
+                            .map_err(|e| e.to_string())?
+                    )
+                );
+
+                let access_appender = RollingFileAppender::builder()
+                    .encoder(Box::new(PatternEncoder::new(ACCESS_PATTERN)))
+                    .build(file, Box::new(compound_policy))
+                    .map_err(|e| format!("failed to open access log file {}: {}", file, e))?;
+
+                config = config.appender(Appender::builder().build("access_appender", Box::new(access_appender)));
+                Logger::builder().appender("access_appender").additive(false)
+            },
+            None => Logger::builder(),
+        };
+        config = config.logger(logger.build(ACCESS_LOG_TARGET, LevelFilter::Info));
+    }
+
+    let config = config
+        .build(root.build(conf.level))
+        .map_err(|e| e.to_string())?;
+
+    log4rs::init_config(config).map(|_| ()).map_err(|e| e.to_string())
+}
+
+fn encoder(conf: &ReboundLogging, default_pattern: &str) -> Box<dyn Encode> {
+    match conf.format {
+        ReboundLogFormat::Json => Box::new(JsonEncoder::new()),
+        ReboundLogFormat::Pattern => Box::new(PatternEncoder::new(conf.pattern.as_deref().unwrap_or(default_pattern))),
+    }
+}
@@ -1,96 +1,213 @@
+mod cli;
 mod conf;
+mod logging;
 mod node;
 mod engine;
 
-use log::LevelFilter;
 use log::debug;
 use log::info;
 use log::error;
-use log4rs::Config;
-use log4rs::append::console::ConsoleAppender;
-use log4rs::append::rolling_file::RollingFileAppender;
-use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
-use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
-use log4rs::append::rolling_file::policy::compound::trigger::size::SizeTrigger;
-use log4rs::config::Appender;
-use log4rs::config::Root;
-use log4rs::encode::pattern::PatternEncoder;
-use log4rs::filter::threshold::ThresholdFilter;
+use log::warn;
 
+use clap::Parser;
+
+use cli::{CheckArgs, Cli, Command, ExplainArgs, RoutesCommand, ServeArgs};
 use node::master::MasterNode;
-use engine::circuit;
+use engine::{circuit, explain, export::CircuitGraph};
 
 fn main() {
 
-    setup_logger();
-
-    let conf_file = match
-    std::env::var(conf::REBOUND_CONF_FILE) 
-    {
-        Ok(f) => f,
-        Err(_e) => {
-            
-            error!("rebound file not specified in env");
-            std::process::exit(-1)
+    let cli = Cli::parse();
+    let code = match cli.command.unwrap_or(Command::Serve(cli.serve)) {
+        Command::Serve(args) => serve(args),
+        Command::Check(args) => check(&args),
+        Command::Routes(args) => match args.command {
+            Some(RoutesCommand::Explain(args)) => explain(&args),
+            None => routes(&args.conf.config, &args.format),
+        },
+        Command::Version => {
+            println!("rebound {}", env!("CARGO_PKG_VERSION"));
+            0
         },
     };
 
+    std::process::exit(code);
+}
+
+/// Runs the server until shutdown
+///
+/// Returns the exit code, 1 when it fails to start or requests had to be dropped on shutdown
+fn serve(args: ServeArgs) -> i32 {
+
+    // read by workers whenever they serve an error
+    if let Some(error_file) = &args.error_file {
+        std::env::set_var(conf::REBOUND_DEFAULT_ERROR_FILE, error_file);
+    }
+
+    // the conf is needed to set up logging, its issues are logged right after
+    let conf_file = args.conf.config.clone();
+    let report = conf::check::check_with(&conf_file, |conf| args.overrides.apply(conf));
+    if let Some(conf) = &report.conf {
+        node::task::configure_runtime(conf);
+    }
+    let logging_conf = report.conf.as_ref().map(|c| c.logging.clone()).unwrap_or_default();
+    if let Err(e) = logging::setup(&logging_conf, args.log_dir.as_deref()) {
+        eprintln!("failed to set up logging: {}", e);
+        return 1;
+    }
+
     info!("reading conf: {}", conf_file);
-    let conf = conf::parser::parse(conf_file);
+    for issue in report.issues.iter() {
+        match issue.severity {
+            conf::check::Severity::Error => error!("{}", issue),
+            conf::check::Severity::Warning => warn!("{}", issue),
+        }
+    }
+    let conf = match report.conf {
+        Some(conf) if report.errors() == 0 => conf,
+        _ => {
+            error!("invalid conf, run `rebound check --config {}` with the same flags for details", conf_file);
+            return 1;
+        },
+    };
     info!("conf: {:?}", conf);
     
     info!("building circuit...");
-    let circuit = circuit::CircuitBuilder
-        ::new(conf.rules.clone().unwrap_or_default())
-        .build();
+    let circuit = match circuit::CircuitBuilder::new(conf.rules.clone().unwrap_or_default()).build() {
+        Ok(circuit) => circuit,
+        Err(e) => {
+            error!("failed to build circuit: {}", e);
+            return 1;
+        },
+    };
 
     debug!("circuit: {:?}", circuit);
 
-    MasterNode::from(conf, circuit)
-    .unwrap()
-    .run();
-}
+    let master = match MasterNode::from(conf_file, conf, circuit) {
+        Ok(master) => master,
+        Err(e) => {
+            error!("failed to start master: {}", e);
+            return 1;
+        },
+    };
 
-fn setup_logger() {
-    
-    let stdout = ConsoleAppender::builder()
-        .encoder(Box::new(PatternEncoder::new("{d(%+)(utc)} [{f}:{L}] {h({l})} {M}:{m}{n}")))
-        .build();
-    
-    let compound_policy = CompoundPolicy::new
-    (
-        Box::new(SizeTrigger::new(5*1024*1024)), // 5MB as max log file size to roll
-        Box::new(FixedWindowRoller::builder().build(format!("{}/rebound.{{}}.log", std::env::var(conf::REBOUND_LOG_DIR).unwrap()).as_str(), 3).unwrap())
-    );
+    if !master.run() {
+        error!("drain timeout reached, dropping remaining requests");
+        return 1;
+    }
+    info!("rebound stopped");
+    0
+}
 
-    let file_appender = RollingFileAppender::builder()
-        .encoder(Box::new(PatternEncoder::new("{d} {l}::{m}{n}")))
-        .build(
-            format!("{}/rebound.log", std::env::var(conf::REBOUND_LOG_DIR).unwrap()),Box::new(compound_policy))
-        .unwrap();
+/// Checks the conf as overridden by the flags, printing every issue
+///
+/// Returns the exit code, 1 when the conf has errors
+fn check(args: &CheckArgs) -> i32 {
+
+    let conf_file = &args.conf.config;
+    let report = conf::check::check_with(conf_file, |conf| args.overrides.apply(conf));
+    for issue in report.issues.iter() {
+        println!("{}", issue);
+    }
+
+    if report.errors() > 0 {
+        println!("{}: {} errors, {} warnings", conf_file, report.errors(), report.warnings());
+        1
+    }
+    else {
+        println!("{}: ok, {} warnings", conf_file, report.warnings());
+        0
+    }
+}
 
-     let config = Config::builder()
-        .appender(
+/// Prints the routes of the circuit, as a tree with nested rules indented under the rule they refine,
+/// or as a DOT or JSON graph
+///
+/// Returns the exit code, 1 when the conf has errors
+fn routes(conf_file: &str, format: &str) -> i32 {
 
-            Appender::builder().build("stdout", Box::new(stdout))
+    let circuit = match load_circuit(conf_file) {
+        Some(circuit) => circuit,
+        None => return 1,
+    };
 
-        )
-        .appender(
+    match format {
+        "dot" => {
+            print!("{}", CircuitGraph::from(&circuit).to_dot());
+            return 0;
+        },
+        "json" => {
+            println!("{}", serde_json::to_string_pretty(&CircuitGraph::from(&circuit)).unwrap_or_default());
+            return 0;
+        },
+        _ => {},
+    }
+
+    let mut stack: Vec<(usize, usize)> = circuit.links
+        .iter()
+        .rev()
+        .filter(|l| l.from == circuit.head_index)
+        .map(|l| (l.to, 0))
+        .collect();
+
+    while let Some((ptr, depth)) = stack.pop() {
+        if let Some(rule) = &circuit.nodes[ptr].rule {
+            let methods = rule.allowed_methods
+                .as_ref()
+                .map(|m| format!(" [{}]", m.join(", ")))
+                .unwrap_or_default();
+            println!("{}{} -> {}{}", "  ".repeat(depth), rule.pattern, rule.upstream, methods);
+        }
+        stack.extend(circuit.links.iter().rev().filter(|l| l.from == ptr).map(|l| (l.to, depth + 1)));
+    }
+
+    0
+}
 
-            Appender::builder()
-            .filter(Box::new(ThresholdFilter::new(LevelFilter::Info)))
-            .build("file_appender", Box::new(file_appender)))
+/// Explains how a request would be routed by the rules of the conf, without sending it
+///
+/// Returns the exit code, 1 when the conf has errors or the request is invalid
+fn explain(args: &ExplainArgs) -> i32 {
 
-        .build(
+    let circuit = match load_circuit(&args.conf.config) {
+        Some(circuit) => circuit,
+        None => return 1,
+    };
 
-            Root::builder()
-                    .appender("stdout")
-                    .appender("file_appender")
-                    .build(LevelFilter::Info)
+    let req = match explain::explain_request(&args.method, &args.url, &args.headers) {
+        Ok(req) => req,
+        Err(e) => {
+            eprintln!("invalid request: {}", e);
+            return 1;
+        },
+    };
 
-        )
-        .unwrap();
+    let explanation = explain::explain(&circuit, &args.method, &args.url, &req);
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&explanation).unwrap_or_default());
+    }
+    else {
+        print!("{}", explanation);
+    }
+    0
+}
 
-    log4rs::init_config(config).unwrap();
-    
-}
\ No newline at end of file
+/// Circuit built from the rules of a valid conf, none after printing why the conf is invalid
+///
+fn load_circuit(conf_file: &str) -> Option<circuit::Circuit> {
+
+    let report = conf::check::check(conf_file);
+    match report.conf {
+        Some(conf) if report.errors() == 0 => match circuit::CircuitBuilder::new(conf.rules.unwrap_or_default()).build() {
+            Ok(circuit) => Some(circuit),
+            Err(e) => {
+                eprintln!("{}: {}", conf_file, e);
+                None
+            },
+        },
+        _ => {
+            eprintln!("{}: invalid conf, run `rebound check --config {}` for details", conf_file, conf_file);
+            None
+        },
+    }
+}
@@ -0,0 +1,287 @@
+use std::{io, net::IpAddr, sync::atomic::{AtomicU64, Ordering}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use tiny_http::{Header, Request, ResponseBox};
+
+use crate::{conf::ReboundAccessLogFormat, engine::request::ReboundRequest};
+
+use super::trace::{is_trace_header, RequestTrace};
+
+/// Log target of access log lines, so they can be routed to their own file
+///
+pub const ACCESS_LOG_TARGET: &str = "rebound::access";
+
+/// Header carrying the request ID, taken from the client when sent, forwarded upstream and returned
+///
+pub const REQUEST_ID_HDR: &str = "X-Request-Id";
+
+/// Max length of a request ID taken from the client
+///
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Requests seen by this process, making generated IDs unique
+///
+static REQUEST_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Access log entry of a single request
+///
+#[derive(Debug, Clone)]
+pub struct AccessRecord {
+
+    pub request_id: String,
+
+    /// Time the request was received
+    ///
+    pub time: DateTime<Utc>,
+
+    /// Instant the request was received, for the total latency
+    ///
+    pub received: Instant,
+
+    /// Address of the client, forwarded by trusted proxies
+    ///
+    pub client: IpAddr,
+
+    pub method: String,
+
+    pub path: String,
+
+    pub protocol: String,
+
+    pub referer: Option<String>,
+
+    pub user_agent: Option<String>,
+
+    /// Pattern of the rule the request was routed by
+    ///
+    pub rule: Option<String>,
+
+    /// Url the request was sent to
+    ///
+    pub upstream: Option<String>,
+
+    pub status: u16,
+
+    /// Kind of the error the request failed with
+    ///
+    pub error: Option<&'static str>,
+
+    /// Size of the request body
+    ///
+    pub bytes_in: usize,
+
+    /// Size of the response body
+    ///
+    pub bytes_out: Option<usize>,
+
+    /// Time the request was sent upstream
+    ///
+    pub upstream_started: Option<DateTime<Utc>>,
+
+    /// Time spent waiting on the upstream
+    ///
+    pub upstream_latency: Option<Duration>,
+
+    /// Time from receiving the request to sending the response
+    ///
+    pub total_latency: Duration,
+
+    /// Trace of the request, when tracing is enabled
+    ///
+    pub trace: Option<RequestTrace>
+
+}
+
+impl AccessRecord {
+
+    /// Starts the record of a request received at the given instant from the given client
+    ///
+    pub fn new(req: &Request, client: IpAddr, received: Instant) -> Self {
+
+        let header = |name: &str| req.headers()
+            .iter()
+            .find(|h| h.field.as_str().as_str().eq_ignore_ascii_case(name))
+            .map(|h| h.value.to_string());
+
+        let elapsed = received.elapsed();
+        AccessRecord {
+            request_id: header(REQUEST_ID_HDR).filter(|id| is_valid_request_id(id)).unwrap_or_else(generate_request_id),
+            time: DateTime::<Utc>::from(SystemTime::now() - elapsed),
+            received,
+            client,
+            method: req.method().to_string(),
+            path: req.url().to_string(),
+            protocol: format!("HTTP/{}", req.http_version()),
+            referer: header("Referer"),
+            user_agent: header("User-Agent"),
+            rule: None,
+            upstream: None,
+            status: 0,
+            error: None,
+            bytes_in: req.body_length().unwrap_or_default(),
+            bytes_out: None,
+            upstream_started: None,
+            upstream_latency: None,
+            total_latency: Duration::ZERO,
+            trace: None
+        }
+    }
+
+    /// Records the upstream a request was routed to, passing the request ID and trace context upstream
+    ///
+    pub fn route(&mut self, req: &mut ReboundRequest) {
+        self.upstream = req.full_url().ok().map(|u| u.as_str().trim_end_matches('?').to_string());
+        req.headers.insert(String::from(REQUEST_ID_HDR), self.request_id.clone());
+
+        if let Some(trace) = &self.trace {
+            req.headers.retain(|k, _| !is_trace_header(k));
+            req.headers.extend(trace.headers());
+        }
+    }
+
+    /// Sends the response with the request ID, recording its status and size
+    ///
+    pub fn respond(&mut self, req: Request, mut res: ResponseBox) -> io::Result<()> {
+
+        let echoed = res.headers().iter().any(|h| h.field.equiv(REQUEST_ID_HDR));
+        if let (false, Ok(hdr)) = (echoed, Header::from_bytes(REQUEST_ID_HDR.as_bytes(), self.request_id.as_bytes())) {
+            res.add_header(hdr);
+        }
+
+        self.status = res.status_code().0;
+        self.bytes_out = res.data_length();
+        let r = req.respond(res);
+        self.total_latency = self.received.elapsed();
+        r
+    }
+
+    /// Access log line in the given format
+    ///
+    pub fn format(&self, format: &ReboundAccessLogFormat) -> String {
+        match format {
+            ReboundAccessLogFormat::Combined => self.combined(),
+            ReboundAccessLogFormat::Json => self.json(),
+        }
+    }
+
+    fn combined(&self) -> String {
+        format!(
+            "{} - - [{}] \"{} {} {}\" {} {} \"{}\" \"{}\" rule=\"{}\" upstream=\"{}\" bytes_in={} upstream_ms={} total_ms={:.3} request_id={}",
+            self.client,
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            self.method,
+            escape(&self.path),
+            self.protocol,
+            self.status,
+            self.bytes_out.map(|b| b.to_string()).unwrap_or_else(|| String::from("-")),
+            escape(self.referer.as_deref().unwrap_or("-")),
+            escape(self.user_agent.as_deref().unwrap_or("-")),
+            escape(self.rule.as_deref().unwrap_or("-")),
+            escape(self.upstream.as_deref().unwrap_or("-")),
+            self.bytes_in,
+            self.upstream_latency.map(|l| format!("{:.3}", millis(l))).unwrap_or_else(|| String::from("-")),
+            millis(self.total_latency),
+            self.request_id
+        )
+    }
+
+    fn json(&self) -> String {
+        json!({
+            "time": self.time.to_rfc3339(),
+            "request_id": self.request_id,
+            "client_ip": self.client.to_string(),
+            "method": self.method,
+            "path": self.path,
+            "protocol": self.protocol,
+            "referer": self.referer,
+            "user_agent": self.user_agent,
+            "rule": self.rule,
+            "upstream": self.upstream,
+            "status": self.status,
+            "bytes_in": self.bytes_in,
+            "bytes_out": self.bytes_out,
+            "upstream_latency_ms": self.upstream_latency.map(millis),
+            "total_latency_ms": millis(self.total_latency)
+        })
+        .to_string()
+    }
+}
+
+/// Whether a request ID sent by the client can be kept, others are replaced
+/// so they can not forge log lines or upstream headers
+///
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.:+/=@".contains(&b))
+}
+
+fn generate_request_id() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or_default();
+    format!("{:x}-{:x}", nanos, REQUEST_COUNT.fetch_add(1, Ordering::Relaxed))
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+
+    use tiny_http::TestRequest;
+
+    use super::*;
+
+    fn record(request_id: Option<&str>) -> AccessRecord {
+        let mut req = TestRequest::new().with_path("/a?b=1");
+        if let Some(id) = request_id {
+            req = req.with_header(Header::from_bytes(REQUEST_ID_HDR, id).unwrap());
+        }
+        AccessRecord::new(&req.into(), IpAddr::from([10, 0, 0, 7]), Instant::now())
+    }
+
+    #[test]
+    fn keeps_client_request_ids() {
+        for id in ["0b2c9b0e-5f7e-4c3a-9d1e-1a2b3c4d5e6f", "abc_123.x:y", "dGVzdA==", "a".repeat(MAX_REQUEST_ID_LEN).as_str()] {
+            assert_eq!(record(Some(id)).request_id, id);
+        }
+    }
+
+    #[test]
+    fn replaces_unsafe_request_ids() {
+        let forged = "x total_ms=0 request_id=y";
+        let too_long = "a".repeat(MAX_REQUEST_ID_LEN + 1);
+        for id in [forged, "\"quoted\"", "tab\there", too_long.as_str()] {
+            let record = record(Some(id));
+            assert_ne!(record.request_id, id);
+            assert!(is_valid_request_id(&record.request_id));
+        }
+        assert!(is_valid_request_id(&record(None).request_id));
+        assert_ne!(record(None).request_id, record(None).request_id);
+    }
+
+    #[test]
+    fn combined_line() {
+        let mut record = record(Some("req-1"));
+        record.rule = Some(String::from("/a"));
+        record.upstream = Some(String::from("http://up/a?b=1"));
+        record.status = 200;
+        record.bytes_in = 12;
+        record.bytes_out = Some(34);
+        record.upstream_latency = Some(Duration::from_millis(2));
+        record.total_latency = Duration::from_millis(3);
+
+        let line = record.format(&ReboundAccessLogFormat::Combined);
+        assert!(line.starts_with("10.0.0.7 - - ["), "{}", line);
+        assert!(line.ends_with(
+            "\"GET /a?b=1 HTTP/1.1\" 200 34 \"-\" \"-\" rule=\"/a\" upstream=\"http://up/a?b=1\" bytes_in=12 upstream_ms=2.000 total_ms=3.000 request_id=req-1"
+        ), "{}", line);
+
+        let json: serde_json::Value = serde_json::from_str(&record.format(&ReboundAccessLogFormat::Json)).unwrap();
+        assert_eq!((json["bytes_in"].as_u64(), json["bytes_out"].as_u64()), (Some(12), Some(34)));
+    }
+}
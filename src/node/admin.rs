@@ -0,0 +1,238 @@
+use std::{io, thread::{self, JoinHandle}};
+use log::{error, info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::{conf::{ReboundAdmin, ReboundMode}, engine::{explain, export::CircuitGraph}};
+
+use super::context::NodeContext;
+
+/// Reloads the rules, as done on conf changes
+///
+pub type ReloadAction = Box<dyn Fn() -> Result<(), String> + Send>;
+
+/// Request to explain, as posted to /explain
+///
+#[derive(Deserialize, Debug)]
+struct ExplainBody {
+
+    method: String,
+
+    url: String,
+
+    /// Headers as `Name: value`
+    ///
+    #[serde(default)]
+    headers: Vec<String>
+
+}
+
+/// Body of an admin response
+///
+enum AdminBody {
+    Json(Value),
+    Dot(String)
+}
+
+impl From<Value> for AdminBody {
+    fn from(value: Value) -> Self {
+        AdminBody::Json(value)
+    }
+}
+
+/// Listener of the admin API, inspecting and controlling the running server
+///
+/// Answers JSON on:
+/// - GET /rules, the rules of the live circuit
+/// - GET /circuit, the graph of the live circuit, as DOT with ?format=dot
+/// - GET /upstreams, the upstream pools with their counters
+/// - POST /upstreams/{host[:port]}/drain and /enable
+/// - POST /reload, reloading the rules from the conf file
+/// - GET /workers, the state of the workers
+/// - POST /explain, how a request would be routed, without sending it
+pub struct AdminServer {
+
+    server: Server,
+
+    /// Expected Authorization header
+    ///
+    authorization: String,
+
+    ctx: NodeContext,
+
+    reload: ReloadAction
+
+}
+
+impl AdminServer {
+
+    pub fn bind(conf: &ReboundAdmin, ctx: NodeContext, reload: ReloadAction) -> io::Result<Self> {
+        let server = Server::http(format!("{}:{}", conf.host, conf.port)).map_err(io::Error::other)?;
+        info!("admin API listening on {}:{}", conf.host, conf.port);
+        Ok(AdminServer { server, authorization: format!("Bearer {}", conf.token), ctx, reload })
+    }
+
+    pub fn start(self) -> io::Result<JoinHandle<()>> {
+        thread::Builder::new()
+            .name(String::from("admin"))
+            .spawn(move || {
+                for mut req in self.server.incoming_requests() {
+
+                    let (status, body) = if self.is_authorized(&req) {
+                        self.handle(&mut req)
+                    }
+                    else {
+                        warn!("unauthorized admin request {} {} from {}", req.method(), req.url(), req.remote_addr());
+                        (401, json!({ "error": "unauthorized" }).into())
+                    };
+
+                    let (body, content_type) = match body {
+                        AdminBody::Json(value) => (value.to_string(), "application/json"),
+                        AdminBody::Dot(dot) => (dot, "text/vnd.graphviz"),
+                    };
+                    let mut res = Response::from_string(body)
+                        .with_status_code(status)
+                        .with_header(Header::from_bytes("Content-Type", content_type).unwrap());
+                    if status == 401 {
+                        res.add_header(Header::from_bytes("WWW-Authenticate", "Bearer").unwrap());
+                    }
+
+                    if req.respond(res).is_err() {
+                        error!("failed to send admin response");
+                    }
+                }
+            })
+    }
+
+    fn is_authorized(&self, req: &Request) -> bool {
+        req.headers()
+            .iter()
+            .find(|h| h.field.equiv("Authorization"))
+            .map(|h| {
+                let given = h.value.as_str().as_bytes();
+                given.len() == self.authorization.len() && openssl::memcmp::eq(given, self.authorization.as_bytes())
+            })
+            .unwrap_or(false)
+    }
+
+    fn handle(&self, req: &mut Request) -> (u16, AdminBody) {
+
+        let (path, query) = req.url().split_once('?').unwrap_or((req.url(), ""));
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+        let (status, body) = match (req.method(), segments.as_slice()) {
+            (Method::Get, ["rules"]) => (200, self.rules()),
+            (Method::Get, ["circuit"]) => {
+                let graph = CircuitGraph::from(self.ctx.circuit.load().as_ref());
+                match query.split('&').any(|p| p == "format=dot") {
+                    true => return (200, AdminBody::Dot(graph.to_dot())),
+                    false => (200, json!(graph)),
+                }
+            },
+            (Method::Get, ["upstreams"]) => (200, self.upstreams()),
+            (Method::Post, ["upstreams", upstream, "drain"]) => {
+                info!("admin: draining upstream {}", upstream);
+                self.ctx.client.drain(upstream);
+                (200, json!({ "upstream": upstream, "state": "drained" }))
+            },
+            (Method::Post, ["upstreams", upstream, "enable"]) => {
+                info!("admin: enabling upstream {}", upstream);
+                match self.ctx.client.enable(upstream) {
+                    true => (200, json!({ "upstream": upstream, "state": "enabled" })),
+                    false => (404, json!({ "error": format!("upstream {} is not drained", upstream) })),
+                }
+            },
+            (Method::Post, ["reload"]) => {
+                info!("admin: reloading rules");
+                match (self.reload)() {
+                    Ok(_) => (200, json!({ "reloaded": true })),
+                    Err(e) => {
+                        error!("refused to reload rules, keeping the current ones: {}", e);
+                        (422, json!({ "reloaded": false, "error": e }))
+                    }
+                }
+            },
+            (Method::Get, ["workers"]) => (200, self.workers()),
+            (Method::Post, ["explain"]) => return self.explain(req),
+            (_, ["rules"] | ["circuit"] | ["upstreams"] | ["upstreams", _, "drain" | "enable"] | ["reload"] | ["workers"] | ["explain"]) => {
+                (405, json!({ "error": format!("{} not allowed on {}", req.method(), path) }))
+            },
+            _ => (404, json!({ "error": format!("no admin endpoint {}", path) })),
+        };
+        (status, body.into())
+    }
+
+    fn rules(&self) -> Value {
+        let circuit = self.ctx.circuit.load();
+        let rules: Vec<_> = circuit.nodes.iter().filter_map(|n| n.rule.as_ref()).collect();
+        json!(rules)
+    }
+
+    fn upstreams(&self) -> Value {
+        let client = &self.ctx.client;
+        let pools: Vec<Value> = client.pools()
+            .iter()
+            .map(|p| json!({
+                "upstream": p.host,
+                "state": if client.is_drained(&p.host) { "drained" } else { "enabled" },
+                "requests": p.stats.requests(),
+                "in_flight": p.stats.in_flight(),
+                "errors": p.stats.errors(),
+                "consecutive_errors": p.stats.consecutive_errors(),
+                "new_connections": p.stats.new_connections(),
+                "reused_connections": p.stats.reused_connections(),
+                "concurrency": p.limiter.as_ref().map(|l| json!({
+                    "limit": l.limit(),
+                    "max_in_flight": l.max_in_flight(),
+                    "rejected": l.rejected()
+                }))
+            }))
+            .collect();
+
+        json!({ "pools": pools, "drained": client.drained() })
+    }
+
+    fn explain(&self, req: &mut Request) -> (u16, AdminBody) {
+
+        let body: ExplainBody = match serde_json::from_reader(req.as_reader()) {
+            Ok(body) => body,
+            Err(e) => return (400, json!({ "error": format!("expected {{\"method\", \"url\", \"headers\"}}: {}", e) }).into()),
+        };
+
+        match explain::explain_request(&body.method, &body.url, &body.headers) {
+            Ok(rebound_req) => {
+                let explanation = explain::explain(&self.ctx.circuit.load(), &body.method, &body.url, &rebound_req);
+                (200, json!(explanation).into())
+            },
+            Err(e) => (400, json!({ "error": e }).into()),
+        }
+    }
+
+    fn workers(&self) -> Value {
+        let stats = &self.ctx.supervisor_stats;
+        let states: Vec<Value> = stats.workers()
+            .iter()
+            .map(|(wid, state)| json!({
+                "id": wid,
+                "state": if state.request.is_some() { "busy" } else { "idle" },
+                "request": state.request,
+                "busy_ms": state.busy_since.map(|t| t.elapsed().as_millis() as u64),
+                "handled": state.handled
+            }))
+            .collect();
+
+        json!({
+            "mode": match self.ctx.config.mode {
+                ReboundMode::Threaded => "threaded",
+                ReboundMode::Async => "async",
+            },
+            "workers": self.ctx.config.workers,
+            "busy": self.ctx.metrics.busy_workers.get(),
+            "queue_depth": self.ctx.metrics.queue_depth.get(),
+            "restarts": stats.restarts(),
+            "panics": stats.panics(),
+            "states": states
+        })
+    }
+}
@@ -0,0 +1,156 @@
+use std::{net::IpAddr, sync::Arc, time::Instant};
+use log::{debug, info};
+use tiny_http::Request;
+
+use crate::{conf::{ReboundConf, ReboundRequestLog}, engine::{acl::{self, IpFilter, IpRange}, auth, client::ReboundClient, limit::RateLimiter, request::ReboundRequest, SharedCircuit}};
+
+use super::{access::{ACCESS_LOG_TARGET, AccessRecord}, metrics::Metrics, queue::QueuePolicy, trace::Tracer, supervisor::SupervisorStats, tls::ConnectionRegistry};
+
+/// Log target of handled requests, so their level can be set apart from the rest
+///
+pub const REQUEST_LOG_TARGET: &str = "rebound::requests";
+
+/// State the master shares with its workers and tasks
+///
+#[derive(Clone)]
+pub struct NodeContext {
+
+    /// Rebound configuration the master was started with
+    ///
+    pub config: ReboundConf,
+
+    /// Circuit requests are routed through
+    ///
+    pub circuit: SharedCircuit,
+
+    /// Upstream client
+    ///
+    pub client: Arc<ReboundClient>,
+
+    /// Admission control of the request queue
+    ///
+    pub queue_policy: Arc<QueuePolicy>,
+
+    /// Counters of the worker supervisor
+    ///
+    pub supervisor_stats: Arc<SupervisorStats>,
+
+    /// Client connections bridged by the TLS listener
+    ///
+    pub connections: ConnectionRegistry,
+
+    /// Metrics of handled requests, queue and workers
+    ///
+    pub metrics: Arc<Metrics>,
+
+    /// Tracer of requests, when tracing is enabled
+    ///
+    pub tracer: Option<Arc<Tracer>>,
+
+    /// Proxies trusted to tell the client address in X-Forwarded-For
+    ///
+    pub trusted_proxies: Arc<Vec<IpRange>>,
+
+    /// Client addresses allowed or denied on every request, when set
+    ///
+    pub ip_filter: Option<Arc<IpFilter>>,
+
+    /// Rate limit of every request, when set
+    ///
+    pub rate_limiter: Option<Arc<RateLimiter>>
+
+}
+
+impl NodeContext {
+
+    /// Reads the client request, along with the certificate of its TLS connection
+    /// and the client address, forwarded by trusted proxies
+    pub fn rebound_request(&self, req: &mut Request) -> ReboundRequest {
+
+        let conn = self.connections.get(req.remote_addr());
+        let client_addr = self.client_addr(req);
+
+        let mut rebound_req = ReboundRequest::from(req);
+        rebound_req.client_addr = Some(client_addr);
+        if let Some(conn) = conn {
+            debug!("request bridged from tls client {}", conn.peer);
+            rebound_req.client_cert = conn.client_cert;
+        }
+        rebound_req
+    }
+
+    /// Address of the client, behind the TLS listener and the trusted proxies
+    ///
+    pub fn client_addr(&self, req: &Request) -> IpAddr {
+        let peer = self.connections
+            .get(req.remote_addr())
+            .map(|c| c.peer)
+            .unwrap_or(*req.remote_addr());
+        let forwarded_for: Vec<&str> = req.headers()
+            .iter()
+            .filter(|h| h.field.equiv("X-Forwarded-For"))
+            .map(|h| h.value.as_str())
+            .collect();
+        acl::client_addr(peer.ip(), &forwarded_for, &self.trusted_proxies)
+    }
+
+    /// Logs a client request about to be handled, as configured
+    ///
+    pub fn log_request(&self, handler: &str, req: &Request) {
+        match self.config.logging.requests {
+            ReboundRequestLog::Full => info!(target: REQUEST_LOG_TARGET, "{} handling request: {:?}", handler, req),
+            ReboundRequestLog::Summary => info!(target: REQUEST_LOG_TARGET, "{} handling {}", handler, self.summary(req)),
+            ReboundRequestLog::None => {},
+        }
+    }
+
+    /// Logs a request about to be sent upstream, as configured
+    ///
+    pub fn log_upstream_request(&self, handler: &str, req: &ReboundRequest) {
+        match self.config.logging.requests {
+            ReboundRequestLog::Full => info!(target: REQUEST_LOG_TARGET, "{} sending upstream request: {:?}", handler, req),
+            ReboundRequestLog::Summary => match req.full_url() {
+                Ok(url) => info!(target: REQUEST_LOG_TARGET, "{} sending upstream {} {}", handler, req.method.as_str(), self.redact(url.as_str())),
+                Err(_) => info!(target: REQUEST_LOG_TARGET, "{} sending upstream {} {}", handler, req.method.as_str(), req.uri),
+            },
+            ReboundRequestLog::None => {},
+        }
+    }
+
+    /// Method, url and client of a request, for logs
+    ///
+    pub fn summary(&self, req: &Request) -> String {
+        format!("{} {} from {}", req.method(), self.redact(req.url()), req.remote_addr())
+    }
+
+    /// Url with the API keys sent as query params redacted, for logs
+    ///
+    pub fn redact(&self, url: &str) -> String {
+        auth::redact_query(url, &self.circuit.load().secret_params)
+    }
+
+    /// Starts the access record of a client request, with the address of the client behind the TLS listener
+    /// and the trusted proxies
+    pub fn access_record(&self, req: &Request, received: Instant) -> AccessRecord {
+        let mut record = AccessRecord::new(req, self.client_addr(req), received);
+        record.trace = self.tracer.as_ref().map(|t| t.start_trace(req));
+        record
+    }
+
+    /// Records a finished request in the metrics, and in the traces and access log when enabled
+    ///
+    pub fn finish(&self, record: &mut AccessRecord) {
+
+        // API keys sent as query params are kept out of the access log and traces
+        record.path = self.redact(&record.path);
+        record.upstream = record.upstream.as_deref().map(|u| self.redact(u));
+
+        self.metrics.record(record);
+        if let Some(tracer) = &self.tracer {
+            tracer.finish(record);
+        }
+        if let Some(access) = &self.config.logging.access {
+            info!(target: ACCESS_LOG_TARGET, "{}", record.format(&access.format));
+        }
+    }
+}
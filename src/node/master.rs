@@ -1,116 +1,280 @@
-use std::{io::Result, thread::{self, JoinHandle}, fs::File, env};
-use flume::{Sender, Receiver};
-use log::{info, error};
-use tiny_http::{Server, SslConfig, Request, Response};
+use std::{io::{Error, ErrorKind, Result}, sync::{Arc, atomic::{AtomicBool, Ordering}}, thread, time::{Duration, Instant}};
+use arc_swap::ArcSwap;
+use flume::{Sender, Receiver, TrySendError};
+use log::{info, error, warn};
+use signal_hook::{consts::TERM_SIGNALS, flag};
+use tiny_http::{Request, Server};
 
-use crate::{conf::{ReboundConf, parser::read_ssl_file, REBOUND_DEFAULT_ERROR_FILE}, engine::circuit::Circuit};
+use crate::{conf::{ReboundConf, ReboundMode, check::{self, Severity}}, engine::{circuit::{Circuit, CircuitBuilder}, client::ReboundClient, error::ReboundError, acl::{self, IpFilter}, limit::RateLimiter}};
 
-use super::worker::WorkerNode;
+use super::{admin::AdminServer, context::NodeContext, metrics::{Metrics, MetricsServer}, queue::{QueuedRequest, QueuePolicy}, supervisor::{Supervisor, SupervisorStats, error_response}, task::TaskDispatcher, trace::Tracer, reload::Reloader, tls::{self, ConnectionRegistry, TlsTerminator}};
+
+/// How long the master waits for a request before checking for shutdown
+///
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
 
 /// Master Node for Rebound that controls the whole Server
 /// 
 pub struct MasterNode {
 
-    ///
+    /// Rebound configuration the master was started with
     /// 
-    #[allow(unused)]
     config: ReboundConf,
     
-    ///
+    /// Listening server accepting incoming requests
     /// 
     server: Server,
 
-    ///
+    /// Supervisor keeping the workers alive
     /// 
-    workers: Vec<WorkerNode>,
+    supervisor: Supervisor,
 
-    ///
+    /// Sending half of the request queue
     /// 
-    request_queue_tx: Sender<Request>,
+    request_queue_tx: Sender<QueuedRequest>,
 
-    ///
+    /// Receiving half of the request queue
+    /// 
+    request_queue_rx: Receiver<QueuedRequest>,
+
+    /// Admission control of the request queue
+    /// 
+    queue_policy: Arc<QueuePolicy>,
+
+    /// State shared with the workers and tasks
+    /// 
+    ctx: NodeContext,
+
+    /// Task dispatcher, replacing the workers in async mode
+    /// 
+    dispatcher: Option<TaskDispatcher>,
+
+    /// Set once SIGTERM or SIGINT is received
     /// 
-    #[allow(unused)]
-    request_queue_rx: Receiver<Request>,
+    shutdown: Arc<AtomicBool>,
 
 }
 
 impl MasterNode {
     
-    pub fn from(conf: ReboundConf, circuit: Circuit) -> Result<Self> {
+    pub fn from(conf_file: String, conf: ReboundConf, circuit: Circuit) -> Result<Self> {
         
         info!("starting master...");
 
-        let (tx, rx) = flume::unbounded::<Request>();
-        let wc = conf.workers;        
-        let workers = (0..wc)
-            .map(|n| WorkerNode::from( String::from(format!("worker-{}", n+1)), conf.clone(), circuit.clone(), rx.clone()))
-            .collect();
+        let queue_policy = Arc::new(QueuePolicy::from(&conf.queue));
+        let (tx, rx) = queue_policy.channel::<QueuedRequest>();
+        let client = Arc::new(
+            ReboundClient::new(&conf).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?
+        );
+        let metrics = Arc::new(Metrics::new().map_err(Error::other)?);
+        metrics.workers.set(conf.workers as i64);
+        if let Some(metrics_conf) = &conf.metrics {
+            MetricsServer::bind(metrics_conf, metrics.clone())?.start()?;
+        }
 
+        let ctx = NodeContext {
+            config: conf.clone(),
+            circuit: Arc::new(ArcSwap::from_pointee(circuit)),
+            client,
+            queue_policy: queue_policy.clone(),
+            supervisor_stats: Arc::new(SupervisorStats::default()),
+            connections: ConnectionRegistry::default(),
+            metrics,
+            tracer: conf.tracing.as_ref().map(Tracer::start).transpose()?.map(Arc::new),
+            trusted_proxies: Arc::new(acl::parse_ranges(&conf.trusted_proxies).map_err(Error::other)?),
+            ip_filter: conf.ip_filter.as_ref().map(IpFilter::new).transpose().map_err(Error::other)?.map(Arc::new),
+            rate_limiter: conf.rate_limit.as_ref().map(|l| Arc::new(RateLimiter::new(l)))
+        };
+        let dispatcher = match conf.mode {
+            ReboundMode::Threaded => None,
+            ReboundMode::Async => Some(TaskDispatcher::new(ctx.clone())),
+        };
+        let supervisor = Supervisor::new(rx.clone(), ctx.clone());
 
-        let s = match conf.clone().ssl {
+        let mut reloader = Reloader::default();
+        let circuit = ctx.circuit.clone();
+        let file = conf_file.clone();
+        reloader.watch("rules", vec![conf_file.clone()], move || reload_circuit(&file, &circuit));
+
+        if let Some(admin_conf) = &conf.admin {
+            let circuit = ctx.circuit.clone();
+            let reload = Box::new(move || reload_circuit(&conf_file, &circuit));
+            AdminServer::bind(admin_conf, ctx.clone(), reload)?.start()?;
+        }
+        let s = match &conf.ssl {
             Some(rebound_ssl) => {
-                Server::https(
 
-                    format!("{}:{}", conf.host, conf.port),
-                    SslConfig
-                    {
-                        certificate: read_ssl_file(rebound_ssl.pub_cert),
-                        private_key: read_ssl_file(rebound_ssl.priv_key)
-                    }
+                // TLS is terminated in front of an internal server, so client certificates
+                // can be verified and handed to the rules
+                let s = Server::http("127.0.0.1:0").map_err(Error::other)?;
+                let internal = s.server_addr();
+                let terminator = TlsTerminator::bind(&format!("{}:{}", conf.host, conf.port), rebound_ssl, internal, ctx.connections.clone())?;
 
-                ).unwrap()
+                let acceptor = terminator.acceptor();
+                let ssl = rebound_ssl.clone();
+                reloader.watch("tls certificates", tls::ssl_files(rebound_ssl), move || tls::reload_acceptor(&acceptor, &ssl));
+
+                terminator.start();
+                info!("tls listener bridged to internal server on {}, client auth: {:?}", internal, rebound_ssl.client_auth);
+                s
             }
-            None => Server::http(format!("{}:{}", conf.host, conf.port)).unwrap(),
+            None => Server::http(format!("{}:{}", conf.host, conf.port)).map_err(Error::other)?,
         };
         info!("master listening on {}:{}", conf.host, conf.port);
 
+        reloader.start()?;
+
+        // a second signal while draining terminates right away
+        let shutdown = Arc::new(AtomicBool::new(false));
+        for sig in TERM_SIGNALS {
+            flag::register_conditional_shutdown(*sig, 1, shutdown.clone())?;
+            flag::register(*sig, shutdown.clone())?;
+        }
+
         Ok(
             MasterNode {
                config: conf.clone(),
                server: s,
-               workers: workers,
+               supervisor,
                request_queue_tx: tx,
-               request_queue_rx: rx
+               request_queue_rx: rx,
+               queue_policy,
+               dispatcher,
+               ctx,
+               shutdown
             }
         )
     }
 
-    pub fn run(self) {
-        
-        let mut worker_handles: Vec<JoinHandle<()>> = Vec::new();
-        for mut w in self.workers {
+    /// Serves requests until SIGTERM or SIGINT, then drains queued and in-flight requests
+    ///
+    /// Returns whether all requests were drained before the drain timeout
+    pub fn run(self) -> bool {
 
-            info!("starting {}", w.id);
-            let handle: JoinHandle<()> = thread::spawn(move || {
-                w
-                    .run(|| {
-                        Response
-                            ::from_file(File::open(env::var(REBOUND_DEFAULT_ERROR_FILE).unwrap()).unwrap())
-                            .with_status_code(502)
+        // connections bridged by the TLS listener, when there is one
+        let bridged = self.config.ssl.as_ref().map(|_| self.ctx.connections.clone());
 
-                    });
-                info!("shutting down {}", w.id);
-            });
+        if let Some(dispatcher) = &self.dispatcher {
 
-            worker_handles.push(handle)
+            info!("master ready, async mode!");
+            while let Some(req) = accept(&self.server, &self.shutdown, bridged.as_ref()) {
+                dispatcher.dispatch(req);
+            }
 
+            let deadline = drain_deadline(&self.config);
+            drop(self.server);
+            return dispatcher.wait(deadline);
         }
         
+        let supervisor_handle = self.supervisor.start();
+        
         info!("master ready!");
 
-        // loop until shutdown, implemented as iterator
-        for req in self.server.incoming_requests() {
+        // loop until shutdown
+        while let Some(req) = accept(&self.server, &self.shutdown, bridged.as_ref()) {
+
+             match self.request_queue_tx.try_send(QueuedRequest::new(req)) {
+                Ok(_) => {
+                    let depth = self.request_queue_tx.len();
+                    self.queue_policy.record_depth(depth);
+                    self.ctx.metrics.queue_depth.set(depth as i64);
+                },
+                Err(TrySendError::Full(queued)) => {
+                    self.queue_policy.record_rejected();
+                    warn!(
+                        "queue full ({} requests, max seen {}), shedding request: {} (rejected: {})",
+                        self.request_queue_tx.len(),
+                        self.queue_policy.max_depth(),
+                        self.ctx.summary(&queued.request),
+                        self.queue_policy.rejected()
+                    );
+
+                    let e = self.queue_policy.overloaded();
+                    let mut access = self.ctx.access_record(&queued.request, queued.queued_at);
+                    access.error = Some(e.kind());
+                    if access.respond(queued.request, error_response(&e)).is_err() {
+                        error!("failed to send overloaded response");
+                    }
+                    self.ctx.finish(&mut access);
+                },
+                Err(TrySendError::Disconnected(queued)) => {
+                    error!("failed to queue request, no worker left: {}", self.ctx.summary(&queued.request));
+                    if queued.request.respond(error_response(&ReboundError::Internal)).is_err() {
+                        error!("failed to send error response");
+                    }
+                },
+            }
+        }
+
+        // closing the queue lets the workers exit once it is drained
+        let deadline = drain_deadline(&self.config);
+        drop(self.server);
+        drop(self.request_queue_tx);
 
-             match self.request_queue_tx.send(req) {
-                Ok(_) =>  (),
-                Err(e) => error!("failed to queue request, error: {}", e),
+        while !supervisor_handle.is_finished() {
+            if Instant::now() >= deadline {
+                warn!("{} requests still queued", self.request_queue_rx.len());
+                return false;
             }
+            thread::sleep(ACCEPT_INTERVAL);
         }
+        supervisor_handle.join().is_ok()
+    }
 
-        for handle in worker_handles {
-            handle.join().unwrap()
+}
+
+/// Next incoming request, none once shutdown was requested or the server failed
+///
+/// Waits for the next request, until shutdown
+///
+/// Behind the TLS listener, requests from connections it did not bridge are refused,
+/// as they come from local processes skipping TLS and client certificate checks
+fn accept(server: &Server, shutdown: &AtomicBool, bridged: Option<&ConnectionRegistry>) -> Option<Request> {
+    while !shutdown.load(Ordering::Relaxed) {
+        match server.recv_timeout(ACCEPT_INTERVAL) {
+            Ok(Some(req)) if bridged.is_some_and(|b| b.get(req.remote_addr()).is_none()) => {
+                warn!("refused request from {}, not bridged by the tls listener", req.remote_addr());
+                let e = ReboundError::Forbidden(String::from("connection not bridged by the tls listener"));
+                if req.respond(error_response(&e)).is_err() {
+                    error!("failed to send forbidden response");
+                }
+            },
+            Ok(Some(req)) => return Some(req),
+            Ok(None) => continue,
+            Err(e) => {
+                error!("failed to accept request: {}", e);
+                return None;
+            },
         }
     }
+    None
+}
 
-}
\ No newline at end of file
+fn drain_deadline(conf: &ReboundConf) -> Instant {
+    info!("shutting down, draining requests for up to {}ms", conf.drain_timeout_ms);
+    Instant::now() + Duration::from_millis(conf.drain_timeout_ms)
+}
+
+/// Rebuilds the circuit from the rules of the conf file and swaps it in for new requests
+///
+/// The conf is checked as on startup, and only its rules are reloaded, other settings need a restart
+fn reload_circuit(conf_file: &str, circuit: &ArcSwap<Circuit>) -> std::result::Result<(), String> {
+
+    let report = check::check(conf_file);
+    let conf = match report.conf {
+        Some(conf) if report.errors() == 0 => conf,
+        _ => {
+            let errors: Vec<String> = report.issues
+                .iter()
+                .filter(|i| i.severity == Severity::Error)
+                .map(|i| i.to_string())
+                .collect();
+            return Err(errors.join("; "));
+        },
+    };
+
+    let reloaded = CircuitBuilder::new(conf.rules.unwrap_or_default()).build()?;
+    info!("circuit reloaded with {} rules", reloaded.nodes.len() - 1);
+    circuit.store(Arc::new(reloaded));
+    Ok(())
+}
@@ -0,0 +1,187 @@
+use std::{io, sync::Arc, thread::{self, JoinHandle}};
+use log::{error, info};
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use tiny_http::{Header, Response, Server};
+
+use crate::conf::ReboundMetrics;
+
+use super::access::AccessRecord;
+
+/// Label of requests no rule matched
+///
+const NO_RULE: &str = "none";
+
+/// Methods labelled as sent, any other is labelled OTHER so clients can not grow the label set
+///
+const KNOWN_METHODS: &[&str] = &["GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH"];
+
+/// Label of requests with an extension method
+///
+const OTHER_METHOD: &str = "OTHER";
+
+/// Latency buckets in seconds, from 1ms to 30s
+///
+const LATENCY_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Metrics of Rebound, shared by the master, workers and tasks
+///
+pub struct Metrics {
+
+    registry: Registry,
+
+    /// Requests by rule, method and status
+    ///
+    requests: IntCounterVec,
+
+    /// Time from receiving a request to sending its response, by rule
+    ///
+    request_duration: HistogramVec,
+
+    /// Time spent waiting on upstreams, by rule
+    ///
+    upstream_latency: HistogramVec,
+
+    /// Failed requests by error kind
+    ///
+    errors: IntCounterVec,
+
+    /// Requests waiting in the queue between the master and the workers
+    ///
+    pub queue_depth: IntGauge,
+
+    /// Workers handling a request, or requests in flight in async mode
+    ///
+    pub busy_workers: IntGauge,
+
+    /// Workers started
+    ///
+    pub workers: IntGauge,
+
+    /// Workers respawned after dying
+    ///
+    pub worker_restarts: IntCounter,
+
+    /// Panics caught while handling requests, by workers or tasks
+    ///
+    pub panics: IntCounter
+
+}
+
+impl Metrics {
+
+    pub fn new() -> Result<Self, prometheus::Error> {
+
+        let registry = Registry::new_custom(Some(String::from("rebound")), None)?;
+
+        let requests = IntCounterVec::new(Opts::new("requests_total", "Requests by rule, method and status"), &["rule", "method", "status"])?;
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new("request_duration_seconds", "Time from receiving a request to sending its response").buckets(LATENCY_BUCKETS.to_vec()),
+            &["rule"]
+        )?;
+        let upstream_latency = HistogramVec::new(
+            HistogramOpts::new("upstream_latency_seconds", "Time spent waiting on the upstream").buckets(LATENCY_BUCKETS.to_vec()),
+            &["rule"]
+        )?;
+        let errors = IntCounterVec::new(Opts::new("errors_total", "Failed requests by error kind"), &["kind"])?;
+        let queue_depth = IntGauge::new("queue_depth", "Requests waiting in the queue between the master and the workers")?;
+        let busy_workers = IntGauge::new("busy_workers", "Workers handling a request, or requests in flight in async mode")?;
+        let workers = IntGauge::new("workers", "Workers started")?;
+        let worker_restarts = IntCounter::new("worker_restarts_total", "Workers respawned after dying")?;
+        let panics = IntCounter::new("panics_total", "Panics caught while handling requests")?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(request_duration.clone()))?;
+        registry.register(Box::new(upstream_latency.clone()))?;
+        registry.register(Box::new(errors.clone()))?;
+        registry.register(Box::new(queue_depth.clone()))?;
+        registry.register(Box::new(busy_workers.clone()))?;
+        registry.register(Box::new(workers.clone()))?;
+        registry.register(Box::new(worker_restarts.clone()))?;
+        registry.register(Box::new(panics.clone()))?;
+
+        Ok(Metrics { registry, requests, request_duration, upstream_latency, errors, queue_depth, busy_workers, workers, worker_restarts, panics })
+    }
+
+    /// Records a finished request
+    ///
+    pub fn record(&self, record: &AccessRecord) {
+
+        let rule = record.rule.as_deref().unwrap_or(NO_RULE);
+        let method = match KNOWN_METHODS.contains(&record.method.as_str()) {
+            true => record.method.as_str(),
+            false => OTHER_METHOD,
+        };
+        self.requests
+            .with_label_values(&[rule, method, record.status.to_string().as_str()])
+            .inc();
+        self.request_duration
+            .with_label_values(&[rule])
+            .observe(record.total_latency.as_secs_f64());
+
+        if let Some(latency) = record.upstream_latency {
+            self.upstream_latency.with_label_values(&[rule]).observe(latency.as_secs_f64());
+        }
+        if let Some(kind) = record.error {
+            self.errors.with_label_values(&[kind]).inc();
+        }
+    }
+
+    /// Metrics in the Prometheus text format
+    ///
+    pub fn encode(&self) -> Result<Vec<u8>, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+/// Listener serving the metrics, apart from the proxied requests
+///
+pub struct MetricsServer {
+
+    server: Server,
+
+    path: String,
+
+    metrics: Arc<Metrics>
+
+}
+
+impl MetricsServer {
+
+    pub fn bind(conf: &ReboundMetrics, metrics: Arc<Metrics>) -> io::Result<Self> {
+        let server = Server::http(format!("{}:{}", conf.host, conf.port)).map_err(io::Error::other)?;
+        info!("metrics listening on {}:{}{}", conf.host, conf.port, conf.path);
+        Ok(MetricsServer { server, path: conf.path.clone(), metrics })
+    }
+
+    pub fn start(self) -> io::Result<JoinHandle<()>> {
+        thread::Builder::new()
+            .name(String::from("metrics"))
+            .spawn(move || {
+                for req in self.server.incoming_requests() {
+
+                    let path = req.url().split('?').next().unwrap_or_default();
+                    let res = if path != self.path {
+                        Response::from_string("not found").with_status_code(404).boxed()
+                    }
+                    else {
+                        match self.metrics.encode() {
+                            Ok(body) => {
+                                let content_type = Header::from_bytes("Content-Type", TextEncoder::new().format_type()).unwrap();
+                                Response::from_data(body).with_header(content_type).boxed()
+                            },
+                            Err(e) => {
+                                error!("failed to encode metrics: {}", e);
+                                Response::from_string("failed to encode metrics").with_status_code(500).boxed()
+                            }
+                        }
+                    };
+
+                    if req.respond(res).is_err() {
+                        error!("failed to send metrics response");
+                    }
+                }
+            })
+    }
+}
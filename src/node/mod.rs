@@ -1,2 +1,12 @@
+pub mod access;
+pub mod admin;
+pub mod context;
 pub mod master;
+pub mod metrics;
+pub mod queue;
+pub mod reload;
+pub mod supervisor;
+pub mod task;
+pub mod tls;
+pub mod trace;
 pub mod worker;
\ No newline at end of file
@@ -0,0 +1,173 @@
+use std::{sync::atomic::{AtomicUsize, Ordering}, time::{Duration, Instant}};
+use tiny_http::Request;
+
+use crate::{conf::ReboundQueue, engine::error::ReboundError};
+
+/// Request waiting in the queue between master and workers
+///
+pub struct QueuedRequest {
+
+    /// Client request
+    ///
+    pub request: Request,
+
+    /// When the master queued the request
+    ///
+    pub queued_at: Instant
+
+}
+
+impl QueuedRequest {
+
+    pub fn new(request: Request) -> Self {
+        QueuedRequest { request, queued_at: Instant::now() }
+    }
+
+    pub fn waited(&self) -> Duration {
+        self.queued_at.elapsed()
+    }
+}
+
+/// Admission control of the request queue
+///
+/// Keeps the queue limits together with the counters of shed requests
+#[derive(Debug)]
+pub struct QueuePolicy {
+
+    /// Max number of queued requests
+    ///
+    pub capacity: usize,
+
+    /// Max time a request may wait in the queue
+    ///
+    pub max_wait: Option<Duration>,
+
+    /// Seconds sent in the Retry-After header of shed requests
+    ///
+    pub retry_after: u64,
+
+    /// Requests rejected because the queue was full
+    ///
+    rejected: AtomicUsize,
+
+    /// Requests dropped because they waited past the deadline
+    ///
+    expired: AtomicUsize,
+
+    /// Highest queue depth seen
+    ///
+    max_depth: AtomicUsize
+
+}
+
+impl QueuePolicy {
+
+    pub fn channel<T>(&self) -> (flume::Sender<T>, flume::Receiver<T>) {
+        flume::bounded(self.capacity)
+    }
+
+    pub fn is_expired(&self, req: &QueuedRequest) -> bool {
+        self.max_wait.map(|max_wait| req.waited() > max_wait).unwrap_or(false)
+    }
+
+    /// Error shed requests are answered with, a 503 telling clients when to retry
+    ///
+    pub fn overloaded(&self) -> ReboundError {
+        ReboundError::Overloaded(self.retry_after)
+    }
+
+    pub fn record_depth(&self, depth: usize) {
+        self.max_depth.fetch_max(depth, Ordering::Relaxed);
+    }
+
+    pub fn record_rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_expired(&self) {
+        self.expired.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn rejected(&self) -> usize {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    pub fn expired(&self) -> usize {
+        self.expired.load(Ordering::Relaxed)
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth.load(Ordering::Relaxed)
+    }
+}
+
+impl From<&ReboundQueue> for QueuePolicy {
+    fn from(conf: &ReboundQueue) -> Self {
+        QueuePolicy {
+            capacity: conf.capacity,
+            max_wait: conf.max_wait_ms.map(Duration::from_millis),
+            retry_after: conf.retry_after,
+            rejected: AtomicUsize::new(0),
+            expired: AtomicUsize::new(0),
+            max_depth: AtomicUsize::new(0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use serde_json::json;
+    use tiny_http::TestRequest;
+
+    use super::*;
+
+    fn policy(conf: serde_json::Value) -> QueuePolicy {
+        QueuePolicy::from(&serde_json::from_value::<ReboundQueue>(conf).unwrap())
+    }
+
+    fn queued(waited: Duration) -> QueuedRequest {
+        QueuedRequest { request: TestRequest::new().into(), queued_at: Instant::now() - waited }
+    }
+
+    #[test]
+    fn rejects_over_capacity() {
+        let policy = policy(json!({ "capacity": 2 }));
+        let (tx, _rx) = policy.channel();
+        assert!(tx.try_send(queued(Duration::ZERO)).is_ok());
+        assert!(tx.try_send(queued(Duration::ZERO)).is_ok());
+        assert!(matches!(tx.try_send(queued(Duration::ZERO)), Err(flume::TrySendError::Full(_))));
+
+        policy.record_rejected();
+        assert_eq!(policy.rejected(), 1);
+    }
+
+    #[test]
+    fn expires_after_max_wait() {
+        let policy = policy(json!({ "max_wait_ms": 100, "retry_after": 3 }));
+        assert!(!policy.is_expired(&queued(Duration::from_millis(50))));
+        assert!(policy.is_expired(&queued(Duration::from_millis(150))));
+
+        let e = policy.overloaded();
+        assert_eq!(e.status_code(), 503);
+        assert_eq!(e.headers(), [(String::from("Retry-After"), String::from("3"))]);
+
+        policy.record_expired();
+        assert_eq!(policy.expired(), 1);
+    }
+
+    #[test]
+    fn never_expires_without_max_wait() {
+        let policy = policy(json!({}));
+        assert!(!policy.is_expired(&queued(Duration::from_secs(3600))));
+    }
+
+    #[test]
+    fn keeps_max_depth() {
+        let policy = policy(json!({}));
+        for depth in [3, 7, 2] {
+            policy.record_depth(depth);
+        }
+        assert_eq!(policy.max_depth(), 7);
+    }
+}
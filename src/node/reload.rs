@@ -0,0 +1,98 @@
+use std::{fs, thread::{self, JoinHandle}, time::{Duration, SystemTime}};
+use log::{error, info};
+use signal_hook::{consts::SIGHUP, iterator::Signals};
+
+/// How often watched files are checked for changes
+///
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Files watched together, reloaded by a single action
+///
+struct Watch {
+
+    /// Name of the watch, for logging
+    ///
+    name: String,
+
+    /// Watched files with their last seen modification time
+    ///
+    files: Vec<(String, Option<SystemTime>)>,
+
+    /// Reloads from the files, returning a description of the failure when the reload was refused
+    ///
+    action: Box<dyn FnMut() -> Result<(), String> + Send>
+
+}
+
+impl Watch {
+
+    /// Whether any file changed since the last check, recording the new modification times
+    ///
+    fn changed(&mut self) -> bool {
+        let mut changed = false;
+        for (file, modified) in self.files.iter_mut() {
+            let current = fs::metadata(file.as_str()).and_then(|m| m.modified()).ok();
+            if current != *modified {
+                *modified = current;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+/// Reloads parts of Rebound when their files change or on SIGHUP
+///
+#[derive(Default)]
+pub struct Reloader {
+
+    /// Registered watches
+    ///
+    watches: Vec<Watch>
+
+}
+
+impl Reloader {
+
+    /// Registers an action run when any of the files changes
+    ///
+    pub fn watch<F>(&mut self, name: &str, files: Vec<String>, action: F)
+    where
+        F: FnMut() -> Result<(), String> + Send + 'static,
+    {
+        let mut watch = Watch {
+            name: String::from(name),
+            files: files.into_iter().map(|f| (f, None)).collect(),
+            action: Box::new(action)
+        };
+        watch.changed();
+        self.watches.push(watch);
+    }
+
+    /// Checks the watched files on a separate thread, SIGHUP reloads everything
+    ///
+    pub fn start(mut self) -> std::io::Result<JoinHandle<()>> {
+
+        let mut signals = Signals::new([SIGHUP])?;
+        Ok(thread::spawn(move || loop {
+
+            thread::sleep(RELOAD_INTERVAL);
+            let hangup = signals.pending().count() > 0;
+            if hangup {
+                info!("SIGHUP received, reloading");
+            }
+
+            for watch in self.watches.iter_mut() {
+                if !watch.changed() && !hangup {
+                    continue;
+                }
+
+                info!("reloading {}", watch.name);
+                match (watch.action)() {
+                    Ok(_) => info!("reloaded {}", watch.name),
+                    Err(e) => error!("refused to reload {}, keeping the current one: {}", watch.name, e),
+                }
+            }
+        }))
+    }
+}
@@ -0,0 +1,198 @@
+use std::{collections::BTreeMap, fs::File, env, sync::{Mutex, atomic::{AtomicUsize, Ordering}}, thread::{self, JoinHandle}, time::{Duration, Instant}};
+use flume::Receiver;
+use log::{info, error, warn};
+use tiny_http::{Header, Response, ResponseBox};
+
+use crate::{conf::REBOUND_DEFAULT_ERROR_FILE, engine::error::ReboundError};
+
+use super::{context::NodeContext, queue::QueuedRequest, worker::WorkerNode};
+
+/// How often the supervisor checks on its workers
+///
+const SUPERVISOR_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Counters kept by the supervisor and its workers, shared for monitoring
+///
+#[derive(Default, Debug)]
+pub struct SupervisorStats {
+
+    /// Number of workers respawned after dying
+    ///
+    restarts: AtomicUsize,
+
+    /// Number of panics caught while handling requests
+    ///
+    panics: AtomicUsize,
+
+    /// State of each worker, by worker id
+    ///
+    workers: Mutex<BTreeMap<String, WorkerState>>
+
+}
+
+/// What a worker is doing
+///
+#[derive(Default, Clone, Debug)]
+pub struct WorkerState {
+
+    /// Request being handled, as method and url
+    ///
+    pub request: Option<String>,
+
+    /// Instant the request being handled was picked up
+    ///
+    pub busy_since: Option<Instant>,
+
+    /// Requests handled since the worker started
+    ///
+    pub handled: usize
+
+}
+
+impl SupervisorStats {
+
+    pub fn restarts(&self) -> usize {
+        self.restarts.load(Ordering::Relaxed)
+    }
+
+    pub fn panics(&self) -> usize {
+        self.panics.load(Ordering::Relaxed)
+    }
+
+    pub fn record_restart(&self) {
+        self.restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_panic(&self) {
+        self.panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_started(&self, wid: &str) {
+        self.workers.lock().unwrap().insert(String::from(wid), WorkerState::default());
+    }
+
+    pub fn record_busy(&self, wid: &str, request: String) {
+        let mut workers = self.workers.lock().unwrap();
+        let state = workers.entry(String::from(wid)).or_default();
+        state.request = Some(request);
+        state.busy_since = Some(Instant::now());
+    }
+
+    pub fn record_idle(&self, wid: &str) {
+        let mut workers = self.workers.lock().unwrap();
+        let state = workers.entry(String::from(wid)).or_default();
+        state.request = None;
+        state.busy_since = None;
+        state.handled += 1;
+    }
+
+    pub fn workers(&self) -> BTreeMap<String, WorkerState> {
+        self.workers.lock().unwrap().clone()
+    }
+}
+
+/// Supervisor keeping the configured number of workers alive
+///
+/// Workers that die while the request queue is still open are respawned
+pub struct Supervisor {
+
+    /// Receiving half of the request queue
+    ///
+    request_queue_rx: Receiver<QueuedRequest>,
+
+    /// State shared with every worker
+    ///
+    ctx: NodeContext
+
+}
+
+impl Supervisor {
+
+    pub fn new(rx: Receiver<QueuedRequest>, ctx: NodeContext) -> Self {
+        Supervisor {
+            request_queue_rx: rx,
+            ctx
+        }
+    }
+
+    /// Spawns the workers and supervises them on a separate thread
+    ///
+    /// The returned handle finishes once the request queue is closed and all workers exited
+    pub fn start(self) -> JoinHandle<()> {
+
+        let mut workers: Vec<(String, JoinHandle<()>)> = (0..self.ctx.config.workers)
+            .map(|n| {
+                let wid = format!("worker-{}", n+1);
+                let handle = self.spawn_worker(wid.clone());
+                (wid, handle)
+            })
+            .collect();
+
+        thread::spawn(move || {
+            while !workers.is_empty() {
+
+                thread::sleep(SUPERVISOR_INTERVAL);
+
+                let mut alive = Vec::with_capacity(workers.len());
+                for (wid, handle) in workers {
+
+                    if !handle.is_finished() {
+                        alive.push((wid, handle));
+                        continue;
+                    }
+
+                    let panicked = handle.join().is_err();
+                    if self.request_queue_rx.is_disconnected() && !panicked {
+                        info!("{} exited", wid);
+                        continue;
+                    }
+
+                    let stats = &self.ctx.supervisor_stats;
+                    stats.record_restart();
+                    self.ctx.metrics.worker_restarts.inc();
+                    error!("{} died, respawning (restarts: {}, panics caught: {})", wid, stats.restarts(), stats.panics());
+                    let handle = self.spawn_worker(wid.clone());
+                    alive.push((wid, handle));
+                }
+
+                workers = alive;
+            }
+
+            info!("supervisor stopped, all workers exited");
+        })
+    }
+
+    fn spawn_worker(&self, wid: String) -> JoinHandle<()> {
+
+        self.ctx.supervisor_stats.record_started(&wid);
+        let mut w = WorkerNode::from(wid, self.request_queue_rx.clone(), self.ctx.clone());
+        info!("starting {}", w.id);
+        thread::spawn(move || {
+            w.run(error_response);
+            info!("shutting down {}", w.id);
+        })
+    }
+}
+
+/// Error response served to clients, from the default error file when available
+///
+pub fn error_response(err: &ReboundError) -> ResponseBox {
+
+    let status = err.status_code();
+    let mut res = match env::var(REBOUND_DEFAULT_ERROR_FILE).map(File::open) {
+        Ok(Ok(f)) => Response::from_file(f).with_status_code(status).boxed(),
+        Ok(Err(e)) => {
+            warn!("failed to open default error file: {}", e);
+            Response::empty(status).boxed()
+        },
+        Err(_) => Response::empty(status).boxed(),
+    };
+
+    for (k, v) in err.headers() {
+        if let Ok(hdr) = Header::from_bytes(k.as_bytes(), v.as_bytes()) {
+            res.add_header(hdr);
+        }
+    }
+
+    res
+}
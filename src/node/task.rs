@@ -0,0 +1,167 @@
+use std::{io::Cursor, panic::AssertUnwindSafe, sync::{Arc, atomic::{AtomicUsize, Ordering}}, time::{Duration, Instant}};
+use async_std::task;
+use chrono::Utc;
+use futures::FutureExt;
+use log::{error, info, warn};
+use tiny_http::{Request, Response};
+
+use crate::{conf::{ReboundConf, ReboundMode}, engine::{error::ReboundError, ReboundEngine}};
+
+use super::{context::NodeContext, supervisor::error_response, worker::panic_message};
+
+/// Env var read by async-std to size its executor
+///
+const ASYNC_STD_THREAD_COUNT: &str = "ASYNC_STD_THREAD_COUNT";
+
+/// Sizes the async runtime to the workers of the conf, unless its thread count is set in the env
+///
+/// async-std reads its thread count once, when first used, and the env must not be changed
+/// once other threads run, so this is called on startup before any thread is spawned
+pub fn configure_runtime(conf: &ReboundConf) {
+    if conf.mode == ReboundMode::Async && std::env::var(ASYNC_STD_THREAD_COUNT).is_err() {
+        std::env::set_var(ASYNC_STD_THREAD_COUNT, conf.workers.max(1).to_string());
+    }
+}
+
+/// Dispatcher handling every request as a task on the async runtime
+///
+/// Unlike worker threads, a task waiting on a slow upstream does not hold a thread,
+/// so the number of requests in flight is only bounded by the queue capacity
+pub struct TaskDispatcher {
+
+    /// Engine shared by all tasks
+    ///
+    engine: Arc<ReboundEngine>,
+
+    /// State shared by all tasks
+    ///
+    ctx: NodeContext,
+
+    /// Max number of requests in flight
+    ///
+    capacity: usize,
+
+    /// Seconds sent in the Retry-After header of shed requests
+    ///
+    retry_after: u64,
+
+    /// Number of requests in flight
+    ///
+    in_flight: Arc<AtomicUsize>
+
+}
+
+impl TaskDispatcher {
+
+    pub fn new(ctx: NodeContext) -> Self {
+
+        let conf = &ctx.config;
+
+        TaskDispatcher {
+            engine: Arc::new(ReboundEngine::new(ctx.circuit.clone(), ctx.ip_filter.clone(), ctx.rate_limiter.clone())),
+            capacity: conf.queue.capacity,
+            retry_after: conf.queue.retry_after,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            ctx
+        }
+    }
+
+    /// Spawns a task handling the request, or sheds it when at capacity
+    ///
+    pub fn dispatch(&self, req: Request) {
+
+        let in_flight = self.in_flight.fetch_add(1, Ordering::AcqRel) + 1;
+        if in_flight > self.capacity {
+            self.in_flight.fetch_sub(1, Ordering::AcqRel);
+            warn!("{} requests in flight, shedding request: {}", in_flight - 1, self.ctx.summary(&req));
+
+            let e = ReboundError::Overloaded(self.retry_after);
+            let mut access = self.ctx.access_record(&req, Instant::now());
+            access.error = Some(e.kind());
+            if access.respond(req, error_response(&e)).is_err() {
+                error!("failed to send overloaded response");
+            }
+            self.ctx.finish(&mut access);
+            return;
+        }
+
+        let engine = self.engine.clone();
+        let ctx = self.ctx.clone();
+        let in_flight = self.in_flight.clone();
+        task::spawn(async move {
+            ctx.metrics.busy_workers.inc();
+            handle(engine, ctx.clone(), req).await;
+            ctx.metrics.busy_workers.dec();
+            in_flight.fetch_sub(1, Ordering::AcqRel);
+        });
+    }
+
+    /// Waits until all requests in flight are done, or the deadline passed
+    ///
+    /// Returns whether all requests were done
+    pub fn wait(&self, deadline: Instant) -> bool {
+        while self.in_flight.load(Ordering::Acquire) > 0 {
+            if Instant::now() >= deadline {
+                warn!("{} requests still in flight", self.in_flight.load(Ordering::Acquire));
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+}
+
+async fn handle(engine: Arc<ReboundEngine>, ctx: NodeContext, req: Request) {
+
+    let received = Instant::now();
+
+    // reading the client request and writing the response are blocking
+    let reader = ctx.clone();
+    let (req, rebound_req, mut access) = task::spawn_blocking(move || {
+        let mut req = req;
+        reader.log_request("task", &req);
+        let access = reader.access_record(&req, received);
+        let rebound_req = reader.rebound_request(&mut req);
+        (req, rebound_req, access)
+    }).await;
+
+    let r = AssertUnwindSafe(async {
+        let (rule, rebound_req) = engine.get(rebound_req);
+        access.rule = rule;
+        let mut rebound_req = rebound_req?;
+        access.route(&mut rebound_req);
+        ctx.log_upstream_request("task", &rebound_req);
+
+        let sent_at = Instant::now();
+        access.upstream_started = Some(Utc::now());
+        let rebound_res = ctx.client.send(rebound_req).await;
+        access.upstream_latency = Some(sent_at.elapsed());
+        Ok::<Response<Cursor<Vec<u8>>>, ReboundError>(rebound_res?.into())
+    })
+    .catch_unwind()
+    .await
+    .unwrap_or_else(|cause| {
+        ctx.supervisor_stats.record_panic();
+        ctx.metrics.panics.inc();
+        error!("task panicked handling {}: {}", ctx.summary(&req), panic_message(&cause));
+        Err(ReboundError::Internal)
+    });
+
+    task::spawn_blocking(move || {
+        match r {
+            Ok(res) => match access.respond(req, res.boxed()) {
+                Ok(_) => info!("task sent response from rule, finished request"),
+                Err(_) => error!("task failed to send response from rule"),
+            },
+            Err(e) => {
+                info!("task could not route request: {}", e);
+                access.error = Some(e.kind());
+                match access.respond(req, error_response(&e)) {
+                    Ok(_) => info!("task sent error response, finished request"),
+                    Err(_) => error!("task failed to send error response"),
+                }
+            }
+        }
+        ctx.finish(&mut access);
+    }).await;
+}
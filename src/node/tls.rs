@@ -0,0 +1,537 @@
+use arc_swap::ArcSwap;
+use std::{collections::HashMap, io::{self, Read, Write}, net::{Shutdown, SocketAddr, TcpListener, TcpStream}, os::fd::AsRawFd, sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}}, thread, time::{Duration, Instant}};
+use log::{debug, error, info, warn};
+use openssl::{hash::MessageDigest, pkey::PKey, ssl::{self, ErrorCode, NameType, SniError, SslAcceptor, SslAcceptorBuilder, SslContext, SslMethod, SslStream, SslVerifyMode}, x509::{X509, X509Name, X509NameRef}};
+
+use crate::{conf::{ReboundClientAuth, ReboundSSL, parser::read_ssl_file}, engine::request::ReboundClientCert};
+
+/// Max time a client gets to complete the TLS handshake
+///
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Size of the buffer of each direction of a bridged connection
+///
+const BRIDGE_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Client connection accepted by the TLS listener
+///
+#[derive(Clone, Debug)]
+pub struct ClientConnection {
+
+    /// Address of the client
+    ///
+    pub peer: SocketAddr,
+
+    /// Certificate presented and verified during the handshake
+    ///
+    pub client_cert: Option<ReboundClientCert>
+
+}
+
+/// Client connections bridged to the internal Http server
+///
+/// The internal server only sees the bridge, requests are mapped back to their client
+/// by the address of the bridge connection
+#[derive(Clone, Default)]
+pub struct ConnectionRegistry {
+
+    connections: Arc<Mutex<HashMap<SocketAddr, ClientConnection>>>
+
+}
+
+impl ConnectionRegistry {
+
+    /// Client connection behind the given remote address, if it was bridged
+    ///
+    pub fn get(&self, remote: &SocketAddr) -> Option<ClientConnection> {
+        self.connections.lock().unwrap().get(remote).cloned()
+    }
+
+    fn register(&self, bridge: SocketAddr, conn: ClientConnection) {
+        self.connections.lock().unwrap().insert(bridge, conn);
+    }
+
+    fn unregister(&self, bridge: &SocketAddr) {
+        self.connections.lock().unwrap().remove(bridge);
+    }
+}
+
+/// TLS listener of Rebound
+///
+/// Terminates TLS for clients, verifying their certificates when configured,
+/// and bridges every connection to the internal Http server
+pub struct TlsTerminator {
+
+    /// Listener accepting client connections
+    ///
+    listener: TcpListener,
+
+    /// Acceptor performing the handshakes, swapped when certificates are reloaded
+    ///
+    acceptor: Arc<ArcSwap<SslAcceptor>>,
+
+    /// Address of the internal Http server
+    ///
+    upstream: SocketAddr,
+
+    /// Registry of bridged connections
+    ///
+    connections: ConnectionRegistry,
+
+    /// Max number of client connections
+    ///
+    max_connections: usize,
+
+    /// Time after which a connection without traffic is closed
+    ///
+    idle_timeout: Duration,
+
+    /// Client connections open
+    ///
+    active: Arc<AtomicUsize>
+
+}
+
+impl TlsTerminator {
+
+    pub fn bind(addr: &str, ssl: &ReboundSSL, upstream: SocketAddr, connections: ConnectionRegistry) -> io::Result<Self> {
+        Ok(TlsTerminator {
+            listener: TcpListener::bind(addr)?,
+            acceptor: Arc::new(ArcSwap::from_pointee(build_acceptor(ssl).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?)),
+            upstream,
+            connections,
+            max_connections: ssl.max_connections,
+            idle_timeout: Duration::from_millis(ssl.idle_timeout_ms),
+            active: Arc::new(AtomicUsize::new(0))
+        })
+    }
+
+    /// Handle to the acceptor, to swap it for new connections
+    ///
+    pub fn acceptor(&self) -> Arc<ArcSwap<SslAcceptor>> {
+        self.acceptor.clone()
+    }
+
+    /// Accepts client connections on a separate thread
+    ///
+    pub fn start(self) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            for stream in self.listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let active = self.active.fetch_add(1, Ordering::AcqRel);
+                        if active >= self.max_connections {
+                            self.active.fetch_sub(1, Ordering::AcqRel);
+                            match stream.peer_addr() {
+                                Ok(peer) => warn!("{} tls connections open, closing connection from {}", active, peer),
+                                Err(_) => warn!("{} tls connections open, closing connection", active),
+                            }
+                            continue;
+                        }
+
+                        let acceptor = self.acceptor.load_full();
+                        let upstream = self.upstream;
+                        let connections = self.connections.clone();
+                        let idle_timeout = self.idle_timeout;
+                        let active = self.active.clone();
+                        let spawned = thread::Builder::new().spawn(move || {
+                            handle_connection(acceptor, stream, upstream, connections, idle_timeout);
+                            active.fetch_sub(1, Ordering::AcqRel);
+                        });
+                        if let Err(e) = spawned {
+                            self.active.fetch_sub(1, Ordering::AcqRel);
+                            error!("failed to spawn tls connection thread: {}", e);
+                        }
+                    },
+                    Err(e) => error!("failed to accept tls connection: {}", e),
+                }
+            }
+        })
+    }
+}
+
+/// Builds the acceptor for the listener certificates and client authentication
+///
+/// The default certificate is served unless the server name sent by the client
+/// matches one of the additional certificates
+pub fn build_acceptor(ssl: &ReboundSSL) -> Result<SslAcceptor, String> {
+
+    let mut builder = acceptor_builder(ssl, &ssl.pub_cert, &ssl.priv_key)?;
+
+    if !ssl.certificates.is_empty() {
+        let mut certificates = Vec::with_capacity(ssl.certificates.len());
+        for cert in ssl.certificates.iter() {
+            if cert.server_names.is_empty() {
+                return Err(format!("certificate {} has no server_names", cert.pub_cert));
+            }
+            let ctx = acceptor_builder(ssl, &cert.pub_cert, &cert.priv_key)?.build().into_context();
+            info!("tls certificate {} serving {:?}", cert.pub_cert, cert.server_names);
+            certificates.push((cert.server_names.iter().map(|n| n.to_lowercase()).collect::<Vec<String>>(), ctx));
+        }
+
+        builder.set_servername_callback(move |ssl, _| {
+            let selected = ssl.servername(NameType::HOST_NAME)
+                .and_then(|name| select_certificate(&certificates, name));
+            if let Some(ctx) = selected {
+                ssl.set_ssl_context(ctx).map_err(|_| SniError::ALERT_FATAL)?;
+            }
+            Ok(())
+        });
+    }
+
+    Ok(builder.build())
+}
+
+/// Rebuilds the acceptor from the certificate files and swaps it in for new connections
+///
+/// Connections already established keep the acceptor they were accepted with
+pub fn reload_acceptor(acceptor: &ArcSwap<SslAcceptor>, ssl: &ReboundSSL) -> Result<(), String> {
+    acceptor.store(Arc::new(build_acceptor(ssl)?));
+    Ok(())
+}
+
+/// Files the acceptor is built from
+///
+pub fn ssl_files(ssl: &ReboundSSL) -> Vec<String> {
+    let mut files = vec![ssl.pub_cert.clone(), ssl.priv_key.clone()];
+    files.extend(ssl.client_ca.iter().cloned());
+    for cert in ssl.certificates.iter() {
+        files.push(cert.pub_cert.clone());
+        files.push(cert.priv_key.clone());
+    }
+    files
+}
+
+/// Acceptor for a single certificate, with the client authentication of the listener
+///
+fn acceptor_builder(ssl: &ReboundSSL, pub_cert: &str, priv_key: &str) -> Result<SslAcceptorBuilder, String> {
+
+    let mut certs = X509::stack_from_pem(&read_ssl_file(pub_cert)?)
+        .map_err(|e| format!("invalid certificate {}: {}", pub_cert, e))?
+        .into_iter();
+    let key = PKey::private_key_from_pem(&read_ssl_file(priv_key)?)
+        .map_err(|e| format!("invalid private key {}: {}", priv_key, e))?;
+
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server()).map_err(|e| e.to_string())?;
+    let leaf = certs.next().ok_or_else(|| format!("no certificate in {}", pub_cert))?;
+    builder.set_certificate(&leaf).map_err(|e| e.to_string())?;
+    info!("tls certificate {} ({}) expires {}", pub_cert, name_to_string(leaf.subject_name()), leaf.not_after());
+    for chain_cert in certs {
+        builder.add_extra_chain_cert(chain_cert).map_err(|e| e.to_string())?;
+    }
+    builder.set_private_key(&key).map_err(|e| e.to_string())?;
+    builder.check_private_key().map_err(|e| format!("private key does not match certificate {}: {}", pub_cert, e))?;
+
+    let verify_mode = match ssl.client_auth {
+        ReboundClientAuth::None => SslVerifyMode::NONE,
+        ReboundClientAuth::Optional => SslVerifyMode::PEER,
+        ReboundClientAuth::Required => SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+    };
+
+    if ssl.client_auth != ReboundClientAuth::None {
+        let client_ca = ssl.client_ca
+            .as_ref()
+            .ok_or_else(|| String::from("client_ca is required for client_auth"))?;
+        builder.set_ca_file(client_ca).map_err(|e| format!("invalid client_ca {}: {}", client_ca, e))?;
+        builder.set_client_ca_list(X509Name::load_client_ca_file(client_ca).map_err(|e| format!("invalid client_ca {}: {}", client_ca, e))?);
+    }
+    builder.set_verify(verify_mode);
+
+    Ok(builder)
+}
+
+/// Certificate for the server name, exact names win over wildcards
+///
+/// A wildcard only stands for the leftmost label, *.example.com matches
+/// www.example.com but neither example.com nor a.www.example.com
+fn select_certificate<'a>(certificates: &'a [(Vec<String>, SslContext)], server_name: &str) -> Option<&'a SslContext> {
+
+    let server_name = server_name.to_lowercase();
+    let parent = server_name.split_once('.').map(|(_, parent)| parent);
+
+    certificates.iter()
+        .find(|(names, _)| names.contains(&server_name))
+        .or_else(|| certificates.iter().find(|(names, _)| {
+            names.iter().any(|n| n.strip_prefix("*.").is_some_and(|domain| Some(domain) == parent))
+        }))
+        .map(|(_, ctx)| {
+            debug!("tls certificate selected for server name {}", server_name);
+            ctx
+        })
+}
+
+fn handle_connection(acceptor: Arc<SslAcceptor>, stream: TcpStream, upstream: SocketAddr, connections: ConnectionRegistry, idle_timeout: Duration) {
+
+    let peer = match stream.peer_addr() {
+        Ok(peer) => peer,
+        Err(_) => return,
+    };
+
+    let _ = stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT));
+    let _ = stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT));
+    let tls = match acceptor.accept(stream) {
+        Ok(tls) => tls,
+        Err(e) => {
+            warn!("tls handshake with {} failed: {}", peer, e);
+            return;
+        },
+    };
+
+    let client_cert = tls.ssl().peer_certificate().map(|cert| client_cert(&cert));
+    debug!("tls connection from {}, client certificate: {:?}", peer, client_cert);
+
+    let internal = match TcpStream::connect(upstream) {
+        Ok(internal) => internal,
+        Err(e) => {
+            error!("failed to bridge tls connection from {}: {}", peer, e);
+            return;
+        },
+    };
+
+    let bridge = match internal.local_addr() {
+        Ok(bridge) => bridge,
+        Err(_) => return,
+    };
+
+    connections.register(bridge, ClientConnection { peer, client_cert });
+    if let Err(e) = pipe(tls, internal, idle_timeout) {
+        debug!("tls connection from {} closed: {}", peer, e);
+    }
+    connections.unregister(&bridge);
+}
+
+/// Copies bytes both ways between the client and the internal server until either side closes,
+/// or no byte went through for the idle timeout
+///
+/// Both sockets are non-blocking and polled together, so a connection takes a single thread
+/// and neither direction waits on the other
+fn pipe(mut tls: SslStream<TcpStream>, mut internal: TcpStream, idle_timeout: Duration) -> io::Result<()> {
+
+    tls.get_ref().set_nonblocking(true)?;
+    internal.set_nonblocking(true)?;
+
+    // bytes read from one side and not yet written to the other, with how much was written
+    let (mut to_internal, mut to_internal_sent) = (Vec::with_capacity(BRIDGE_BUFFER_SIZE), 0);
+    let (mut to_client, mut to_client_sent) = (Vec::with_capacity(BRIDGE_BUFFER_SIZE), 0);
+    let (mut client_open, mut internal_open) = (true, true);
+
+    // readiness the last TLS read and write are waiting on, renegotiation may swap them
+    let (mut tls_read_wants, mut tls_write_wants) = (libc::POLLIN, libc::POLLOUT);
+    let mut last_active = Instant::now();
+
+    loop {
+        let mut progress = false;
+
+        // client -> internal server
+        if client_open && to_internal.is_empty() {
+            to_internal.resize(BRIDGE_BUFFER_SIZE, 0);
+            match tls.ssl_read(&mut to_internal) {
+                Ok(n) => {
+                    to_internal.truncate(n);
+                    progress = true;
+                },
+                Err(e) => {
+                    to_internal.clear();
+                    match tls_wants(&e) {
+                        Some(wants) => tls_read_wants = wants,
+                        None if is_closed(&e) => client_open = false,
+                        None => return Err(io::Error::other(e)),
+                    }
+                },
+            }
+        }
+        if !to_internal.is_empty() {
+            match internal.write(&to_internal[to_internal_sent..]) {
+                Ok(n) => {
+                    to_internal_sent += n;
+                    progress = true;
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                Err(e) => return Err(e),
+            }
+            if to_internal_sent == to_internal.len() {
+                to_internal.clear();
+                to_internal_sent = 0;
+            }
+        }
+
+        // internal server -> client
+        if internal_open && to_client.is_empty() {
+            to_client.resize(BRIDGE_BUFFER_SIZE, 0);
+            match internal.read(&mut to_client) {
+                Ok(0) => {
+                    to_client.clear();
+                    internal_open = false;
+                },
+                Ok(n) => {
+                    to_client.truncate(n);
+                    progress = true;
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => to_client.clear(),
+                Err(e) => return Err(e),
+            }
+        }
+        if !to_client.is_empty() {
+            // a write the TLS layer could not finish has to be retried with the same bytes
+            match tls.ssl_write(&to_client[to_client_sent..]) {
+                Ok(n) => {
+                    to_client_sent += n;
+                    progress = true;
+                },
+                Err(e) => match tls_wants(&e) {
+                    Some(wants) => tls_write_wants = wants,
+                    None => return Err(io::Error::other(e)),
+                },
+            }
+            if to_client_sent == to_client.len() {
+                to_client.clear();
+                to_client_sent = 0;
+            }
+        }
+
+        // a side closed, once what it sent is passed on
+        if (!client_open && to_internal.is_empty()) || (!internal_open && to_client.is_empty()) {
+            let _ = tls.shutdown();
+            let _ = internal.shutdown(Shutdown::Both);
+            return Ok(());
+        }
+
+        if progress {
+            last_active = Instant::now();
+            continue;
+        }
+
+        let remaining = idle_timeout.saturating_sub(last_active.elapsed());
+        if remaining.is_zero() {
+            let _ = internal.shutdown(Shutdown::Both);
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "idle connection"));
+        }
+
+        let mut client_events = 0;
+        if client_open && to_internal.is_empty() {
+            client_events |= tls_read_wants;
+        }
+        if !to_client.is_empty() {
+            client_events |= tls_write_wants;
+        }
+        let mut internal_events = 0;
+        if internal_open && to_client.is_empty() {
+            internal_events |= libc::POLLIN;
+        }
+        if !to_internal.is_empty() {
+            internal_events |= libc::POLLOUT;
+        }
+        poll(&mut [(tls.get_ref().as_raw_fd(), client_events), (internal.as_raw_fd(), internal_events)], remaining)?;
+    }
+}
+
+/// Readiness a non-blocking TLS operation is waiting on, none when it failed
+///
+fn tls_wants(e: &ssl::Error) -> Option<libc::c_short> {
+    match e.code() {
+        ErrorCode::WANT_READ => Some(libc::POLLIN),
+        ErrorCode::WANT_WRITE => Some(libc::POLLOUT),
+        _ => None,
+    }
+}
+
+/// Whether the client closed the connection, with or without a TLS close notify
+///
+fn is_closed(e: &ssl::Error) -> bool {
+    e.code() == ErrorCode::ZERO_RETURN || (e.code() == ErrorCode::SYSCALL && e.io_error().is_none())
+}
+
+/// Waits until one of the sockets is ready for its events, or the timeout is reached
+///
+fn poll(sockets: &mut [(std::os::fd::RawFd, libc::c_short)], timeout: Duration) -> io::Result<()> {
+
+    let mut fds: Vec<libc::pollfd> = sockets
+        .iter()
+        .map(|(fd, events)| libc::pollfd { fd: *fd, events: *events, revents: 0 })
+        .collect();
+    let timeout = timeout.as_millis().clamp(1, libc::c_int::MAX as u128) as libc::c_int;
+
+    // SAFETY: fds is a valid array of pollfd for the length given
+    match unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } {
+        -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => Ok(()),
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+fn client_cert(cert: &X509) -> ReboundClientCert {
+
+    let san = cert.subject_alt_names()
+        .map(|names| names
+            .iter()
+            .filter_map(|name| {
+                name.dnsname().map(|n| format!("DNS:{}", n))
+                    .or_else(|| name.email().map(|n| format!("email:{}", n)))
+                    .or_else(|| name.uri().map(|n| format!("URI:{}", n)))
+                    .or_else(|| name.ipaddress().and_then(ip_address).map(|n| format!("IP:{}", n)))
+            })
+            .collect())
+        .unwrap_or_default();
+
+    let fingerprint = cert.digest(MessageDigest::sha256())
+        .map(|d| d.iter().map(|b| format!("{:02X}", b)).collect::<Vec<String>>().join(":"))
+        .unwrap_or_default();
+
+    ReboundClientCert { subject: name_to_string(cert.subject_name()), san, fingerprint }
+}
+
+fn name_to_string(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|e| {
+            let key = e.object().nid().short_name().unwrap_or("?");
+            let value = e.data().to_string().unwrap_or_default();
+            format!("{}={}", key, value)
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+fn ip_address(bytes: &[u8]) -> Option<String> {
+    match bytes.len() {
+        4 => <[u8; 4]>::try_from(bytes).ok().map(|b| std::net::Ipv4Addr::from(b).to_string()),
+        16 => <[u8; 16]>::try_from(bytes).ok().map(|b| std::net::Ipv6Addr::from(b).to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn certificates(names: &[&[&str]]) -> Vec<(Vec<String>, SslContext)> {
+        names.iter()
+            .map(|n| (n.iter().map(|s| s.to_string()).collect(), SslContext::builder(SslMethod::tls()).unwrap().build()))
+            .collect()
+    }
+
+    /// Index of the certificate selected for the server name
+    ///
+    fn selected(certificates: &[(Vec<String>, SslContext)], server_name: &str) -> Option<usize> {
+        let ctx = select_certificate(certificates, server_name)?;
+        certificates.iter().position(|(_, c)| std::ptr::eq(c, ctx))
+    }
+
+    #[test]
+    fn exact_name_wins_over_wildcard() {
+        let certificates = certificates(&[&["*.example.com"], &["api.example.com", "example.org"]]);
+        assert_eq!(selected(&certificates, "api.example.com"), Some(1));
+        assert_eq!(selected(&certificates, "API.Example.com"), Some(1));
+        assert_eq!(selected(&certificates, "www.example.com"), Some(0));
+        assert_eq!(selected(&certificates, "example.org"), Some(1));
+    }
+
+    #[test]
+    fn wildcard_covers_one_label() {
+        let certificates = certificates(&[&["*.example.com"]]);
+        assert_eq!(selected(&certificates, "a.example.com"), Some(0));
+        assert_eq!(selected(&certificates, "a.b.example.com"), None);
+        assert_eq!(selected(&certificates, "example.com"), None);
+        assert_eq!(selected(&certificates, "aexample.com"), None);
+        assert_eq!(selected(&certificates, "other.org"), None);
+    }
+}
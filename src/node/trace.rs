@@ -0,0 +1,653 @@
+use std::{io, sync::atomic::{AtomicUsize, Ordering}, thread, time::{Duration, Instant}};
+use chrono::{DateTime, Utc};
+use flume::{Receiver, RecvTimeoutError, Sender};
+use isahc::{HttpClient, config::Configurable};
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+use tiny_http::Request;
+
+use crate::conf::ReboundTracing;
+
+use super::access::AccessRecord;
+
+/// W3C trace context headers
+///
+pub const TRACEPARENT_HDR: &str = "traceparent";
+pub const TRACESTATE_HDR: &str = "tracestate";
+
+/// B3 headers, in the single header and the multi header forms
+///
+pub const B3_HDR: &str = "b3";
+pub const B3_TRACE_ID_HDR: &str = "X-B3-TraceId";
+pub const B3_SPAN_ID_HDR: &str = "X-B3-SpanId";
+pub const B3_PARENT_SPAN_ID_HDR: &str = "X-B3-ParentSpanId";
+pub const B3_SAMPLED_HDR: &str = "X-B3-Sampled";
+pub const B3_FLAGS_HDR: &str = "X-B3-Flags";
+
+/// Most spans sent to the collector at once
+///
+const EXPORT_BATCH: usize = 512;
+
+/// Longest time a span waits for its batch to fill before it is exported
+///
+const EXPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Spans waiting to be exported, more are dropped
+///
+const EXPORT_QUEUE: usize = 4096;
+
+/// Timeout of export requests to the collector
+///
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// OTLP span kinds
+///
+const SPAN_KIND_SERVER: u8 = 2;
+const SPAN_KIND_CLIENT: u8 = 3;
+
+/// OTLP status codes
+///
+const STATUS_UNSET: u8 = 0;
+const STATUS_ERROR: u8 = 2;
+
+/// Error kinds raised by the upstream call itself
+///
+const UPSTREAM_ERRORS: [&str; 3] = ["upstream", "connect_failure", "timeout"];
+
+/// Trace context of a request, continued from its headers or new
+///
+#[derive(Clone, Debug)]
+pub struct RequestTrace {
+
+    pub trace_id: [u8; 16],
+
+    /// Span of the caller, none when the trace starts here
+    ///
+    pub parent_span_id: Option<[u8; 8]>,
+
+    /// Span of the request in rebound
+    ///
+    pub server_span_id: [u8; 8],
+
+    /// Span of the upstream call, the parent of upstream spans
+    ///
+    pub client_span_id: [u8; 8],
+
+    pub sampled: bool,
+
+    pub tracestate: Option<String>,
+
+    /// Whether the context came in B3 headers, it is then propagated as B3 too
+    ///
+    pub b3: bool
+
+}
+
+impl RequestTrace {
+
+    /// Headers carrying the trace context upstream, with the client span as parent
+    ///
+    pub fn headers(&self) -> Vec<(String, String)> {
+
+        let mut headers = vec![(
+            String::from(TRACEPARENT_HDR),
+            format!("00-{}-{}-{:02x}", hex(&self.trace_id), hex(&self.client_span_id), self.sampled as u8)
+        )];
+        if let Some(tracestate) = &self.tracestate {
+            headers.push((String::from(TRACESTATE_HDR), tracestate.clone()));
+        }
+        if self.b3 {
+            headers.push((
+                String::from(B3_HDR),
+                format!("{}-{}-{}-{}", hex(&self.trace_id), hex(&self.client_span_id), self.sampled as u8, hex(&self.server_span_id))
+            ));
+        }
+        headers
+    }
+}
+
+/// Whether the header carries a trace context, replaced on requests sent upstream
+///
+pub fn is_trace_header(name: &str) -> bool {
+    [TRACEPARENT_HDR, TRACESTATE_HDR, B3_HDR, B3_TRACE_ID_HDR, B3_SPAN_ID_HDR, B3_PARENT_SPAN_ID_HDR, B3_SAMPLED_HDR, B3_FLAGS_HDR]
+        .iter()
+        .any(|h| h.eq_ignore_ascii_case(name))
+}
+
+/// Trace context found in the headers of a client request
+///
+#[derive(Default, Debug)]
+struct IncomingContext {
+
+    ids: Option<([u8; 16], [u8; 8])>,
+
+    sampled: Option<bool>,
+
+    tracestate: Option<String>,
+
+    b3: bool
+
+}
+
+/// Traces requests and exports their spans to the collector
+///
+pub struct Tracer {
+
+    sampling_ratio: f64,
+
+    /// Spans waiting to be exported
+    ///
+    spans: Sender<Value>,
+
+    /// Spans dropped because the export queue was full
+    ///
+    dropped: AtomicUsize
+
+}
+
+impl Tracer {
+
+    /// Starts the exporter thread
+    ///
+    pub fn start(conf: &ReboundTracing) -> io::Result<Self> {
+
+        let client = HttpClient::builder()
+            .timeout(EXPORT_TIMEOUT)
+            .build()
+            .map_err(io::Error::other)?;
+
+        let (tx, rx) = flume::bounded(EXPORT_QUEUE);
+        let exporter_conf = conf.clone();
+        thread::Builder::new()
+            .name(String::from("trace-exporter"))
+            .spawn(move || export(rx, client, exporter_conf))?;
+
+        info!("tracing to {}, sampling ratio {}", conf.endpoint, conf.sampling_ratio);
+        Ok(Tracer { sampling_ratio: conf.sampling_ratio, spans: tx, dropped: AtomicUsize::new(0) })
+    }
+
+    /// Trace of a client request, continuing the trace of its headers when they carry one
+    ///
+    pub fn start_trace(&self, req: &Request) -> RequestTrace {
+
+        let incoming = incoming_context(req);
+        let (trace_id, parent_span_id) = match incoming.ids {
+            Some((trace_id, span_id)) => (trace_id, Some(span_id)),
+            None => (random_id(), None),
+        };
+
+        RequestTrace {
+            trace_id,
+            parent_span_id,
+            server_span_id: random_id(),
+            client_span_id: random_id(),
+            sampled: incoming.sampled.unwrap_or_else(|| self.sample(&trace_id)),
+            tracestate: incoming.tracestate,
+            b3: incoming.b3
+        }
+    }
+
+    /// Sampling decision of a new trace, derived from its id so every service sampling the same ratio agrees
+    ///
+    fn sample(&self, trace_id: &[u8; 16]) -> bool {
+        if self.sampling_ratio >= 1.0 {
+            return true;
+        }
+        let mut low = [0u8; 8];
+        low.copy_from_slice(&trace_id[8..]);
+        u64::from_be_bytes(low) < (self.sampling_ratio * u64::MAX as f64) as u64
+    }
+
+    /// Queues the spans of a finished request for export, when sampled
+    ///
+    pub fn finish(&self, record: &AccessRecord) {
+
+        let trace = match &record.trace {
+            Some(trace) if trace.sampled => trace,
+            _ => return,
+        };
+
+        let path = record.path.split('?').next().unwrap_or_default();
+        let mut attributes = vec![
+            str_attr("http.request.method", &record.method),
+            str_attr("url.path", path),
+            str_attr("network.protocol.version", record.protocol.trim_start_matches("HTTP/")),
+            str_attr("client.address", &record.client.to_string()),
+            int_attr("http.response.status_code", record.status as i64),
+            str_attr("rebound.request_id", &record.request_id)
+        ];
+        if let Some(rule) = &record.rule {
+            attributes.push(str_attr("http.route", rule));
+        }
+        if let Some(user_agent) = &record.user_agent {
+            attributes.push(str_attr("user_agent.original", user_agent));
+        }
+        if let Some(kind) = record.error {
+            attributes.push(str_attr("error.type", kind));
+        }
+
+        let name = match &record.rule {
+            Some(rule) => format!("{} {}", record.method, rule),
+            None => record.method.clone(),
+        };
+        self.queue(span(
+            trace,
+            trace.server_span_id,
+            trace.parent_span_id,
+            name,
+            SPAN_KIND_SERVER,
+            record.time,
+            record.total_latency,
+            attributes,
+            record.status >= 500
+        ));
+
+        if let (Some(started), Some(latency), Some(upstream)) = (record.upstream_started, record.upstream_latency, &record.upstream) {
+
+            let failed = record.error.map(|kind| UPSTREAM_ERRORS.contains(&kind)).unwrap_or(false);
+            let mut attributes = vec![
+                str_attr("http.request.method", &record.method),
+                str_attr("url.full", upstream)
+            ];
+            if failed {
+                attributes.push(str_attr("error.type", record.error.unwrap_or_default()));
+            }
+            else {
+                attributes.push(int_attr("http.response.status_code", record.status as i64));
+            }
+
+            self.queue(span(
+                trace,
+                trace.client_span_id,
+                Some(trace.server_span_id),
+                record.method.clone(),
+                SPAN_KIND_CLIENT,
+                started,
+                latency,
+                attributes,
+                failed || record.status >= 500
+            ));
+        }
+    }
+
+    fn queue(&self, span: Value) {
+        if self.spans.try_send(span).is_err() {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped % 1000 == 1 {
+                warn!("span export queue full, dropped {} spans so far", dropped);
+            }
+        }
+    }
+}
+
+/// Exports queued spans in batches, until the queue is closed
+///
+fn export(rx: Receiver<Value>, client: HttpClient, conf: ReboundTracing) {
+
+    loop {
+        let first = match rx.recv() {
+            Ok(span) => span,
+            Err(_) => return,
+        };
+
+        let mut batch = vec![first];
+        let deadline = Instant::now() + EXPORT_INTERVAL;
+        while batch.len() < EXPORT_BATCH {
+            match rx.recv_deadline(deadline) {
+                Ok(span) => batch.push(span),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        let count = batch.len();
+        let body = json!({
+            "resourceSpans": [{
+                "resource": { "attributes": [ str_attr("service.name", &conf.service_name) ] },
+                "scopeSpans": [{
+                    "scope": { "name": "rebound", "version": env!("CARGO_PKG_VERSION") },
+                    "spans": batch
+                }]
+            }]
+        });
+
+        let sent = isahc::http::Request::post(conf.endpoint.as_str())
+            .header("Content-Type", "application/json")
+            .body(body.to_string())
+            .map_err(|e| e.to_string())
+            .and_then(|req| client.send(req).map_err(|e| e.to_string()));
+
+        match sent {
+            Ok(res) if res.status().is_success() => debug!("exported {} spans", count),
+            Ok(res) => warn!("collector {} refused {} spans with status {}", conf.endpoint, count, res.status()),
+            Err(e) => warn!("failed to export {} spans to {}: {}", count, conf.endpoint, e),
+        }
+    }
+}
+
+/// Span in the OTLP JSON encoding
+///
+#[allow(clippy::too_many_arguments)]
+fn span(
+    trace: &RequestTrace,
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    name: String,
+    kind: u8,
+    start: DateTime<Utc>,
+    duration: Duration,
+    attributes: Vec<Value>,
+    failed: bool
+) -> Value {
+
+    let start_nanos = start.timestamp_nanos_opt().unwrap_or_default();
+    let end_nanos = start_nanos + duration.as_nanos() as i64;
+    json!({
+        "traceId": hex(&trace.trace_id),
+        "spanId": hex(&span_id),
+        "parentSpanId": parent_span_id.map(|id| hex(&id)).unwrap_or_default(),
+        "traceState": trace.tracestate.clone().unwrap_or_default(),
+        "name": name,
+        "kind": kind,
+        "startTimeUnixNano": start_nanos.to_string(),
+        "endTimeUnixNano": end_nanos.to_string(),
+        "attributes": attributes,
+        "status": { "code": if failed { STATUS_ERROR } else { STATUS_UNSET } }
+    })
+}
+
+fn str_attr(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+fn int_attr(key: &str, value: i64) -> Value {
+    json!({ "key": key, "value": { "intValue": value.to_string() } })
+}
+
+/// Trace context of the request headers, W3C winning over B3
+///
+fn incoming_context(req: &Request) -> IncomingContext {
+
+    let header = |name: &str| req.headers()
+        .iter()
+        .find(|h| h.field.as_str().as_str().eq_ignore_ascii_case(name))
+        .map(|h| h.value.as_str().trim().to_string());
+
+    if let Some((trace_id, span_id, sampled)) = header(TRACEPARENT_HDR).as_deref().and_then(parse_traceparent) {
+        return IncomingContext {
+            ids: Some((trace_id, span_id)),
+            sampled: Some(sampled),
+            tracestate: header(TRACESTATE_HDR).filter(|s| !s.is_empty()),
+            b3: false
+        };
+    }
+
+    if let Some(b3) = header(B3_HDR) {
+        return parse_b3(&b3).unwrap_or_default();
+    }
+
+    let sampled = match (header(B3_FLAGS_HDR).as_deref(), header(B3_SAMPLED_HDR).as_deref()) {
+        (Some("1"), _) => Some(true),
+        (_, Some("1") | Some("true")) => Some(true),
+        (_, Some("0") | Some("false")) => Some(false),
+        _ => None,
+    };
+    let ids = match (header(B3_TRACE_ID_HDR), header(B3_SPAN_ID_HDR)) {
+        (Some(trace_id), Some(span_id)) => b3_trace_id(&trace_id).zip(decode_hex::<8>(&span_id)),
+        _ => None,
+    };
+    IncomingContext { b3: ids.is_some() || sampled.is_some(), ids, sampled, tracestate: None }
+}
+
+/// Trace id, parent span id and sampled flag of a traceparent header
+///
+fn parse_traceparent(value: &str) -> Option<([u8; 16], [u8; 8], bool)> {
+
+    let parts: Vec<&str> = value.split('-').collect();
+    let version = parts.first().and_then(|v| decode_hex::<1>(v))?;
+    if version[0] == 0xff || parts.len() < 4 || (version[0] == 0 && parts.len() != 4) {
+        return None;
+    }
+
+    let trace_id = decode_hex::<16>(parts[1]).filter(|id| id.iter().any(|b| *b != 0))?;
+    let span_id = decode_hex::<8>(parts[2]).filter(|id| id.iter().any(|b| *b != 0))?;
+    let flags = decode_hex::<1>(parts[3])?;
+    Some((trace_id, span_id, flags[0] & 1 == 1))
+}
+
+/// Context of a single b3 header, traceid-spanid[-sampled[-parentspanid]] or only the sampling decision
+///
+fn parse_b3(value: &str) -> Option<IncomingContext> {
+
+    let sampling = |s: &str| match s {
+        "1" | "d" => Some(true),
+        "0" => Some(false),
+        _ => None,
+    };
+
+    let parts: Vec<&str> = value.split('-').collect();
+    if parts.len() == 1 {
+        return Some(IncomingContext { sampled: sampling(parts[0]), b3: true, ..Default::default() });
+    }
+
+    let trace_id = b3_trace_id(parts[0])?;
+    let span_id = decode_hex::<8>(parts[1])?;
+    Some(IncomingContext {
+        ids: Some((trace_id, span_id)),
+        sampled: parts.get(2).and_then(|s| sampling(s)),
+        tracestate: None,
+        b3: true
+    })
+}
+
+/// B3 trace ids are 64 or 128 bits, 64 bit ids are left padded
+///
+fn b3_trace_id(value: &str) -> Option<[u8; 16]> {
+    match value.len() {
+        16 => decode_hex::<8>(value).map(|low| {
+            let mut id = [0u8; 16];
+            id[8..].copy_from_slice(&low);
+            id
+        }),
+        _ => decode_hex::<16>(value),
+    }
+}
+
+/// Bytes of lowercase hex, as both W3C and B3 ids are sent, other headers are invalid
+///
+fn decode_hex<const N: usize>(value: &str) -> Option<[u8; N]> {
+    if value.len() != N * 2 || !value.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = u8::from_str_radix(value.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn random_id<const N: usize>() -> [u8; N] {
+    let mut id = [0u8; N];
+    // ids only need to be unique, a failing generator falls back to the clock
+    if openssl::rand::rand_bytes(&mut id).is_err() {
+        let nanos = Utc::now().timestamp_nanos_opt().unwrap_or_default().to_be_bytes();
+        for (i, b) in id.iter_mut().enumerate() {
+            *b = nanos[i % nanos.len()];
+        }
+    }
+    id
+}
+
+#[cfg(test)]
+mod tests {
+
+    use tiny_http::{Header, TestRequest};
+
+    use super::*;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const SPAN_ID: &str = "00f067aa0ba902b7";
+
+    fn request(headers: &[(&str, &str)]) -> Request {
+        headers
+            .iter()
+            .fold(TestRequest::new(), |req, (k, v)| req.with_header(Header::from_bytes(k.as_bytes(), v.as_bytes()).unwrap()))
+            .into()
+    }
+
+    fn tracer(sampling_ratio: f64) -> Tracer {
+        let (spans, _) = flume::bounded(1);
+        Tracer { sampling_ratio, spans, dropped: AtomicUsize::new(0) }
+    }
+
+    fn trace_id(s: &str) -> [u8; 16] {
+        decode_hex::<16>(s).unwrap()
+    }
+
+    #[test]
+    fn traceparent_accepted() {
+        let (trace, span, sampled) = parse_traceparent(&format!("00-{}-{}-01", TRACE_ID, SPAN_ID)).unwrap();
+        assert_eq!((hex(&trace), hex(&span), sampled), (String::from(TRACE_ID), String::from(SPAN_ID), true));
+
+        let (_, _, sampled) = parse_traceparent(&format!("00-{}-{}-00", TRACE_ID, SPAN_ID)).unwrap();
+        assert!(!sampled);
+
+        // later versions may add fields
+        assert!(parse_traceparent(&format!("01-{}-{}-03-extra", TRACE_ID, SPAN_ID)).is_some());
+    }
+
+    #[test]
+    fn traceparent_rejected() {
+        assert!(parse_traceparent(&format!("ff-{}-{}-01", TRACE_ID, SPAN_ID)).is_none());
+        assert!(parse_traceparent(&format!("00-{}-{}-01-extra", TRACE_ID, SPAN_ID)).is_none());
+        assert!(parse_traceparent(&format!("00-{}-{}-01", "0".repeat(32), SPAN_ID)).is_none());
+        assert!(parse_traceparent(&format!("00-{}-{}-01", TRACE_ID, "0".repeat(16))).is_none());
+        assert!(parse_traceparent(&format!("00-{}-{}-01", &TRACE_ID[1..], SPAN_ID)).is_none());
+        assert!(parse_traceparent(&format!("00-{}-+{}-01", TRACE_ID, &SPAN_ID[1..])).is_none());
+        assert!(parse_traceparent(&format!("00-{}-{}-zz", TRACE_ID, SPAN_ID)).is_none());
+        assert!(parse_traceparent(&format!("00-{}-{}-01", TRACE_ID.to_uppercase(), SPAN_ID)).is_none());
+        assert!(parse_traceparent(&format!("00-{}-{}-01", TRACE_ID, SPAN_ID.to_uppercase())).is_none());
+        assert!(parse_traceparent(&format!("00-{}-{}", TRACE_ID, SPAN_ID)).is_none());
+        assert!(parse_traceparent("").is_none());
+    }
+
+    #[test]
+    fn b3_single_header() {
+        let ctx = parse_b3(&format!("{}-{}-1-05e3ac9a4f6e3b90", TRACE_ID, SPAN_ID)).unwrap();
+        assert_eq!(ctx.ids, Some((trace_id(TRACE_ID), decode_hex::<8>(SPAN_ID).unwrap())));
+        assert_eq!(ctx.sampled, Some(true));
+        assert!(ctx.b3);
+
+        // 64 bit trace ids are left padded
+        let ctx = parse_b3(&format!("a3ce929d0e0e4736-{}", SPAN_ID)).unwrap();
+        assert_eq!(ctx.ids.map(|(t, _)| hex(&t)), Some(format!("{}a3ce929d0e0e4736", "0".repeat(16))));
+        assert_eq!(ctx.sampled, None);
+
+        assert_eq!(parse_b3(&format!("{}-{}-d", TRACE_ID, SPAN_ID)).unwrap().sampled, Some(true));
+        assert_eq!(parse_b3("0").unwrap().sampled, Some(false));
+        assert!(parse_b3("0").unwrap().ids.is_none());
+    }
+
+    #[test]
+    fn b3_single_header_rejected() {
+        assert!(parse_b3(&format!("{}-{}", &TRACE_ID[2..], SPAN_ID)).is_none());
+        assert!(parse_b3(&format!("{}-{}", TRACE_ID, &SPAN_ID[2..])).is_none());
+        assert!(parse_b3(TRACE_ID).unwrap().ids.is_none());
+    }
+
+    #[test]
+    fn incoming_w3c_wins_over_b3() {
+        let ctx = incoming_context(&request(&[
+            ("Traceparent", &format!("00-{}-{}-01", TRACE_ID, SPAN_ID)),
+            ("tracestate", "vendor=value"),
+            ("b3", "0"),
+        ]));
+        assert_eq!(ctx.ids.map(|(t, _)| t), Some(trace_id(TRACE_ID)));
+        assert_eq!(ctx.sampled, Some(true));
+        assert_eq!(ctx.tracestate.as_deref(), Some("vendor=value"));
+        assert!(!ctx.b3);
+
+        // an invalid traceparent falls back to b3
+        let ctx = incoming_context(&request(&[("traceparent", "garbage"), ("b3", "0")]));
+        assert_eq!((ctx.ids.is_none(), ctx.sampled, ctx.b3), (true, Some(false), true));
+
+        // uppercase ids are invalid, a new trace is started
+        let ctx = incoming_context(&request(&[("traceparent", &format!("00-{}-{}-01", TRACE_ID.to_uppercase(), SPAN_ID))]));
+        assert!(ctx.ids.is_none());
+        let trace = tracer(1.0).start_trace(&request(&[("traceparent", &format!("00-{}-{}-01", TRACE_ID.to_uppercase(), SPAN_ID))]));
+        assert_ne!(hex(&trace.trace_id), TRACE_ID);
+        assert!(trace.parent_span_id.is_none());
+    }
+
+    #[test]
+    fn incoming_b3_multi_headers() {
+        let ctx = incoming_context(&request(&[
+            ("X-B3-TraceId", TRACE_ID),
+            ("X-B3-SpanId", SPAN_ID),
+            ("X-B3-Sampled", "0"),
+        ]));
+        assert_eq!(ctx.ids, Some((trace_id(TRACE_ID), decode_hex::<8>(SPAN_ID).unwrap())));
+        assert_eq!(ctx.sampled, Some(false));
+        assert!(ctx.b3);
+
+        // debug flag forces sampling
+        let ctx = incoming_context(&request(&[("X-B3-Flags", "1"), ("X-B3-Sampled", "0")]));
+        assert_eq!((ctx.ids.is_none(), ctx.sampled, ctx.b3), (true, Some(true), true));
+
+        let ctx = incoming_context(&request(&[("X-B3-TraceId", TRACE_ID)]));
+        assert_eq!((ctx.ids.is_none(), ctx.sampled, ctx.b3), (true, None, false));
+
+        let ctx = incoming_context(&request(&[]));
+        assert_eq!((ctx.ids.is_none(), ctx.sampled, ctx.b3), (true, None, false));
+    }
+
+    #[test]
+    fn start_trace_continues_incoming_context() {
+        let trace = tracer(0.0).start_trace(&request(&[("traceparent", &format!("00-{}-{}-01", TRACE_ID, SPAN_ID))]));
+
+        assert_eq!(hex(&trace.trace_id), TRACE_ID);
+        assert_eq!(trace.parent_span_id.map(|s| hex(&s)).as_deref(), Some(SPAN_ID));
+        assert_ne!(trace.server_span_id, trace.client_span_id);
+        assert!(trace.sampled);
+
+        let trace = tracer(1.0).start_trace(&request(&[]));
+        assert!(trace.parent_span_id.is_none());
+        assert!(trace.sampled);
+    }
+
+    #[test]
+    fn sampling_by_trace_id() {
+        let low = trace_id("ffffffffffffffff0000000000000001");
+        let high = trace_id("00000000000000fffffffffffffffff0");
+
+        assert!(tracer(0.5).sample(&low));
+        assert!(!tracer(0.5).sample(&high));
+        assert!(!tracer(0.0).sample(&low));
+        assert!(tracer(1.0).sample(&high));
+    }
+
+    #[test]
+    fn propagated_headers() {
+        let trace = RequestTrace {
+            trace_id: trace_id(TRACE_ID),
+            parent_span_id: None,
+            server_span_id: [1; 8],
+            client_span_id: [2; 8],
+            sampled: true,
+            tracestate: Some(String::from("vendor=value")),
+            b3: true
+        };
+        let headers = trace.headers();
+
+        assert!(headers.contains(&(String::from(TRACEPARENT_HDR), format!("00-{}-0202020202020202-01", TRACE_ID))));
+        assert!(headers.contains(&(String::from(TRACESTATE_HDR), String::from("vendor=value"))));
+        assert!(headers.contains(&(String::from(B3_HDR), format!("{}-0202020202020202-1-0101010101010101", TRACE_ID))));
+        assert!(headers.iter().all(|(k, _)| is_trace_header(k)));
+        assert!(is_trace_header("X-B3-SAMPLED"));
+        assert!(!is_trace_header("X-Request-Id"));
+    }
+}
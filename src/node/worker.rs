@@ -1,74 +1,138 @@
-use std::fs::File;
+use std::io::Cursor;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::Instant;
+use chrono::Utc;
 
 use flume::Receiver;
-use log::{error, info};
-use tiny_http::{Request, Response};
+use log::{error, info, warn};
+use tiny_http::{Request, Response, ResponseBox};
 
-use crate::conf::ReboundConf;
-use crate::engine::circuit::Circuit;
-use crate::engine::client::ReboundClient;
+use crate::engine::error::ReboundError;
 use crate::engine::ReboundEngine;
 
-///
+use super::access::AccessRecord;
+use super::context::NodeContext;
+use super::queue::QueuedRequest;
+
+/// Worker Node for Rebound that handles queued requests
 ///
 pub struct WorkerNode {
-    ///
+    /// Worker identifier
     ///
     pub id: String,
 
+    /// Receiving half of the request queue
     ///
-    ///
-    request_queue_rx: Receiver<Request>,
+    request_queue_rx: Receiver<QueuedRequest>,
 
-    ///
+    /// Engine matching requests against the circuit
     ///
     engine: ReboundEngine,
 
+    /// State shared with the master
     ///
-    ///
-    client: ReboundClient,
+    ctx: NodeContext,
 }
 
-///
-///
 impl WorkerNode {
-    pub fn from(wid: String, _conf: ReboundConf, circuit: Circuit, receiver: Receiver<Request>) -> Self {
+    pub fn from(wid: String, receiver: Receiver<QueuedRequest>, ctx: NodeContext) -> Self {
         WorkerNode {
             id: wid,
             request_queue_rx: receiver,
-            engine: ReboundEngine::new(circuit),
-            client: ReboundClient::new(),
+            engine: ReboundEngine::new(ctx.circuit.clone(), ctx.ip_filter.clone(), ctx.rate_limiter.clone()),
+            ctx,
         }
     }
 
-    // Response::from_file(File::open(format!("{}/default.html", std::env::var(REBOUND_SITE_DIR).unwrap())).unwrap()).with_status_code(502)
     pub fn run<F>(&mut self, mut error_provider: F)
     where
-        F: FnMut() -> Response<File>,
+        F: FnMut(&ReboundError) -> ResponseBox,
     {
-        for mut conn_req in self.request_queue_rx.iter() {
-            info!("{} handling request: {:?}", self.id, conn_req);
-            let r = self.engine.get(&mut conn_req);
+        let rx = self.request_queue_rx.clone();
+        let queue_policy = self.ctx.queue_policy.clone();
+        for queued in rx.iter() {
+
+            self.ctx.metrics.queue_depth.set(rx.len() as i64);
+            let mut access = self.ctx.access_record(&queued.request, queued.queued_at);
+            if queue_policy.is_expired(&queued) {
+                queue_policy.record_expired();
+                warn!(
+                    "{} dropping request waiting {}ms in queue: {:?} (expired: {})",
+                    self.id,
+                    queued.waited().as_millis(),
+                    queued.request,
+                    queue_policy.expired()
+                );
+
+                let e = queue_policy.overloaded();
+                access.error = Some(e.kind());
+                if access.respond(queued.request, error_provider(&e)).is_err() {
+                    error!("{} failed to send overloaded response", self.id);
+                }
+                self.ctx.finish(&mut access);
+                continue;
+            }
+
+            let mut conn_req = queued.request;
+            self.ctx.log_request(&self.id, &conn_req);
+            self.ctx.metrics.busy_workers.inc();
+            self.ctx.supervisor_stats.record_busy(&self.id, format!("{} {}", conn_req.method(), self.ctx.redact(conn_req.url())));
+
+            // a panic while handling a single request must not take the worker down
+            let r = panic::catch_unwind(AssertUnwindSafe(|| self.handle(&mut conn_req, &mut access)));
+            let r = match r {
+                Ok(r) => r,
+                Err(cause) => {
+                    self.ctx.supervisor_stats.record_panic();
+                    self.ctx.metrics.panics.inc();
+                    error!("{} panicked handling {}: {}", self.id, self.ctx.summary(&conn_req), panic_message(&cause));
+                    Err(ReboundError::Internal)
+                }
+            };
+
             match r {
-                Some(rebound_req) => {
-                    info!("{} sending upstream request: {:?}", self.id, rebound_req);
-                    match futures::executor::block_on(self.client.send(rebound_req)) {
-                        Ok(rebound_res) => match conn_req.respond(rebound_res.into()) {
-                            Ok(_) => info!("{} sent response from rule, finished request", self.id),
-                            Err(_) => error!("{} failed to send response from rule", self.id),
-                        },
-
-                        Err(_) => match conn_req.respond(error_provider()) {
-                            Ok(_) => info!("{} sent error response, finished request", self.id),
-                            Err(_) => error!("{} failed to send error response", self.id),
-                        },
+                Ok(rebound_res) => match access.respond(conn_req, rebound_res.boxed()) {
+                    Ok(_) => info!("{} sent response from rule, finished request", self.id),
+                    Err(_) => error!("{} failed to send response from rule", self.id),
+                },
+                Err(e) => {
+                    info!("{} could not route request: {}", self.id, e);
+                    access.error = Some(e.kind());
+                    match access.respond(conn_req, error_provider(&e)) {
+                        Ok(_) => info!("{} sent error response, finished request", self.id),
+                        Err(_) => error!("{} failed to send error response", self.id),
                     }
                 }
-                None => match conn_req.respond(error_provider()) {
-                    Ok(_) => info!("{} sent default response, finished request", self.id),
-                    Err(_) => error!("{} failed to send default response", self.id),
-                },
             }
+            self.ctx.metrics.busy_workers.dec();
+            self.ctx.supervisor_stats.record_idle(&self.id);
+            self.ctx.finish(&mut access);
         }
     }
+
+    fn handle(&mut self, conn_req: &mut Request, access: &mut AccessRecord) -> Result<Response<Cursor<Vec<u8>>>, ReboundError> {
+        let (rule, rebound_req) = self.engine.get(self.ctx.rebound_request(conn_req));
+        access.rule = rule;
+        let mut rebound_req = rebound_req?;
+        access.route(&mut rebound_req);
+        self.ctx.log_upstream_request(&self.id, &rebound_req);
+
+        let sent_at = Instant::now();
+        access.upstream_started = Some(Utc::now());
+        let rebound_res = futures::executor::block_on(self.ctx.client.send(rebound_req));
+        access.upstream_latency = Some(sent_at.elapsed());
+        Ok(rebound_res?.into())
+    }
+}
+
+pub fn panic_message(cause: &Box<dyn std::any::Any + Send>) -> String {
+    if let Some(s) = cause.downcast_ref::<&str>() {
+        String::from(*s)
+    }
+    else if let Some(s) = cause.downcast_ref::<String>() {
+        s.clone()
+    }
+    else {
+        String::from("unknown cause")
+    }
 }